@@ -0,0 +1,51 @@
+//! Reading and writing the system clipboard, for /clip and /copy.
+//!
+//! There is no clipboard without a display, e.g. over SSH, in which case both functions return
+//! an error meant to be shown to the user as is.
+
+#[cfg(feature = "clipboard")]
+use std::sync::Mutex;
+
+/// Kept open for as long as the chat runs, on X11 the copied text is gone once it is dropped.
+#[cfg(feature = "clipboard")]
+static CLIPBOARD: Mutex<Option<arboard::Clipboard>> = Mutex::new(None);
+
+/// Runs `f` with the clipboard, opening it the first time.
+#[cfg(feature = "clipboard")]
+fn with_clipboard<T>(
+    f: impl FnOnce(&mut arboard::Clipboard) -> Result<T, arboard::Error>,
+) -> Result<T, String> {
+    let mut clipboard = CLIPBOARD.lock().unwrap();
+    if clipboard.is_none() {
+        *clipboard =
+            Some(arboard::Clipboard::new().map_err(|e| format!("No clipboard available: {}", e))?);
+    }
+    f(clipboard.as_mut().unwrap()).map_err(|e| format!("Clipboard error: {}", e))
+}
+
+/// The text in the clipboard.
+#[cfg(feature = "clipboard")]
+pub fn read() -> Result<String, String> {
+    with_clipboard(|clipboard| clipboard.get_text())
+}
+
+/// Replaces the contents of the clipboard with `text`.
+#[cfg(feature = "clipboard")]
+pub fn write(text: &str) -> Result<(), String> {
+    with_clipboard(|clipboard| clipboard.set_text(text))
+}
+
+#[cfg(not(feature = "clipboard"))]
+pub fn read() -> Result<String, String> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "clipboard"))]
+pub fn write(_text: &str) -> Result<(), String> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "clipboard"))]
+fn unsupported() -> String {
+    "lan-chat was built without clipboard support".to_string()
+}
@@ -0,0 +1,411 @@
+//! User settings for the client, read from a plain `name = value` file.
+//!
+//! The file lives at `$XDG_CONFIG_HOME/lan-chat/config` (or `~/.config/lan-chat/config`) unless
+//! another path is passed with `--config`. Blank lines and lines starting with `#` are ignored.
+//!
+//! ```text
+//! # vim-like scrolling
+//! scroll_up = ctrl+k, up
+//! scroll_down = ctrl+j, down
+//!
+//! # ring the bell for every message, only when mentioned, or never
+//! notify = mentions
+//!
+//! # shown in the empty input box
+//! placeholder = Say hi to everyone
+//! ```
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// Something the user can do with a key press.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Send,
+    ScrollUp,
+    ScrollDown,
+    ScrollToBottom,
+    Quit,
+    EmojiPicker,
+    Newline,
+    Retry,
+    Select,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "send" => Ok(Action::Send),
+            "scroll_up" => Ok(Action::ScrollUp),
+            "scroll_down" => Ok(Action::ScrollDown),
+            "scroll_to_bottom" => Ok(Action::ScrollToBottom),
+            "quit" => Ok(Action::Quit),
+            "emoji_picker" => Ok(Action::EmojiPicker),
+            "newline" => Ok(Action::Newline),
+            "retry" => Ok(Action::Retry),
+            "select" => Ok(Action::Select),
+            _ => Err(format!("unknown setting '{}'", s)),
+        }
+    }
+}
+
+/// Which incoming messages ring the terminal bell.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NotifyLevel {
+    All,
+    #[default]
+    Mentions,
+    Off,
+}
+
+impl FromStr for NotifyLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(NotifyLevel::All),
+            "mentions" => Ok(NotifyLevel::Mentions),
+            "off" => Ok(NotifyLevel::Off),
+            _ => Err(format!(
+                "unknown notification level '{}', expected all, mentions or off",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for NotifyLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotifyLevel::All => write!(f, "all"),
+            NotifyLevel::Mentions => write!(f, "mentions"),
+            NotifyLevel::Off => write!(f, "off"),
+        }
+    }
+}
+
+/// A key along with the modifiers held down with it, written like `ctrl+k` or `enter`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyBinding {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl KeyBinding {
+    pub const fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        KeyBinding { code, modifiers }
+    }
+
+    fn matches(&self, key: &KeyEvent) -> bool {
+        // Shift is already part of the character for printable keys
+        let modifiers = match key.code {
+            KeyCode::Char(_) => key.modifiers - KeyModifiers::SHIFT,
+            _ => key.modifiers,
+        };
+        self.code == key.code && self.modifiers == modifiers
+    }
+}
+
+impl FromStr for KeyBinding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts: Vec<&str> = s.split('+').map(str::trim).collect();
+        let key = parts.pop().unwrap_or_default();
+
+        let mut modifiers = KeyModifiers::NONE;
+        for modifier in parts {
+            modifiers |= match modifier.to_lowercase().as_str() {
+                "ctrl" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => return Err(format!("unknown modifier '{}' in '{}'", modifier, s)),
+            };
+        }
+
+        let code = match key.to_lowercase().as_str() {
+            "enter" => KeyCode::Enter,
+            "esc" => KeyCode::Esc,
+            "tab" => KeyCode::Tab,
+            "backspace" => KeyCode::Backspace,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            "space" => KeyCode::Char(' '),
+            name => match name.strip_prefix('f').and_then(|n| n.parse().ok()) {
+                Some(n) => KeyCode::F(n),
+                None => {
+                    let mut chars = key.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => KeyCode::Char(c),
+                        _ => return Err(format!("unknown key '{}'", s)),
+                    }
+                }
+            },
+        };
+
+        Ok(KeyBinding::new(code, modifiers))
+    }
+}
+
+/// The keys bound to every action.
+#[derive(Debug, Clone)]
+pub struct KeyBindings {
+    bindings: Vec<(Action, KeyBinding)>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            bindings: vec![
+                (
+                    Action::Send,
+                    KeyBinding::new(KeyCode::Enter, KeyModifiers::NONE),
+                ),
+                (
+                    Action::ScrollUp,
+                    KeyBinding::new(KeyCode::Up, KeyModifiers::NONE),
+                ),
+                (
+                    Action::ScrollDown,
+                    KeyBinding::new(KeyCode::Down, KeyModifiers::NONE),
+                ),
+                (
+                    Action::ScrollToBottom,
+                    KeyBinding::new(KeyCode::End, KeyModifiers::NONE),
+                ),
+                (
+                    Action::Quit,
+                    KeyBinding::new(KeyCode::Char('c'), KeyModifiers::CONTROL),
+                ),
+                (
+                    Action::EmojiPicker,
+                    KeyBinding::new(KeyCode::Char('e'), KeyModifiers::CONTROL),
+                ),
+                // Most terminals can't tell Shift+Enter from Enter, so Alt+Enter works too
+                (
+                    Action::Newline,
+                    KeyBinding::new(KeyCode::Enter, KeyModifiers::SHIFT),
+                ),
+                (
+                    Action::Newline,
+                    KeyBinding::new(KeyCode::Enter, KeyModifiers::ALT),
+                ),
+                (
+                    Action::Retry,
+                    KeyBinding::new(KeyCode::Char('r'), KeyModifiers::CONTROL),
+                ),
+                (
+                    Action::Select,
+                    KeyBinding::new(KeyCode::Char('s'), KeyModifiers::CONTROL),
+                ),
+            ],
+        }
+    }
+}
+
+impl KeyBindings {
+    /// Returns the action bound to a key press, if any.
+    pub fn action(&self, key: &KeyEvent) -> Option<Action> {
+        self.bindings
+            .iter()
+            .find(|(_, binding)| binding.matches(key))
+            .map(|(action, _)| *action)
+    }
+
+    /// Replaces the keys bound to an action.
+    fn bind(&mut self, action: Action, keys: Vec<KeyBinding>) {
+        self.bindings.retain(|(a, _)| *a != action);
+        self.bindings
+            .extend(keys.into_iter().map(|binding| (action, binding)));
+    }
+}
+
+/// Settings read from the config file.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub key_bindings: KeyBindings,
+    pub notify: NotifyLevel,
+    /// Shown in the empty input box instead of the default text.
+    pub placeholder: Option<String>,
+}
+
+impl Config {
+    /// Reads the config file at the given path, or at the default path if none is given.
+    ///
+    /// A missing file at the default path just means the defaults are used.
+    pub fn load(path: Option<&Path>) -> io::Result<Self> {
+        let contents = match path {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => match default_path().map(std::fs::read_to_string) {
+                Some(Ok(contents)) => contents,
+                Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        contents
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Returns the path of the config file, the given one or the default one.
+    pub fn path(path: Option<&Path>) -> Option<PathBuf> {
+        path.map(Path::to_path_buf).or_else(default_path)
+    }
+
+    /// Sets a single setting in the config file, keeping the rest of the file as it is.
+    pub fn save_setting(path: &Path, name: &str, value: &str) -> io::Result<()> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let setting = format!("{} = {}", name, value);
+        let mut replaced = false;
+        let mut lines: Vec<String> = contents
+            .lines()
+            .map(|line| {
+                let is_setting = !line.trim().starts_with('#')
+                    && line
+                        .split_once('=')
+                        .is_some_and(|(key, _)| key.trim() == name);
+                if is_setting {
+                    replaced = true;
+                    setting.clone()
+                } else {
+                    line.to_string()
+                }
+            })
+            .collect();
+        if !replaced {
+            lines.push(setting);
+        }
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, lines.join("\n") + "\n")
+    }
+}
+
+impl FromStr for Config {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Config::default();
+
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = |e: String| format!("config line {}: {}", number + 1, e);
+            let Some((name, value)) = line.split_once('=') else {
+                return Err(error(format!("expected 'name = value', got '{}'", line)));
+            };
+
+            if name.trim() == "notify" {
+                config.notify = value.trim().parse().map_err(error)?;
+                continue;
+            }
+            if name.trim() == "placeholder" {
+                config.placeholder = Some(value.trim().to_string());
+                continue;
+            }
+
+            let action = name.trim().parse().map_err(error)?;
+            let keys = value
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .map_err(error)?;
+            config.key_bindings.bind(action, keys);
+        }
+
+        Ok(config)
+    }
+}
+
+/// Where the config file is looked for when no path is given.
+fn default_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("lan-chat").join("config"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_key_binding() {
+        assert_eq!(
+            "ctrl+k".parse(),
+            Ok(KeyBinding::new(KeyCode::Char('k'), KeyModifiers::CONTROL))
+        );
+        assert_eq!(
+            "Enter".parse(),
+            Ok(KeyBinding::new(KeyCode::Enter, KeyModifiers::NONE))
+        );
+        assert_eq!(
+            "alt+f5".parse(),
+            Ok(KeyBinding::new(KeyCode::F(5), KeyModifiers::ALT))
+        );
+        assert!("hyper+k".parse::<KeyBinding>().is_err());
+        assert!("nokey".parse::<KeyBinding>().is_err());
+    }
+
+    #[test]
+    fn test_config_replaces_bindings() {
+        let config: Config = "# vim-like\nscroll_up = ctrl+k, up\n".parse().unwrap();
+        let key_bindings = config.key_bindings;
+
+        let ctrl_k = KeyEvent::new(KeyCode::Char('k'), KeyModifiers::CONTROL);
+        let up = KeyEvent::new(KeyCode::Up, KeyModifiers::NONE);
+        let enter = KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(config.notify, NotifyLevel::Mentions);
+        assert_eq!(config.placeholder, None);
+        assert_eq!(key_bindings.action(&ctrl_k), Some(Action::ScrollUp));
+        assert_eq!(key_bindings.action(&up), Some(Action::ScrollUp));
+        assert_eq!(key_bindings.action(&enter), Some(Action::Send));
+
+        let config: Config = "placeholder = Say hi = wave \n".parse().unwrap();
+        assert_eq!(config.placeholder.as_deref(), Some("Say hi = wave"));
+
+        assert!("scroll_up ctrl+k".parse::<Config>().is_err());
+        assert!("notify = sometimes".parse::<Config>().is_err());
+        assert!("search = ctrl+f".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn test_save_setting_keeps_the_rest_of_the_file() {
+        let path = std::env::temp_dir().join(format!("lan-chat-config-{}", std::process::id()));
+        std::fs::write(&path, "# notify = all\nscroll_up = ctrl+k\nnotify = all\n").unwrap();
+
+        Config::save_setting(&path, "notify", "off").unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            contents,
+            "# notify = all\nscroll_up = ctrl+k\nnotify = off\n"
+        );
+
+        let config: Config = contents.parse().unwrap();
+        assert_eq!(config.notify, NotifyLevel::Off);
+    }
+}
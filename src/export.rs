@@ -0,0 +1,172 @@
+//! Exports the chat history into a self-contained HTML file.
+//!
+//! The colors mirror the ones used by the terminal user interface.
+
+use std::path::Path;
+
+use crate::networking::messaging::{MessageType, PresenceState};
+
+const HTML_HEADER: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Lan Chat transcript</title>
+<style>
+body { background: #1e1e1e; color: #ffffff; font-family: monospace; white-space: pre-wrap; }
+.info { color: #4ec94e; }
+.leave { color: #e5c07b; }
+.message { color: #ffffff; }
+.error { color: #e06c75; }
+.file { color: #61afef; }
+.sender { font-weight: bold; }
+pre { background: #111111; border-left: 2px solid #5c6370; margin: 0; padding: 2px 6px; }
+img { max-width: 320px; display: block; }
+</style>
+</head>
+<body>
+"#;
+
+const HTML_FOOTER: &str = "</body>\n</html>\n";
+
+/// Escapes the characters that have a special meaning in HTML.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+/// Renders the messages into an HTML document, one `<div>` per line as shown in the chat.
+///
+/// Received files are linked and received images are shown inline, pointing to where they were saved in the current directory.
+/// Senders are centered in a column `name_width` characters wide.
+pub fn export_html(messages: &[MessageType], name_width: usize) -> String {
+    let current_dir = std::env::current_dir().unwrap_or_default();
+    let mut html = String::from(HTML_HEADER);
+
+    for message in messages {
+        let (class, content) = match message {
+            MessageType::Info(info) => ("info", escape_html(info)),
+            MessageType::Notice(kind, text) => {
+                ("info", escape_html(&format!("{}{}", kind.prefix(), text)))
+            }
+            MessageType::Leave(leave) => {
+                ("leave", format!("{} has left the chat", escape_html(leave)))
+            }
+            MessageType::Message(source, message, ..) => (
+                "message",
+                format!(
+                    "<span class=\"sender\">{:^width$}</span>: {}",
+                    escape_html(source),
+                    escape_html(message),
+                    width = name_width
+                ),
+            ),
+            MessageType::Code {
+            sender, lang, body, ..
+        } => (
+                "message",
+                format!(
+                    "<span class=\"sender\">{:^width$}</span>:<pre><code class=\"language-{}\">{}</code></pre>",
+                    escape_html(sender),
+                    escape_html(lang),
+                    escape_html(body),
+                    width = name_width
+                ),
+            ),
+            MessageType::Error(error) => ("error", escape_html(error)),
+            MessageType::Presence { nick, state } => (
+                "leave",
+                match state {
+                    PresenceState::Active => format!("{} is back", escape_html(nick)),
+                    _ => format!(
+                        "{} is now {}",
+                        escape_html(nick),
+                        escape_html(&state.to_string())
+                    ),
+                },
+            ),
+            MessageType::File(file_name, _, _) | MessageType::SavedFile { file_name, .. } => {
+                let file_name_only = file_name_only(file_name, "downloaded_file");
+                (
+                    "file",
+                    format!(
+                        "Received file: <a href=\"{}\">{}</a>",
+                        escape_html(&current_dir.join(file_name_only).to_string_lossy()),
+                        escape_html(file_name_only)
+                    ),
+                )
+            }
+            MessageType::Image(image_name, _) | MessageType::SavedImage { image_name, .. } => {
+                let image_name_only = file_name_only(image_name, "downloaded_image");
+                (
+                    "file",
+                    format!(
+                        "Received image: {}<img src=\"{}\" alt=\"{}\">",
+                        escape_html(image_name_only),
+                        escape_html(&current_dir.join(image_name_only).to_string_lossy()),
+                        escape_html(image_name_only)
+                    ),
+                )
+            }
+            MessageType::Poll {
+                id, question, options,
+            } => (
+                "info",
+                format!(
+                    "Poll #{}: {}<ol>{}</ol>",
+                    id,
+                    escape_html(question),
+                    options
+                        .iter()
+                        .map(|option| format!("<li>{}</li>", escape_html(option)))
+                        .collect::<String>()
+                ),
+            ),
+            _ => continue,
+        };
+
+        html.push_str(&format!("<div class=\"{}\">{}</div>\n", class, content));
+    }
+
+    html.push_str(HTML_FOOTER);
+    html
+}
+
+/// Extracts the file name, ignoring any path components.
+fn file_name_only<'a>(file_name: &'a str, default: &'a str) -> &'a str {
+    Path::new(file_name)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_bodies_are_escaped() {
+        let html = export_html(
+            &[MessageType::Message(
+                "<b>eve</b>".to_string(),
+                "<script>alert('hi')</script> & more".to_string(),
+                0,
+                vec![],
+            )],
+            10,
+        );
+
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&lt;script&gt;alert(&#39;hi&#39;)&lt;/script&gt; &amp; more"));
+        assert!(html.contains("&lt;b&gt;eve&lt;/b&gt;"));
+    }
+}
@@ -0,0 +1,149 @@
+//! Code blocks: recognizing them in typed input and highlighting them for display.
+//!
+//! Highlighting is done by syntect with the syntaxes and themes it comes with, its styles turned into the ones
+//! of the terminal. Languages it doesn't know are shown plain.
+
+use std::sync::OnceLock;
+
+use ratatui::style::{Color, Modifier, Style};
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{self, FontStyle, ThemeSet};
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+
+/// Opens and closes a code block.
+const FENCE: &str = "```";
+
+/// The syntect theme code is highlighted with, one of those it comes with.
+const THEME: &str = "base16-ocean.dark";
+
+static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
+
+/// The syntax of a language, by its name or file extension, e.g. `rust` or `rs`.
+fn syntax(lang: &str) -> Option<&'static SyntaxReference> {
+    // Not among the syntaxes syntect comes with, but close enough to these
+    let lang = match lang.to_lowercase().as_str() {
+        "typescript" | "ts" => "js".to_string(),
+        "shell" => "sh".to_string(),
+        lang => lang.to_string(),
+    };
+    SYNTAXES
+        .get_or_init(SyntaxSet::load_defaults_newlines)
+        .find_syntax_by_token(&lang)
+}
+
+/// Splits a fenced code block into its language and body.
+///
+/// The language is the word right after the opening fence, and may be left out. On a single line, as in
+/// "```rust let x = 1;```", the first word is only taken as the language if it names a known one.
+/// Returns `None` if the input isn't a code block or has nothing in it.
+pub fn parse_code_block(input: &str) -> Option<(String, String)> {
+    let rest = input.trim().strip_prefix(FENCE)?;
+    let rest = rest.strip_suffix(FENCE).unwrap_or(rest);
+
+    let (lang, body) = match rest.split_once('\n') {
+        Some((lang, body)) => (lang.trim(), body),
+        None => match rest.trim_start().split_once(' ') {
+            Some((lang, body)) if syntax(lang).is_some() => (lang, body),
+            _ => ("", rest),
+        },
+    };
+
+    let body = body.trim_end().trim_start_matches('\n');
+    if body.trim().is_empty() {
+        return None;
+    }
+    Some((lang.to_lowercase(), body.to_string()))
+}
+
+/// Highlights the lines of a code block, as pieces of text and their style. `None` for unknown languages.
+pub fn highlight(lang: &str, body: &str) -> Option<Vec<Vec<(Style, String)>>> {
+    let syntax = syntax(lang)?;
+    let syntaxes = SYNTAXES.get()?;
+    let theme = &THEME_SET.get_or_init(ThemeSet::load_defaults).themes[THEME];
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    body.lines()
+        .map(|line| {
+            // The syntaxes expect lines to end with a newline, which isn't shown
+            let line = format!("{}\n", line);
+            let pieces = highlighter.highlight_line(&line, syntaxes).ok()?;
+            Some(
+                pieces
+                    .into_iter()
+                    .map(|(style, text)| (to_style(style), text.trim_end_matches('\n').to_string()))
+                    .filter(|(_, text)| !text.is_empty())
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+/// Turns a syntect style into a terminal one. Only the text is colored, the background is left to the block.
+fn to_style(style: highlighting::Style) -> Style {
+    let highlighting::Color { r, g, b, .. } = style.foreground;
+    let mut modifiers = Modifier::empty();
+    if style.font_style.contains(FontStyle::BOLD) {
+        modifiers |= Modifier::BOLD;
+    }
+    if style.font_style.contains(FontStyle::ITALIC) {
+        modifiers |= Modifier::ITALIC;
+    }
+    if style.font_style.contains(FontStyle::UNDERLINE) {
+        modifiers |= Modifier::UNDERLINED;
+    }
+    Style::default()
+        .fg(Color::Rgb(r, g, b))
+        .add_modifier(modifiers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_blocks_are_parsed() {
+        assert_eq!(
+            parse_code_block("```rust\nfn main() {}\n```"),
+            Some(("rust".to_string(), "fn main() {}".to_string()))
+        );
+        assert_eq!(
+            parse_code_block("```Python print(1)```"),
+            Some(("python".to_string(), "print(1)".to_string()))
+        );
+        assert_eq!(
+            parse_code_block("```let x = 1;"),
+            Some((String::new(), "let x = 1;".to_string()))
+        );
+        assert_eq!(parse_code_block("```rust\n```"), None);
+        assert_eq!(parse_code_block("no code here"), None);
+    }
+
+    #[test]
+    fn test_code_is_highlighted() {
+        let lines = highlight("rust", "let s = \"a\";\n// done").unwrap();
+        assert_eq!(lines.len(), 2);
+        let text: String = lines[0].iter().map(|(_, text)| text.as_str()).collect();
+        assert_eq!(text, "let s = \"a\";");
+        // The keyword, the string and the comment each stand out, looked up by position as syntect splits
+        // pieces by scope, e.g. quotes apart from what they enclose
+        let style_at = |line: &[(Style, String)], mut offset: usize| {
+            for (style, text) in line {
+                if offset < text.len() {
+                    return *style;
+                }
+                offset -= text.len();
+            }
+            panic!("no piece at the offset");
+        };
+        let keyword = style_at(&lines[0], 0);
+        let string = style_at(&lines[0], 9);
+        let comment = style_at(&lines[1], 3);
+        assert_ne!(keyword, string);
+        assert_ne!(string, comment);
+        assert_ne!(keyword, comment);
+
+        assert!(highlight("rs", "fn main() {}").is_some());
+        assert!(highlight("ts", "let x = 1;").is_some());
+        assert_eq!(highlight("cobol", "MOVE 1 TO X"), None);
+    }
+}
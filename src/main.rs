@@ -1,20 +1,53 @@
 #![doc = include_str!("../README.md")]
 
+use crate::config::Config;
+use crate::networking::client::{
+    install_leave_handlers, run_client, ClientState, ALTERNATE_SCREEN, DEFAULT_SCROLLBACK,
+};
+use crate::networking::connection::Endpoint;
+use crate::networking::discovery::{discover, DISCOVERY_TIMEOUT};
+use crate::networking::error::ChatError;
+use crate::networking::mdns::resolve_server_addr;
+use crate::networking::messaging::{
+    name_length, random_pseudonym, MessageType, DEFAULT_MAX_NAME_LENGTH, DEFAULT_ROOM,
+    NAME_LENGTH_CEILING,
+};
+#[cfg(unix)]
+use crate::networking::server::run_unix_server;
+use crate::networking::server::{
+    bind, get_local_ipv4, run_server, serve, Duplicates, ServerConfig, BIND_FAILED_EXIT_CODE,
+    DEFAULT_HISTORY, DEFAULT_MAX_FILE_SIZE,
+};
+use crate::networking::{crypto, signing, transcript};
+use crate::plain::run_plain;
+use crate::thumbnail::{GraphicsSupport, Thumbnails};
+use crate::timezone::TimeZone;
+use crate::tui_handler::{
+    color_enabled, handle_events, ui, Theme, UiState, IDLE_REDRAW_INTERVAL, REDRAW_INTERVAL,
+};
 use clap::Parser;
-use env_logger::Builder;
-use crate::networking::client::run_client;
-use crate::networking::messaging::MessageType;
-use crate::networking::server::{get_local_ipv4, run_server};
-use crate::tui_handler::{handle_events, ui, MAX_NAME_LENGTH};
+use env_logger::{Builder, Target};
 use log::*;
-use std::io::{self, stdout};
-use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
+use std::fs::OpenOptions;
+use std::io::{self, stdout, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, SystemTime};
+mod clipboard;
+mod config;
+mod export;
+mod highlight;
 mod networking;
+mod plain;
+mod thumbnail;
+mod timezone;
 mod tui_handler;
 
 use crossterm::{
-    event::{DisableMouseCapture, EnableMouseCapture},
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind, KeyModifiers,
+    },
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::prelude::*;
@@ -27,41 +60,385 @@ struct Args {
     /// Use to start the application as a server.
     #[arg(short, long)]
     is_server: bool,
-    /// The IP address of the target server.
+    /// Also join the chat from this terminal when running as a server. Server logs then only go to the log file.
+    #[arg(long, requires = "is_server")]
+    host_and_join: bool,
+    /// The address of the target server, as `ip:port` or `host:port`. `.local` hosts are also looked up over mDNS.
     #[arg(short, long)]
     server_ip: Option<String>,
+    /// Use the Unix domain socket at this path instead of TCP, listening on it as a server or connecting to it as a client.
+    #[cfg(unix)]
+    #[arg(long, conflicts_with_all = ["server_ip", "discover", "bind", "host_and_join"])]
+    socket: Option<PathBuf>,
+    /// Look for servers on the LAN and pick one from a list, when no server IP is given.
+    #[arg(short, long)]
+    discover: bool,
     /// The pseudonym of the user.
     #[arg(short, long)]
     pseudonym: Option<String>,
+    /// The longest pseudonym allowed, in characters. Servers shorten longer ones, and never allow more than 32.
+    #[arg(
+        long,
+        default_value_t = DEFAULT_MAX_NAME_LENGTH,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=NAME_LENGTH_CEILING as u64)
+    )]
+    max_name_length: usize,
+    /// The address to listen on when running as a server, instead of the best guess at the LAN address.
+    #[arg(long)]
+    bind: Option<String>,
+    /// The port to listen on when running as a server, instead of any free one.
+    #[arg(long, default_value_t = 0)]
+    port: u16,
+    /// The largest file in bytes the server relays, when running as a server.
+    #[arg(long, default_value_t = DEFAULT_MAX_FILE_SIZE)]
+    max_file_size: u64,
+    /// What the server does when someone connects under the pseudonym of another user on the same machine: give
+    /// them a numbered pseudonym (rename), refuse them (reject) or disconnect the other user (replace).
+    #[arg(long, default_value_t = Duplicates::Rename)]
+    duplicates: Duplicates,
+    /// Only let users join under these pseudonyms, ignoring case, when running as a server.
+    #[arg(long, value_delimiter = ',', value_name = "NICKS")]
+    allow_nicks: Vec<String>,
+    /// Only let users join under the pseudonyms listed in this file, one per line, when running as a server.
+    #[arg(long, value_name = "FILE")]
+    allow_nicks_file: Option<PathBuf>,
+    /// Refuse to relay files, when running as a server.
+    #[arg(long)]
+    no_files: bool,
+    /// Refuse to relay images, when running as a server.
+    #[arg(long)]
+    no_images: bool,
+    /// The room to join on the server.
+    #[arg(short, long, default_value = DEFAULT_ROOM)]
+    room: String,
+    /// The number of recent messages replayed to users joining a room, when running as a server. Use 0 to keep no history.
+    #[arg(long, default_value_t = DEFAULT_HISTORY)]
+    history: usize,
+    /// Log traffic metrics every this many seconds (10 if not given), when running as a server.
+    #[arg(long, value_name = "SECONDS", num_args = 0..=1, default_missing_value = "10")]
+    metrics: Option<u64>,
+    /// The most detailed level of log messages shown (error, warn, info, debug or trace).
+    #[arg(long, default_value_t = LevelFilter::Info)]
+    log_level: LevelFilter,
+    /// The IANA time zone times are shown and logged in, e.g. Europe/Berlin, instead of the system's own.
+    #[arg(long)]
+    timezone: Option<String>,
+    /// Also append log messages to this file.
+    #[arg(long)]
+    log_file: Option<String>,
+    /// Append every message sent and received by the client to this file, one JSON object per line, e.g. for bug reports.
+    #[arg(long)]
+    transcript: Option<PathBuf>,
+    /// Encrypt messages and files with a passphrase, so that only clients started with the same one can read
+    /// them, and not the server. It is read from LAN_CHAT_PASSPHRASE, or asked for without showing it.
+    #[arg(long)]
+    passphrase: bool,
+    /// Sign messages with a key made for this session, so that other clients can tell they really come from it.
+    #[arg(long)]
+    sign: bool,
+    /// Use a plain line based interface instead of the full screen one, e.g. for screen readers.
+    #[arg(long)]
+    plain: bool,
+    /// Draw the chat in the terminal's normal screen instead of the alternate one, so that it stays in the
+    /// scrollback after quitting.
+    #[arg(long, conflicts_with = "plain")]
+    no_alt_screen: bool,
+    /// Join to watch the room only, with no input box. The server refuses anything sent as a spectator.
+    #[arg(long)]
+    spectator: bool,
+    /// Show how many others each of your messages was delivered to.
+    #[arg(long)]
+    show_recipients: bool,
+    /// The number of messages kept on screen before the oldest ones are dropped.
+    #[arg(long, default_value_t = DEFAULT_SCROLLBACK)]
+    scrollback: usize,
+    /// The config file to read, instead of the one in the user's config directory.
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+
+/// Writes everything to both the terminal and a log file.
+struct Tee(std::fs::File);
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(buf)?;
+        self.0.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()?;
+        self.0.flush()
+    }
+}
+
+/// Gathers the pseudonyms given with `--allow-nicks` and those in the `--allow-nicks-file`, if either is used.
+///
+/// Blank lines and lines starting with `#` in the file are skipped.
+fn allowed_nicks(nicks: &[String], file: Option<&Path>) -> io::Result<Option<Vec<String>>> {
+    let mut allowed: Vec<String> = nicks
+        .iter()
+        .map(|nick| nick.trim().to_string())
+        .filter(|nick| !nick.is_empty())
+        .collect();
+    if let Some(file) = file {
+        allowed.extend(
+            std::fs::read_to_string(file)?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_string),
+        );
+    }
+
+    Ok((!nicks.is_empty() || file.is_some()).then_some(allowed))
+}
+
+/// Returns the passphrase in `LAN_CHAT_PASSPHRASE`, or asks for it without echoing what is typed.
+fn read_passphrase() -> io::Result<String> {
+    if let Ok(passphrase) = std::env::var(crypto::PASSPHRASE_VAR) {
+        return match passphrase.is_empty() {
+            true => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is empty", crypto::PASSPHRASE_VAR),
+            )),
+            false => Ok(passphrase),
+        };
+    }
+    if !io::stdin().is_terminal() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Set {} to give the passphrase", crypto::PASSPHRASE_VAR),
+        ));
+    }
+
+    loop {
+        print!("Passphrase: ");
+        io::Write::flush(&mut io::stdout())?;
+        enable_raw_mode()?;
+        let typed = read_hidden_line();
+        disable_raw_mode()?;
+        println!();
+
+        let passphrase = typed?;
+        if !passphrase.is_empty() {
+            return Ok(passphrase);
+        }
+        println!("The passphrase cannot be empty");
+    }
+}
+
+/// Reads a line from the terminal in raw mode, so that nothing typed is shown. Ctrl-C gives up.
+fn read_hidden_line() -> io::Result<String> {
+    let mut line = String::new();
+    loop {
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Enter => return Ok(line),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "No passphrase given",
+                ));
+            }
+            KeyCode::Char(c) => line.push(c),
+            KeyCode::Backspace => {
+                line.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Lists the servers heard from on the LAN and asks which one to join.
+fn pick_discovered_server() -> io::Result<Option<String>> {
+    println!("Looking for servers on the LAN...");
+    let servers = discover(DISCOVERY_TIMEOUT)?;
+    if servers.is_empty() {
+        return Ok(None);
+    }
+
+    for (index, server) in servers.iter().enumerate() {
+        println!(
+            "{}. {} (rooms: {})",
+            index + 1,
+            server.addr,
+            server.rooms.join(", ")
+        );
+    }
+
+    loop {
+        print!("Pick a server (1-{}, default 1): ", servers.len());
+        io::Write::flush(&mut io::stdout())?;
+        let mut choice = String::new();
+        if io::stdin().read_line(&mut choice)? == 0 {
+            return Ok(None);
+        }
+
+        let choice = choice.trim();
+        let index = if choice.is_empty() {
+            Some(0)
+        } else {
+            choice.parse::<usize>().ok().and_then(|n| n.checked_sub(1))
+        };
+        match index.and_then(|index| servers.get(index)) {
+            Some(server) => return Ok(Some(server.addr.clone())),
+            None => println!("Please enter a number between 1 and {}", servers.len()),
+        }
+    }
+}
+
+/// Exits with `BIND_FAILED_EXIT_CODE` after explaining why when the server can't listen, and passes on any
+/// other error.
+fn exit_if_unbound(e: ChatError) -> Box<dyn std::error::Error> {
+    if matches!(e, ChatError::Bind { .. }) {
+        eprintln!("{}", e);
+        std::process::exit(BIND_FAILED_EXIT_CODE);
+    }
+    e.into()
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    Builder::new().filter(None, LevelFilter::Info).init();
+    // Loaded before logging starts so that every log line is in the zone, any problem is logged once it has
+    let zone = match &args.timezone {
+        Some(name) => TimeZone::named(name),
+        None => TimeZone::local(),
+    };
+    let zone_error = zone.as_ref().err().cloned();
+    timezone::set(zone.unwrap_or_else(|_| TimeZone::utc()));
+
+    let mut builder = Builder::new();
+    builder.filter(None, args.log_level);
+    builder.format(|buf, record| {
+        let style = buf.default_level_style(record.level());
+        writeln!(
+            buf,
+            "[{} {style}{:<5}{style:#} {}] {}",
+            timezone::timestamp(SystemTime::now()),
+            record.level(),
+            record.target(),
+            record.args()
+        )
+    });
+    match &args.log_file {
+        // The server's logs would be drawn over the chat
+        Some(log_file) if args.host_and_join => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(log_file)?;
+            builder.target(Target::Pipe(Box::new(file)));
+        }
+        Some(log_file) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(log_file)?;
+            builder.target(Target::Pipe(Box::new(Tee(file))));
+        }
+        None if args.host_and_join => {
+            builder.filter(None, LevelFilter::Off);
+        }
+        None => {}
+    }
+    builder.init();
+    if let Some(e) = zone_error {
+        warn!("Showing times in UTC, {}", e);
+    }
+
+    let mut hosted = None;
 
     if args.is_server {
-        run_server(get_local_ipv4()?.as_str())?;
-        return Ok(());
+        let config = ServerConfig {
+            port: args.port,
+            max_file_size: args.max_file_size,
+            max_name_length: args.max_name_length,
+            history: args.history,
+            metrics_interval: args.metrics.map(Duration::from_secs),
+            allow_files: !args.no_files,
+            allow_images: !args.no_images,
+            duplicates: args.duplicates,
+            allowed_nicks: allowed_nicks(&args.allow_nicks, args.allow_nicks_file.as_deref())?,
+        };
+        #[cfg(unix)]
+        if let Some(socket) = &args.socket {
+            run_unix_server(socket, config)?;
+            return Ok(());
+        }
+        let server_ip = match args.bind {
+            Some(server_ip) => server_ip,
+            None => get_local_ipv4()?,
+        };
+        if !args.host_and_join {
+            run_server(&server_ip, config).map_err(exit_if_unbound)?;
+            return Ok(());
+        }
+
+        // Bound here so that the address to join is known before the server starts
+        let listener = bind(&server_ip, config.port).map_err(exit_if_unbound)?;
+        hosted = Some(Endpoint::Tcp(listener.local_addr()?));
+        std::thread::spawn(move || {
+            if let Err(e) = serve(listener, config, false) {
+                error!("Server stopped: {}", e);
+            }
+        });
+    }
+
+    let config = Config::load(args.config.as_deref())?;
+    if let Some(path) = &args.transcript {
+        transcript::start(path)?;
+    }
+    if args.passphrase {
+        crypto::start(&read_passphrase()?)?;
+    }
+    if args.sign {
+        signing::start()?;
     }
 
     let message_vector: Arc<Mutex<Vec<MessageType>>> = Arc::new(Mutex::new(Vec::new()));
     let message_vector_clone = Arc::clone(&message_vector);
 
     let pseudonym = match args.pseudonym {
-        Some(pseudonym) if (pseudonym.len() <= MAX_NAME_LENGTH && pseudonym.is_empty()) => {
+        Some(pseudonym)
+            if !pseudonym.is_empty() && name_length(&pseudonym) <= args.max_name_length =>
+        {
             pseudonym
         }
         Some(_) | None => {
             let mut pseudonym = String::new();
 
             loop {
-                print!("Enter your pseudonym (0 <= size <= {}): ", MAX_NAME_LENGTH);
+                let suggested = random_pseudonym(args.max_name_length);
+                match &suggested {
+                    Some(suggested) => print!(
+                        "Enter your pseudonym (1 to {} characters), or nothing to be {}: ",
+                        args.max_name_length, suggested
+                    ),
+                    None => print!(
+                        "Enter your pseudonym (1 to {} characters): ",
+                        args.max_name_length
+                    ),
+                }
                 io::Write::flush(&mut io::stdout())?;
                 io::stdin().read_line(&mut pseudonym)?;
                 pseudonym = pseudonym.trim().to_string();
 
-                if pseudonym.len() > MAX_NAME_LENGTH {
-                    println!("Pseudonym too long (currently {} chars). Please enter a pseudonym with less than {} characters", pseudonym.len(), MAX_NAME_LENGTH);
+                if pseudonym.is_empty() {
+                    if let Some(suggested) = suggested {
+                        println!("You are {}", suggested);
+                        pseudonym = suggested;
+                        break;
+                    }
+                }
+                if name_length(&pseudonym) > args.max_name_length {
+                    println!("Pseudonym too long (currently {} characters). Please enter a pseudonym of at most {} characters", name_length(&pseudonym), args.max_name_length);
                     pseudonym = String::new();
                     continue;
                 } else if pseudonym.is_empty() {
@@ -77,59 +454,170 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
-    let server_ip = match args.server_ip {
-        Some(server_ip) => server_ip,
-        None => {
-            println!("Please provide a target server IP address to connect to it. Try lan-chat --help for more info");
+    #[cfg(unix)]
+    let socket = args.socket.map(Endpoint::Unix);
+    #[cfg(not(unix))]
+    let socket = None;
+    let server_endpoint = match (hosted.or(socket), args.server_ip) {
+        (Some(endpoint), _) => endpoint,
+        (None, Some(server_ip)) => Endpoint::Tcp(resolve_server_addr(&server_ip)?),
+        (None, None) if args.discover => match pick_discovered_server()? {
+            Some(server_ip) => Endpoint::Tcp(resolve_server_addr(&server_ip)?),
+            None => {
+                println!("No servers found on the LAN. Use --server-ip to connect to one directly");
+                return Ok(());
+            }
+        },
+        (None, None) => {
+            println!("Please provide a target server IP address (or a socket path with --socket) to connect to it. Try lan-chat --help for more info");
             return Ok(());
         }
     };
 
-    let mut stream = TcpStream::connect(server_ip)?;
-    let mut stream_clone = stream.try_clone()?;
+    let mut stream = server_endpoint.connect()?;
+    let mut client_state = ClientState::new(stream.try_clone()?);
+    client_state.scrollback = args.scrollback;
+    client_state.max_name_length = args.max_name_length;
+    client_state.show_recipients = args.show_recipients;
+    client_state.spectator = args.spectator;
+    client_state.notify = Arc::new(Mutex::new(config.notify));
+    client_state.config_path = Config::path(args.config.as_deref());
+    install_leave_handlers(&client_state.stream)?;
+
+    let client_state_clone = client_state.clone();
 
     let pseduonym_clone = pseudonym.clone();
     std::thread::spawn(move || {
-        run_client(&mut stream, message_vector_clone, pseudonym.clone()).unwrap();
+        run_client(
+            &mut stream,
+            message_vector_clone,
+            pseudonym.clone(),
+            args.room,
+            client_state_clone,
+        )
+        .unwrap();
     });
 
+    if args.plain {
+        run_plain(message_vector, &client_state, &pseduonym_clone)?;
+        return Ok(());
+    }
+
     enable_raw_mode()?;
-    crossterm::execute!(stdout(), EnterAlternateScreen, EnableMouseCapture)?;
-    let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
+    crossterm::execute!(stdout(), EnableMouseCapture)?;
+    let mut terminal = if args.no_alt_screen {
+        // Drawn below what is already on screen, the shell's output is scrolled up rather than cleared
+        let (_, height) = crossterm::terminal::size()?;
+        Terminal::with_options(
+            CrosstermBackend::new(stdout()),
+            TerminalOptions {
+                viewport: Viewport::Inline(height),
+            },
+        )?
+    } else {
+        crossterm::execute!(stdout(), EnterAlternateScreen)?;
+        ALTERNATE_SCREEN.store(true, Ordering::SeqCst);
+        Terminal::new(CrosstermBackend::new(stdout()))?
+    };
     terminal.show_cursor()?;
     let mut text_area = TextArea::default();
-    let mut scroll = 0;
+    let color_enabled = color_enabled();
+    let graphics_support = if color_enabled {
+        GraphicsSupport::detect()
+    } else {
+        GraphicsSupport::Ascii
+    };
+    let mut ui_state = UiState {
+        key_bindings: config.key_bindings,
+        theme: Theme::new(color_enabled),
+        thumbnails: Thumbnails::new(graphics_support),
+        pseudonym: pseduonym_clone.clone(),
+        ..Default::default()
+    };
     text_area.set_cursor_line_style(Style::default());
-    text_area.set_placeholder_text("Enter message here");
+    text_area.set_placeholder_text(
+        config
+            .placeholder
+            .as_deref()
+            .unwrap_or("Enter message here"),
+    );
 
     // Main loop
+    // Input events are read on a thread of their own, so that the main loop can sleep until there is something to do
+    let (event_sender, events) = mpsc::channel();
+    let input_changes = Arc::clone(&client_state.changes);
+    std::thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            if event_sender.send(event).is_err() {
+                break;
+            }
+            input_changes.mark();
+        }
+    });
+
     let mut should_quit = false;
+    let mut drawn = None; // Generation last drawn
+    let mut timed_out = false;
     while !should_quit {
-        terminal.draw(|f| ui(f, Arc::clone(&message_vector), &mut text_area, &mut scroll))?;
-        should_quit = match handle_events(
-            Arc::clone(&message_vector),
-            &mut text_area,
-            &mut stream_clone,
-            &mut scroll,
-            pseduonym_clone.clone(),
-        ) {
-            Ok(should_quit) => should_quit,
-            Err(e) => {
-                message_vector
-                    .lock()
-                    .unwrap()
-                    .push(MessageType::Error(e.to_string()));
-                false
+        let generation = client_state.changes.current();
+        if drawn != Some(generation) || timed_out {
+            drawn = Some(generation);
+            terminal.draw(|f| {
+                ui(
+                    f,
+                    Arc::clone(&message_vector),
+                    &mut text_area,
+                    &mut ui_state,
+                    &client_state,
+                )
+            })?;
+        }
+        client_state.ring_bell()?;
+
+        // Sleep until an input event or a message comes, waking up now and then while an Ack is late
+        let timeout = if client_state.awaiting_ack() {
+            REDRAW_INTERVAL
+        } else {
+            IDLE_REDRAW_INTERVAL
+        };
+        timed_out = client_state.changes.wait_past(generation, timeout) == generation;
+
+        for event in events.try_iter() {
+            should_quit = match handle_events(
+                event,
+                Arc::clone(&message_vector),
+                &mut text_area,
+                &mut ui_state,
+                &client_state,
+                pseduonym_clone.clone(),
+            ) {
+                Ok(should_quit) => should_quit,
+                Err(e) => {
+                    message_vector
+                        .lock()
+                        .unwrap()
+                        .push(MessageType::Error(e.to_string()));
+                    client_state.mark_changed();
+                    false
+                }
+            };
+            if should_quit {
+                break;
             }
         }
     }
 
     disable_raw_mode()?;
-    crossterm::execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
+    crossterm::execute!(terminal.backend_mut(), DisableMouseCapture)?;
+    if args.no_alt_screen {
+        // The last screen of the chat is left as it is, with the shell carrying on below it
+        let area = terminal.get_frame().size();
+        terminal.set_cursor(0, area.bottom().saturating_sub(1))?;
+        println!();
+    } else {
+        crossterm::execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+        ALTERNATE_SCREEN.store(false, Ordering::SeqCst);
+    }
     terminal.show_cursor()?;
 
     Ok(())
@@ -1,17 +1,50 @@
 //! Contains the client code for the chat program
-//! 
+//!
 //! Includes the main client loop and the function to run the client.
-//! Also contains the tips that are displayed to the user when they join the chat.
+//! Also contains the tips that are displayed to the user when they join the chat,
+//! and the handlers that announce the client's departure when it exits abruptly.
 
-use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+
+use crossterm::{
+    event::DisableMouseCapture,
+    terminal::{disable_raw_mode, LeaveAlternateScreen},
+};
 extern crate if_addrs;
 use lazy_static::lazy_static;
 use log::*;
 use rand::Rng;
 
-use crate::networking::messaging::{receive_message, send_message, MessageType};
+use crate::config::NotifyLevel;
+use crate::networking::connection::{Connection, Endpoint};
+use crate::networking::crypto;
+use crate::networking::error::ChatError;
+use crate::networking::messaging::{
+    receive_message, MessageType, NoticeKind, PresenceState, DEFAULT_MAX_NAME_LENGTH, DEFAULT_ROOM,
+};
+use crate::networking::signing;
+use crate::networking::stats::SessionStats;
+use crate::networking::transfer::{
+    file_name_only, format_size, is_risky_file, save_to_current_dir, sha256_hex, Transfers,
+};
+
+/// Exit code of a client the server turned away, to tell it apart from a crash.
+pub const REJECTED_EXIT_CODE: i32 = 3;
+
+/// Exit code of a client too old for the server it connected to.
+pub const OUTDATED_EXIT_CODE: i32 = 4;
+
+/// Where newer versions of the program can be found.
+const RELEASES_URL: &str = "https://github.com/Saphereye/lan-chat/releases";
+
+/// Whether the chat is drawn on the alternate screen, which has to be left again on exit.
+pub static ALTERNATE_SCREEN: AtomicBool = AtomicBool::new(false);
 
 lazy_static! {
     /// A vector of tips that are displayed to the user when they join the chat.
@@ -27,73 +60,1551 @@ lazy_static! {
     ]);
 }
 
+/// How often stalled file transfers are checked for.
+const TRANSFER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait between attempts to reconnect to the server.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The most messages kept while disconnected, the oldest ones are dropped beyond this.
+pub const MAX_QUEUED_MESSAGES: usize = 100;
+
+/// How long a sent message can go unacknowledged before it is marked as possibly lost.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many messages are kept by default before the oldest ones are dropped.
+pub const DEFAULT_SCROLLBACK: usize = 5000;
+
+/// Whether the server has broadcast a message sent by this client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delivery {
+    Queued,           // Waiting for the connection to come back
+    Pending(Instant), // When the message was sent
+    Delivered(u32),   // How many others the server sent it to
+    Failed,           // Sending it failed, until the user sends it again
+}
+
+/// A count of the changes to what is shown, which the interface sleeps on until it moves.
+#[derive(Default)]
+pub struct Changes {
+    generation: Mutex<u64>,
+    moved: Condvar,
+}
+
+impl Changes {
+    /// Counts a change and wakes up whoever waits for one.
+    pub fn mark(&self) {
+        *self.generation.lock().unwrap() += 1;
+        self.moved.notify_all();
+    }
+
+    pub fn current(&self) -> u64 {
+        *self.generation.lock().unwrap()
+    }
+
+    /// Waits until there are changes since `seen`, or the timeout passes. Returns the generation then.
+    pub fn wait_past(&self, seen: u64, timeout: Duration) -> u64 {
+        let generation = self.generation.lock().unwrap();
+        let (generation, _) = self
+            .moved
+            .wait_timeout_while(generation, timeout, |generation| *generation == seen)
+            .unwrap();
+        *generation
+    }
+}
+
+/// Chat messages and code blocks seen lately, by sender and nonce, forgetting the oldest ones past a limit.
+#[derive(Default)]
+pub struct RecentIds {
+    order: VecDeque<(String, u64)>,
+    ids: HashSet<(String, u64)>,
+}
+
+impl RecentIds {
+    /// Remembers an id, returning whether it is new.
+    pub fn insert(&mut self, id: (String, u64), limit: usize) -> bool {
+        if !self.ids.insert(id.clone()) {
+            return false;
+        }
+        self.order.push_back(id);
+        while self.order.len() > limit {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, sender: &str, nonce: u64) -> bool {
+        !self.ids.is_empty() && self.ids.contains(&(sender.to_string(), nonce))
+    }
+}
+
+/// The sender and nonce of a chat message or code block.
+fn id_of(message: &MessageType) -> Option<(&str, u64)> {
+    match message {
+        MessageType::Message(sender, _, nonce, _) | MessageType::Code { sender, nonce, .. } => {
+            Some((sender, *nonce))
+        }
+        _ => None,
+    }
+}
+
+/// State shared between the user interface and the threads talking to the server.
+#[derive(Clone)]
+pub struct ClientState {
+    /// The connection to the server used for sending, replaced when reconnecting.
+    ///
+    /// Every message is sent with this locked, whichever thread sends it, so that message frames never interleave.
+    pub stream: Arc<Mutex<Connection>>,
+    /// Set while the thread reading messages from the server is running.
+    pub reader_alive: Arc<AtomicBool>,
+    /// The pseudonym the server last registered us under, put on the chat messages we send.
+    pub registered_as: Arc<Mutex<String>>,
+    /// Set once the server has registered the pseudonym on the current connection, chat messages are queued until then.
+    pub registered: Arc<AtomicBool>,
+    pub transfers: Arc<Mutex<Transfers>>,
+    /// Received files that look executable, held back until the user accepts them.
+    pub pending_files: Arc<Mutex<Vec<MessageType>>>,
+    /// Delivery of the messages sent by this client, by nonce.
+    pub deliveries: Arc<Mutex<HashMap<u64, Delivery>>>,
+    /// Messages typed while disconnected, sent in order once reconnected.
+    pub outbox: Arc<Mutex<VecDeque<MessageType>>>,
+    /// Number of messages kept before the oldest ones are dropped.
+    pub scrollback: usize,
+    /// Number of messages dropped from the start of the message vector so far, so that a position
+    /// in it can be found again after the messages before it are dropped.
+    pub trimmed: Arc<AtomicUsize>,
+    /// Which received messages ring the bell.
+    pub notify: Arc<Mutex<NotifyLevel>>,
+    /// Set when a received message should ring the bell, until the interface rings it.
+    pub bell: Arc<AtomicBool>,
+    /// Where settings changed from the chat are saved.
+    pub config_path: Option<PathBuf>,
+    /// Longest pseudonym allowed, in characters, which is also how wide the sender column is drawn.
+    pub max_name_length: usize,
+    /// Whether our delivered messages show how many others they were sent to.
+    pub show_recipients: bool,
+    /// Latest vote counts of the polls seen, by poll id.
+    pub polls: Arc<Mutex<HashMap<u64, Vec<u32>>>>,
+    /// Marked by every thread that changes what is shown, so that the interface only redraws when needed.
+    pub changes: Arc<Changes>,
+    /// What was sent and received this session, every message sent by the client goes through it.
+    pub stats: Arc<SessionStats>,
+    /// Token the server gave us to take our registration over when reconnecting.
+    pub session: Arc<Mutex<Option<u64>>>,
+    /// Whether we joined to watch only, with no way to send messages.
+    pub spectator: bool,
+    /// Text last held back for looking like binary data, sent if it is submitted again as it is.
+    pub held_back: Arc<Mutex<Option<String>>>,
+    /// Set by `/reconnect` when it closes the connection, so that dropping it isn't reported as an error.
+    pub reconnect_requested: Arc<AtomicBool>,
+    /// Pseudonyms of the users in our room, including ours, as the server has told us.
+    pub roster: Arc<Mutex<Vec<String>>>,
+    /// Set by `/users` until the user list it asked for comes, the ones the server sends on its own aren't shown.
+    pub users_requested: Arc<AtomicBool>,
+    /// Public keys the users in our room sign their messages with, by pseudonym, the first one published wins.
+    pub signing_keys: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    /// Received messages whose signature doesn't check out, shown with a warning.
+    pub unverified: Arc<Mutex<RecentIds>>,
+    /// Messages received or sent lately, so that one coming again is dropped instead of shown twice.
+    pub seen: Arc<Mutex<RecentIds>>,
+}
+
+impl ClientState {
+    pub fn new(stream: Connection) -> Self {
+        ClientState {
+            stream: Arc::new(Mutex::new(stream)),
+            reader_alive: Arc::default(),
+            registered_as: Arc::default(),
+            registered: Arc::default(),
+            transfers: Arc::default(),
+            pending_files: Arc::default(),
+            deliveries: Arc::default(),
+            outbox: Arc::default(),
+            scrollback: DEFAULT_SCROLLBACK,
+            trimmed: Arc::default(),
+            notify: Arc::default(),
+            bell: Arc::default(),
+            config_path: None,
+            max_name_length: DEFAULT_MAX_NAME_LENGTH,
+            show_recipients: false,
+            polls: Arc::default(),
+            changes: Arc::default(),
+            stats: Arc::default(),
+            session: Arc::default(),
+            spectator: false,
+            held_back: Arc::default(),
+            reconnect_requested: Arc::default(),
+            roster: Arc::default(),
+            users_requested: Arc::default(),
+            signing_keys: Arc::default(),
+            unverified: Arc::default(),
+            seen: Arc::default(),
+        }
+    }
+
+    /// Whether a received message is signed and its signature didn't check out, or isn't signed when it should be.
+    pub fn is_unverified(&self, message: &MessageType) -> bool {
+        id_of(message)
+            .is_some_and(|(sender, nonce)| self.unverified.lock().unwrap().contains(sender, nonce))
+    }
+
+    /// Whether a received message is new. A chat message or code block that was seen before under the same
+    /// sender and nonce is not, whether it was replayed or came again with the history of a room. New ones are
+    /// remembered as unverified if their signature doesn't check out, see `signing`.
+    fn check_received(&self, message: &MessageType) -> bool {
+        self.check_with(message, &self.signing_keys.lock().unwrap())
+    }
+
+    /// Like `check_received`, for a message replayed from history, whose signature is checked against the key
+    /// its sender had then rather than the keys of who is here now.
+    fn check_replayed(&self, message: &MessageType, key: Vec<u8>) -> bool {
+        let mut keys = HashMap::new();
+        if let Some((sender, _)) = id_of(message).filter(|_| !key.is_empty()) {
+            keys.insert(sender.to_string(), key);
+        }
+        self.check_with(message, &keys)
+    }
+
+    fn check_with(&self, message: &MessageType, keys: &HashMap<String, Vec<u8>>) -> bool {
+        let Some((sender, nonce)) = id_of(message) else {
+            return true;
+        };
+        let id = (sender.to_string(), nonce);
+        if !self
+            .seen
+            .lock()
+            .unwrap()
+            .insert(id.clone(), self.scrollback)
+        {
+            return false;
+        }
+        if !signing::is_authentic(message, keys) {
+            self.unverified.lock().unwrap().insert(id, self.scrollback);
+        }
+        true
+    }
+
+    /// Tells the interface that what is shown has changed.
+    pub fn mark_changed(&self) {
+        self.changes.mark();
+    }
+
+    /// Whether any of our messages still waits for its Ack.
+    pub fn awaiting_ack(&self) -> bool {
+        self.deliveries
+            .lock()
+            .unwrap()
+            .values()
+            .any(|delivery| matches!(delivery, Delivery::Pending(_)))
+    }
+
+    /// Whether a received message should ring the bell, given the notification level.
+    fn should_notify(&self, message: &MessageType, pseudonym: &str) -> bool {
+        let MessageType::Message(sender, text, ..) = message else {
+            return false;
+        };
+        if sender == pseudonym {
+            return false;
+        }
+        match *self.notify.lock().unwrap() {
+            NotifyLevel::All => true,
+            NotifyLevel::Mentions => is_mention(text, pseudonym),
+            NotifyLevel::Off => false,
+        }
+    }
+
+    /// Rings the terminal bell if a received message asked for it since the last call.
+    pub fn ring_bell(&self) -> std::io::Result<()> {
+        if self.bell.swap(false, Ordering::SeqCst) {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(b"\x07")?;
+            stdout.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Drops the oldest messages beyond the scrollback limit, returning how many were dropped.
+    pub fn trim_scrollback(&self, messages: &mut Vec<MessageType>) -> usize {
+        let excess = messages.len().saturating_sub(self.scrollback);
+        messages.drain(..excess);
+        self.trimmed.fetch_add(excess, Ordering::SeqCst);
+        excess
+    }
+
+    /// Drops every message, counting them as trimmed.
+    pub fn clear_messages(&self, messages: &mut Vec<MessageType>) {
+        self.trimmed.fetch_add(messages.len(), Ordering::SeqCst);
+        messages.clear();
+    }
+
+    /// Sends a chat message or code block, or queues it while the connection is down.
+    ///
+    /// Returns the message dropped from the queue to make room, if it was full. If sending fails, the message is
+    /// marked `Failed` for `retry_failed` to send again and the error is returned.
+    pub fn send_or_queue(
+        &self,
+        stream: &mut Connection,
+        message: MessageType,
+    ) -> Result<Option<MessageType>, ChatError> {
+        let Some((_, nonce)) = id_of(&message) else {
+            return Ok(None);
+        };
+        let mut outbox = self.outbox.lock().unwrap();
+        let mut deliveries = self.deliveries.lock().unwrap();
+
+        // Queued messages go first to keep the order
+        if self.registered.load(Ordering::SeqCst) && outbox.is_empty() {
+            let delivery = match self.stats.send(stream, &self.outgoing(message)) {
+                Ok(_) => Delivery::Pending(Instant::now()),
+                Err(e) => {
+                    deliveries.insert(nonce, Delivery::Failed);
+                    return Err(e);
+                }
+            };
+            deliveries.insert(nonce, delivery);
+            return Ok(None);
+        }
+
+        deliveries.insert(nonce, Delivery::Queued);
+        outbox.push_back(message);
+        if outbox.len() > MAX_QUEUED_MESSAGES {
+            let dropped = outbox.pop_front();
+            if let Some((_, nonce)) = dropped.as_ref().and_then(id_of) {
+                deliveries.remove(&nonce);
+            }
+            return Ok(dropped);
+        }
+        Ok(None)
+    }
+
+    /// Sends again our chat messages and code blocks among `messages` that failed to send, returning how many were
+    /// sent or queued.
+    pub fn retry_failed(
+        &self,
+        stream: &mut Connection,
+        messages: &[MessageType],
+    ) -> Result<usize, ChatError> {
+        let failed: Vec<MessageType> = {
+            let deliveries = self.deliveries.lock().unwrap();
+            messages
+                .iter()
+                .filter_map(|message| match message {
+                    MessageType::Message(_, text, nonce, _)
+                        if deliveries.get(nonce) == Some(&Delivery::Failed) =>
+                    {
+                        // The server puts our registered pseudonym on the message
+                        Some(MessageType::Message(
+                            String::new(),
+                            text.clone(),
+                            *nonce,
+                            vec![],
+                        ))
+                    }
+                    MessageType::Code {
+                        lang, body, nonce, ..
+                    } if deliveries.get(nonce) == Some(&Delivery::Failed) => {
+                        Some(MessageType::Code {
+                            sender: String::new(),
+                            lang: lang.clone(),
+                            body: body.clone(),
+                            nonce: *nonce,
+                            signature: vec![],
+                        })
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let mut retried = 0;
+        for message in failed {
+            self.send_or_queue(stream, message)?;
+            retried += 1;
+        }
+        Ok(retried)
+    }
+
+    /// Prepares a chat message or code block of ours for sending. It gets our registered pseudonym, which the
+    /// server would put on it anyway and encryption binds it to, and is then sealed and signed.
+    pub fn outgoing(&self, message: MessageType) -> MessageType {
+        let pseudonym = self.registered_as.lock().unwrap().clone();
+        let message = match message {
+            MessageType::Message(_, text, nonce, signature) => {
+                MessageType::Message(pseudonym, text, nonce, signature)
+            }
+            MessageType::Code {
+                lang,
+                body,
+                nonce,
+                signature,
+                ..
+            } => MessageType::Code {
+                sender: pseudonym,
+                lang,
+                body,
+                nonce,
+                signature,
+            },
+            message => message,
+        };
+        if let Some((sender, nonce)) = id_of(&message) {
+            // So that it is dropped if it comes back with the history of a room
+            self.seen
+                .lock()
+                .unwrap()
+                .insert((sender.to_string(), nonce), self.scrollback);
+        }
+        signing::sign(crypto::seal(message))
+    }
+
+    /// Marks the current connection as registered and sends the queued messages over it, returning how many were sent.
+    fn mark_registered(&self) -> usize {
+        let mut stream = self.stream.lock().unwrap();
+        let mut outbox = self.outbox.lock().unwrap();
+        let mut deliveries = self.deliveries.lock().unwrap();
+        // Set with the stream locked, so that no message is queued after the queue is emptied
+        self.registered.store(true, Ordering::SeqCst);
+
+        let mut sent = 0;
+        while let Some(message) = outbox.front() {
+            if self
+                .stats
+                .send(&mut stream, &self.outgoing(message.clone()))
+                .is_err()
+            {
+                break;
+            }
+            if let Some((_, nonce)) = id_of(message) {
+                deliveries.insert(nonce, Delivery::Pending(Instant::now()));
+            }
+            outbox.pop_front();
+            sent += 1;
+        }
+        sent
+    }
+}
+
+/// The lines shown when connecting, always in the same order and each shown once
+fn startup_header(
+    local_addr: Option<String>,
+    room: &str,
+    endpoint: &str,
+    tip: String,
+) -> Vec<MessageType> {
+    let mut header = Vec::new();
+    if let Some(addr) = local_addr {
+        header.push(MessageType::Info(format!("Your ip is: {}", addr)));
+    }
+    header.push(MessageType::Info(format!("You are in room: {}", room)));
+    header.push(MessageType::Info(format!(
+        "Connected to server at address: {}",
+        endpoint
+    )));
+    header.push(MessageType::Notice(NoticeKind::Tip, tip));
+    header.push(MessageType::Info(String::new()));
+    header
+}
+
 /// Runs the client. Connects to the server and receives server messages.
 pub fn run_client(
-    stream: &mut TcpStream,
+    stream: &mut Connection,
     message_vector: Arc<Mutex<Vec<MessageType>>>,
     pseudonym: String,
-) -> Result<(), Box<dyn std::error::Error>> {
-    match send_message(stream, &MessageType::Pseudonym(pseudonym)) {
-        Ok(_) => {}
+    room: String,
+    state: ClientState,
+) -> Result<(), ChatError> {
+    // Collect failures while joining so they show up after the header instead of racing it
+    let mut failures = Vec::new();
+
+    if state.spectator {
+        if let Err(e) = state
+            .stats
+            .send(&mut state.stream.lock().unwrap(), &MessageType::Spectate)
+        {
+            failures.push(MessageType::Error(format!(
+                "Failed to join as a spectator: {}",
+                e
+            )));
+        }
+    }
+
+    // Pick the room before announcing ourselves so the join notice goes to the right room
+    if room != DEFAULT_ROOM {
+        let join_room = MessageType::JoinRoom(room.clone());
+        if let Err(e) = state
+            .stats
+            .send(&mut state.stream.lock().unwrap(), &join_room)
+        {
+            failures.push(MessageType::Error(format!(
+                "Failed to join room {}: {}",
+                room, e
+            )));
+        }
+    }
+
+    let pseudonym_message = MessageType::Pseudonym(pseudonym.clone());
+    if let Err(e) = state
+        .stats
+        .send(&mut state.stream.lock().unwrap(), &pseudonym_message)
+    {
+        failures.push(MessageType::Error(format!(
+            "Failed to send pseudonym to server: {}",
+            e
+        )));
+    }
+
+    for key in published_keys(&state) {
+        if let Err(e) = state.stats.send(&mut state.stream.lock().unwrap(), &key) {
+            failures.push(MessageType::Error(format!(
+                "Failed to publish our keys: {}",
+                e
+            )));
+            break;
+        }
+    }
+
+    let server_endpoint = match stream.peer_endpoint() {
+        Ok(endpoint) => endpoint,
         Err(e) => {
-            message_vector
-                .lock()
-                .unwrap()
-                .push(MessageType::Error(format!(
-                    "Failed to send pseudonym to server: {}",
-                    e
-                )));
+            error!("Failed to retrieve server address: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Choose a random tip
+    let mut rng = rand::thread_rng();
+    let index = rng.gen_range(0..TIPS.lock().unwrap().len());
+    let tip = TIPS.lock().unwrap()[index].clone();
+
+    // Push the whole header at once, the reader thread only starts afterwards
+    let mut header = startup_header(
+        stream.local_addr().ok(),
+        &room,
+        &server_endpoint.to_string(),
+        tip,
+    );
+    header.extend(failures);
+    message_vector.lock().unwrap().extend(header);
+
+    // Spawn a thread to ask for the missing parts of stalled file transfers
+    let transfer_state = state.clone();
+    let transfer_message_vector = Arc::clone(&message_vector);
+    thread::spawn(move || loop {
+        thread::sleep(TRANSFER_POLL_INTERVAL);
+        let notices = {
+            let mut stream = transfer_state.stream.lock().unwrap();
+            let mut transfers = transfer_state.transfers.lock().unwrap();
+            transfers.poll_stalled(&mut stream, &transfer_state.stats)
+        };
+        if !notices.is_empty() {
+            transfer_message_vector.lock().unwrap().extend(notices);
+            transfer_state.mark_changed();
         }
-    } // Send the pseudonym to the server
+    });
+
+    // Spawn a thread to read messages from the server, reconnecting whenever the connection drops
+    let mut server_stream = stream.try_clone().unwrap();
+    state.reader_alive.store(true, Ordering::SeqCst);
+    thread::spawn(move || loop {
+        let error = read_messages(&mut server_stream, &message_vector, &state);
+        state.reader_alive.store(false, Ordering::SeqCst);
+        state.registered.store(false, Ordering::SeqCst);
+
+        let notice = if state.reconnect_requested.swap(false, Ordering::SeqCst) {
+            MessageType::Info("Disconnected, connecting again...".to_string())
+        } else if error.is_disconnect() {
+            MessageType::Error("The server closed the connection, reconnecting...".to_string())
+        } else {
+            MessageType::Error(format!(
+                "Lost connection to the server ({}), reconnecting...",
+                error
+            ))
+        };
+        message_vector.lock().unwrap().push(notice);
+        state.mark_changed();
+        let session = *state.session.lock().unwrap();
+        server_stream = reconnect(&server_endpoint, &pseudonym, &room, session, &state);
+        state.stats.reconnected();
+        *state.stream.lock().unwrap() = server_stream.try_clone().unwrap();
+        state.reader_alive.store(true, Ordering::SeqCst);
 
-    if let Ok(s) = stream.local_addr() {
         message_vector
             .lock()
             .unwrap()
-            .push(MessageType::Info(format!("Your ip is: {}", s)));
+            .push(MessageType::Info("Reconnected to server".to_string()));
+        state.mark_changed();
+    });
+
+    Ok(())
+}
+
+/// Reads messages from the server into the message vector until the connection drops, returning why it did.
+fn read_messages(
+    server_stream: &mut Connection,
+    message_vector: &Mutex<Vec<MessageType>>,
+    state: &ClientState,
+) -> ChatError {
+    loop {
+        // Every way around the loop ends here, so whatever the last message changed is shown before the next one
+        state.mark_changed();
+        let message = match receive_message(server_stream) {
+            Ok(message) => {
+                state.stats.received(&message);
+                // Signatures are over the text as sent, so they are checked before it is decrypted
+                let (message, is_new) = match message {
+                    MessageType::Replayed { message, key } => {
+                        let is_new = state.check_replayed(&message, key);
+                        (*message, is_new)
+                    }
+                    message => {
+                        let is_new = state.check_received(&message);
+                        (message, is_new)
+                    }
+                };
+                if !is_new {
+                    debug!("Dropped a message received before: {:?}", id_of(&message));
+                    continue;
+                }
+                crypto::open(message)
+            }
+            Err(e) => return e,
+        };
+        let message = match message {
+            MessageType::FileChunk {
+                transfer_id,
+                file_name,
+                seq,
+                total,
+                data,
+                checksum,
+            } => {
+                let mut transfers = state.transfers.lock().unwrap();
+                match transfers.receive_chunk(transfer_id, &file_name, seq, total, &data, checksum)
+                {
+                    Ok(Some((file_name, contents, checksum))) => {
+                        MessageType::File(file_name, contents, checksum)
+                    }
+                    Ok(None) => continue,
+                    Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                        MessageType::Error(e.to_string())
+                    }
+                    Err(e) => {
+                        MessageType::Error(format!("Failed to write part of {}: {}", file_name, e))
+                    }
+                }
+            }
+            MessageType::File(file_name, contents, checksum) => {
+                if sha256_hex(&contents) == checksum {
+                    MessageType::File(file_name, contents, checksum)
+                } else {
+                    MessageType::Error(format!("checksum mismatch for {}", file_name))
+                }
+            }
+            MessageType::FileResend {
+                transfer_id,
+                missing,
+            } => {
+                let mut stream = state.stream.lock().unwrap();
+                let transfers = state.transfers.lock().unwrap();
+                match transfers.resend(&mut stream, &state.stats, transfer_id, &missing) {
+                    Ok(_) => continue,
+                    Err(e) => MessageType::Error(format!("Failed to resend file chunks: {}", e)),
+                }
+            }
+            MessageType::FileCancel { transfer_id } => {
+                match state.transfers.lock().unwrap().drop_incoming(transfer_id) {
+                    Some(file_name) => MessageType::Info(format!(
+                        "Sending of {} was cancelled",
+                        file_name_only(&file_name)
+                    )),
+                    None => continue,
+                }
+            }
+            MessageType::Rejected(reason) => exit_rejected(&reason),
+            MessageType::UserList(users) => {
+                *state.roster.lock().unwrap() = users.iter().map(|user| user.0.clone()).collect();
+                if !state.users_requested.swap(false, Ordering::SeqCst) {
+                    continue;
+                }
+                MessageType::UserList(users)
+            }
+            MessageType::UserJoined(nick) => {
+                let mut roster = state.roster.lock().unwrap();
+                if !roster.contains(&nick) {
+                    roster.push(nick);
+                }
+                continue;
+            }
+            MessageType::UserLeft(nick) => {
+                state.roster.lock().unwrap().retain(|user| *user != nick);
+                state.signing_keys.lock().unwrap().remove(&nick);
+                crypto::forget(&nick);
+                continue;
+            }
+            MessageType::SigningKey { nick, key } => {
+                state
+                    .signing_keys
+                    .lock()
+                    .unwrap()
+                    .entry(nick)
+                    .or_insert(key);
+                continue;
+            }
+            MessageType::Salt { nick, salt } => {
+                crypto::learn(&nick, &salt);
+                continue;
+            }
+            MessageType::Hello { version, .. } => {
+                let ours = env!("CARGO_PKG_VERSION");
+                if is_newer_release(&version, ours) {
+                    exit_with(
+                        &format!(
+                            "The server runs lan-chat {}, which this lan-chat {} can't talk to. Please update, e.g. with `cargo install lan-chat` or from {}",
+                            version, ours, RELEASES_URL
+                        ),
+                        OUTDATED_EXIT_CODE,
+                    );
+                }
+                MessageType::Info(format!("Server version: {}", version))
+            }
+            MessageType::Welcome(pseudonym) => {
+                *state.registered_as.lock().unwrap() = pseudonym;
+                let flushed = state.mark_registered();
+                if flushed > 0 {
+                    message_vector
+                        .lock()
+                        .unwrap()
+                        .push(MessageType::Info(format!(
+                            "Sent {} queued message(s)",
+                            flushed
+                        )));
+                }
+                continue;
+            }
+            MessageType::Poll {
+                id,
+                question,
+                options,
+            } => {
+                state
+                    .polls
+                    .lock()
+                    .unwrap()
+                    .insert(id, vec![0; options.len()]);
+                MessageType::Poll {
+                    id,
+                    question,
+                    options,
+                }
+            }
+            MessageType::PollUpdate { id, counts } => {
+                state.polls.lock().unwrap().insert(id, counts.clone());
+                MessageType::PollUpdate { id, counts }
+            }
+            MessageType::Resume { token } => {
+                *state.session.lock().unwrap() = Some(token);
+                continue;
+            }
+            MessageType::Ack { nonce, recipients } => {
+                if let Some(delivery) = state.deliveries.lock().unwrap().get_mut(&nonce) {
+                    *delivery = Delivery::Delivered(recipients);
+                }
+                continue;
+            }
+            // Our own messages are already shown from the moment they are sent, the server leaves us out of them
+            MessageType::Message(_, _, nonce, _)
+                if state.deliveries.lock().unwrap().contains_key(&nonce) =>
+            {
+                continue;
+            }
+            message => message,
+        };
+
+        if matches!(message, MessageType::File(..) | MessageType::Image(..)) {
+            state.stats.file_received();
+        }
+
+        // Executable looking files are only saved once the user accepts them
+        if let MessageType::File(file_name, file_contents, _) = &message {
+            if is_risky_file(file_name, file_contents) {
+                let file_name = file_name_only(file_name).to_string();
+                state.pending_files.lock().unwrap().push(message);
+                message_vector
+                    .lock()
+                    .unwrap()
+                    .push(MessageType::Error(format!(
+                        "WARNING: {} could be a program. Only run it if you trust the sender. Type /accept {} to save it",
+                        file_name, file_name
+                    )));
+                continue;
+            }
+        }
+
+        // The server may have registered us under another pseudonym than we asked for
+        if state.should_notify(&message, &state.registered_as.lock().unwrap()) {
+            state.bell.store(true, Ordering::SeqCst);
+        }
+        message_vector.lock().unwrap().push(save_received(message));
+    }
+}
+
+/// Formats how long something has lasted compactly, in the largest whole unit, e.g. `45s`, `12m` or `3h`.
+pub fn format_duration(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m", seconds / 60),
+        _ => format!("{}h", seconds / 3600),
+    }
+}
+
+/// Formats a user of a `UserList`, e.g. `alice · 12m (away) — reviewing PRs`.
+pub fn user_entry(
+    nick: &str,
+    state: &PresenceState,
+    connected: u64,
+    status: &str,
+    spectator: bool,
+) -> String {
+    let mut entry = format!("{} · {}", nick, format_duration(connected));
+    if spectator {
+        entry.push_str(" [spectator]");
+    }
+    if *state != PresenceState::Active {
+        entry.push_str(&format!(" ({})", state));
+    }
+    if !status.is_empty() {
+        entry.push_str(&format!(" — {}", status));
+    }
+    entry
+}
+
+/// Whether a message mentions the pseudonym as a whole word, ignoring case and an `@` before it.
+pub fn is_mention(text: &str, pseudonym: &str) -> bool {
+    !pseudonym.is_empty()
+        && text
+            .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+            .any(|word| word.eq_ignore_ascii_case(pseudonym))
+}
+
+/// Saves a received file or image in the current directory.
+///
+/// A file is replaced by a `SavedFile` right away so that its contents don't stay in memory. An image is kept
+/// whole until its thumbnail has been drawn, and then replaced using `without_contents`.
+pub fn save_received(message: MessageType) -> MessageType {
+    let (MessageType::File(file_name, contents, _) | MessageType::Image(file_name, contents)) =
+        &message
+    else {
+        return message;
     };
 
-    // Print the server's address
-    match stream.peer_addr() {
-        Ok(addr) => {
+    match save_to_current_dir(file_name, contents) {
+        Ok(_) if matches!(message, MessageType::Image(_, _)) => message,
+        Ok(_) => without_contents(&message).unwrap_or(message),
+        Err(e) => MessageType::Error(format!("Failed to write {}: {}", file_name, e)),
+    }
+}
+
+/// Returns the stand-in for a saved file or image that no longer holds its contents.
+pub fn without_contents(message: &MessageType) -> Option<MessageType> {
+    match message {
+        MessageType::File(file_name, contents, checksum) => Some(MessageType::SavedFile {
+            file_name: file_name_only(file_name).to_string(),
+            size: contents.len() as u64,
+            checksum: checksum.clone(),
+        }),
+        MessageType::Image(image_name, contents) => Some(MessageType::SavedImage {
+            image_name: image_name.clone(),
+            size: contents.len() as u64,
+        }),
+        _ => None,
+    }
+}
+
+/// Reads and sends files one after the other from a background thread so that the interface stays responsive.
+///
+/// Each file comes with the message showing its progress, see `send_file`. When there are several, a count
+/// of those sent is added once they have all been tried, a file that fails doesn't stop the others.
+pub fn spawn_file_send(
+    state: &ClientState,
+    message_vector: Arc<Mutex<Vec<MessageType>>>,
+    files: Vec<(String, usize)>,
+) {
+    let state = state.clone();
+    thread::spawn(move || {
+        let sent = files
+            .iter()
+            .filter(|(file_path, progress_line)| {
+                send_file(&state, &message_vector, file_path, *progress_line)
+            })
+            .count();
+        if files.len() > 1 {
             message_vector
                 .lock()
                 .unwrap()
                 .push(MessageType::Info(format!(
-                    "Connected to server at address: {}",
-                    addr
+                    "Sent {} of {} files",
+                    sent,
+                    files.len()
                 )));
+            state.mark_changed();
         }
+    });
+}
+
+/// Reads and sends a file, returning whether all of it was sent.
+///
+/// The message at `progress_line`, counted from the first message ever pushed including those trimmed since,
+/// is kept up to date with the percentage sent, and a summary is added once
+/// the whole file has been written to the stream. Every chunk is written with the stream locked, so chat
+/// messages sent in the meantime go out between chunks rather than in the middle of one.
+fn send_file(
+    state: &ClientState,
+    message_vector: &Mutex<Vec<MessageType>>,
+    file_path: &str,
+    progress_line: usize,
+) -> bool {
+    let started = Instant::now();
+    let file_name = file_name_only(file_path).to_string();
+    let contents = match std::fs::read(file_path) {
+        Ok(contents) => contents,
         Err(e) => {
-            error!("Failed to retrieve server address: {}", e);
-            std::process::exit(1);
+            let mut messages = message_vector.lock().unwrap();
+            let index = progress_line.checked_sub(state.trimmed.load(Ordering::SeqCst));
+            if let Some(line) = index.and_then(|index| messages.get_mut(index)) {
+                *line = MessageType::Error(format!("Failed to read {}: {}", file_path, e));
+            }
+            state.mark_changed();
+            return false;
+        }
+    };
+    let size = contents.len();
+    let progress = |percent| {
+        MessageType::Info(format!(
+            "Sending {} ({}): {}%",
+            file_name,
+            format_size(size),
+            percent
+        ))
+    };
+    {
+        let mut messages = message_vector.lock().unwrap();
+        let index = progress_line.checked_sub(state.trimmed.load(Ordering::SeqCst));
+        if let Some(line) = index.and_then(|index| messages.get_mut(index)) {
+            *line = progress(0);
         }
     }
-
-    // Choose a random tip
-    let mut rng = rand::thread_rng();
-    let index = rng.gen_range(0..TIPS.lock().unwrap().len());
-    let tip = TIPS.lock().unwrap()[index].clone();
-    message_vector
+    let (transfer_id, total) = state
+        .transfers
         .lock()
         .unwrap()
-        .push(MessageType::Info(format!("TIP: {}", tip)));
+        .start_send(file_path.to_string(), contents);
 
-    // make a lot of black lines after this
-    for _ in 0..2 {
-        message_vector
+    let mut percent = 0;
+    for seq in 0..total {
+        let chunk = state
+            .transfers
             .lock()
             .unwrap()
-            .push(MessageType::Info("".to_string()));
+            .outgoing_chunk(transfer_id, seq);
+        let Some(chunk) = chunk else {
+            // Cancelled with /cancel
+            let mut messages = message_vector.lock().unwrap();
+            let index = progress_line.checked_sub(state.trimmed.load(Ordering::SeqCst));
+            if let Some(line) = index.and_then(|index| messages.get_mut(index)) {
+                *line = MessageType::Info(format!(
+                    "Sending {} ({}): cancelled at {}%",
+                    file_name,
+                    format_size(size),
+                    percent
+                ));
+            }
+            state.mark_changed();
+            return false;
+        };
+        let result = state
+            .stats
+            .send(&mut state.stream.lock().unwrap(), &crypto::seal(chunk));
+        if let Err(e) = result {
+            message_vector
+                .lock()
+                .unwrap()
+                .push(MessageType::Error(format!(
+                    "Failed to send {}: {}",
+                    file_name, e
+                )));
+            state.mark_changed();
+            return false;
+        }
+
+        let sent = (seq + 1) * 100 / total;
+        if sent != percent {
+            percent = sent;
+            let mut messages = message_vector.lock().unwrap();
+            let index = progress_line.checked_sub(state.trimmed.load(Ordering::SeqCst));
+            if let Some(line) = index.and_then(|index| messages.get_mut(index)) {
+                *line = progress(percent);
+            }
+            state.mark_changed();
+        }
     }
 
-    // Spawn a thread to read messages from the server
-    let mut server_stream = stream.try_clone().unwrap();
+    state.stats.file_sent();
+    message_vector
+        .lock()
+        .unwrap()
+        .push(MessageType::Info(format!(
+            "Sent {} ({}) in {:.1}s",
+            file_name,
+            format_size(size),
+            started.elapsed().as_secs_f64()
+        )));
+    state.mark_changed();
+    true
+}
+
+/// Reads and sends an image from a background thread so that the interface stays responsive.
+pub fn spawn_image_send(
+    state: &ClientState,
+    message_vector: Arc<Mutex<Vec<MessageType>>>,
+    file_path: String,
+) {
+    let state = state.clone();
     thread::spawn(move || {
-        while let Ok(message) = receive_message(&mut server_stream) {
-            message_vector.lock().unwrap().push(message);
+        let error = match std::fs::read(&file_path) {
+            Ok(contents) => match state.stats.send(
+                &mut state.stream.lock().unwrap(),
+                &crypto::seal(MessageType::Image(file_path, contents)),
+            ) {
+                Ok(_) => {
+                    state.stats.file_sent();
+                    None
+                }
+                Err(e) => Some(format!("Failed to send image: {}", e)),
+            },
+            Err(e) => Some(format!("Failed to read image: {}", e)),
+        };
+        if let Some(error) = error {
+            message_vector
+                .lock()
+                .unwrap()
+                .push(MessageType::Error(error));
+            state.mark_changed();
         }
     });
+}
+
+/// Connects to the server again, retrying until it succeeds, and rejoins the room under the same pseudonym.
+fn reconnect(
+    server_endpoint: &Endpoint,
+    pseudonym: &str,
+    room: &str,
+    session: Option<u64>,
+    state: &ClientState,
+) -> Connection {
+    let stats = &state.stats;
+    loop {
+        thread::sleep(RECONNECT_INTERVAL);
+        let Ok(mut stream) = server_endpoint.connect() else {
+            continue;
+        };
+
+        // First, so that the server lets go of our old connection before we ask for our pseudonym again
+        if let Some(token) = session {
+            if stats
+                .send(&mut stream, &MessageType::Resume { token })
+                .is_err()
+            {
+                continue;
+            }
+        }
+
+        if state.spectator && stats.send(&mut stream, &MessageType::Spectate).is_err() {
+            continue;
+        }
+        if room != DEFAULT_ROOM
+            && stats
+                .send(&mut stream, &MessageType::JoinRoom(room.to_string()))
+                .is_err()
+        {
+            continue;
+        }
+        if stats
+            .send(&mut stream, &MessageType::Pseudonym(pseudonym.to_string()))
+            .is_err()
+        {
+            continue;
+        }
+        if published_keys(state)
+            .iter()
+            .any(|key| stats.send(&mut stream, key).is_err())
+        {
+            continue;
+        }
+        return stream;
+    }
+}
+
+/// The keys published once we have joined, so that the server knows whose they are: the one our messages are
+/// signed with and the salt they are sealed with. Spectators have nothing to sign or seal.
+fn published_keys(state: &ClientState) -> Vec<MessageType> {
+    if state.spectator {
+        return vec![];
+    }
+    let signing_key = signing::public_key().map(|key| MessageType::SigningKey {
+        nick: String::new(),
+        key,
+    });
+    let salt = crypto::salt().map(|salt| MessageType::Salt {
+        nick: String::new(),
+        salt,
+    });
+    signing_key.into_iter().chain(salt).collect()
+}
+
+/// Sends a `Leave` for this client on the given stream and restores the terminal.
+///
+/// Used on the abnormal exit paths, where the main loop never gets to run its own teardown.
+fn leave_and_restore_terminal(stream: &mut Connection) {
+    if let Ok(addr) = stream.local_addr() {
+        let _ = stream.send(&MessageType::Leave(addr));
+    }
+    restore_terminal();
+}
+
+/// Leaves the terminal the way it was before the chat took it over.
+fn restore_terminal() {
+    let _ = disable_raw_mode();
+    let _ = crossterm::execute!(std::io::stdout(), DisableMouseCapture);
+    if ALTERNATE_SCREEN.load(Ordering::SeqCst) {
+        let _ = crossterm::execute!(std::io::stdout(), LeaveAlternateScreen);
+    }
+}
+
+/// Gives up on the chat after the server turned us away, as reconnecting would only be turned away again.
+fn exit_rejected(reason: &str) -> ! {
+    exit_with(
+        &format!("Server rejected connection: {}", reason),
+        REJECTED_EXIT_CODE,
+    )
+}
+
+/// Restores the terminal and exits with the given code, telling the user why once the chat is gone.
+fn exit_with(message: &str, code: i32) -> ! {
+    error!("{}", message);
+    restore_terminal();
+    eprintln!("{}", message);
+    std::process::exit(code);
+}
+
+/// Whether `version` is a release that breaks compatibility with `ours`, going by the semver rules Cargo uses:
+/// a higher major version, or a higher minor version while the major version is 0.
+fn is_newer_release(version: &str, ours: &str) -> bool {
+    let release = |version: &str| {
+        let mut parts = version
+            .split(['.', '-', '+'])
+            .map(|part| part.parse::<u64>().unwrap_or_default());
+        match (
+            parts.next().unwrap_or_default(),
+            parts.next().unwrap_or_default(),
+        ) {
+            (0, minor) => (0, minor),
+            (major, _) => (major, 0),
+        }
+    };
+    release(version) > release(ours)
+}
+
+/// Installs a panic hook and a signal handler (SIGINT, SIGTERM, SIGHUP) that announce the
+/// client's departure to the server before the process goes away.
+///
+/// Nothing can be done for a `kill -9`, in which case the server notices on its next failed read.
+pub fn install_leave_handlers(stream: &Arc<Mutex<Connection>>) -> std::io::Result<()> {
+    let hook_stream = Arc::clone(stream);
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        // The panicking thread may be the one holding the lock
+        if let Ok(mut stream) = hook_stream.try_lock() {
+            leave_and_restore_terminal(&mut stream);
+        }
+        default_hook(info);
+    }));
+
+    #[cfg(unix)]
+    {
+        use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+        use signal_hook::iterator::Signals;
+
+        let signal_stream = Arc::clone(stream);
+        let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+        thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                if let Ok(mut stream) = signal_stream.lock() {
+                    leave_and_restore_terminal(&mut stream);
+                }
+                std::process::exit(128 + signal);
+            }
+        });
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn test_startup_header_order() {
+        let header = startup_header(
+            Some("10.0.0.2:5000".to_string()),
+            "general",
+            "10.0.0.1:8080",
+            "Use /help".to_string(),
+        );
+        assert_eq!(
+            header,
+            vec![
+                MessageType::Info("Your ip is: 10.0.0.2:5000".to_string()),
+                MessageType::Info("You are in room: general".to_string()),
+                MessageType::Info("Connected to server at address: 10.0.0.1:8080".to_string()),
+                MessageType::Notice(NoticeKind::Tip, "Use /help".to_string()),
+                MessageType::Info(String::new()),
+            ]
+        );
+
+        let header = startup_header(None, "general", "10.0.0.1:8080", "Use /help".to_string());
+        assert_eq!(
+            header.first(),
+            Some(&MessageType::Info("You are in room: general".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_saved_files_drop_their_contents() {
+        let file = MessageType::File(
+            "docs/report.pdf".to_string(),
+            vec![0; 1024],
+            "ab".repeat(32),
+        );
+        assert_eq!(
+            without_contents(&file),
+            Some(MessageType::SavedFile {
+                file_name: "report.pdf".to_string(),
+                size: 1024,
+                checksum: "ab".repeat(32),
+            })
+        );
+
+        let image = MessageType::Image("cat.png".to_string(), vec![0; 10]);
+        assert_eq!(
+            without_contents(&image),
+            Some(MessageType::SavedImage {
+                image_name: "cat.png".to_string(),
+                size: 10,
+            })
+        );
+        assert_eq!(without_contents(&MessageType::Info("hi".to_string())), None);
+    }
+
+    #[test]
+    fn test_unreadable_file_does_not_stop_the_others() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let _server_side = listener.accept().unwrap();
+        let state = ClientState::new(stream);
+
+        let dir = std::env::temp_dir();
+        let missing = dir.join(format!("lan-chat-missing-{}.txt", std::process::id()));
+        let present = dir.join(format!("lan-chat-present-{}.txt", std::process::id()));
+        std::fs::write(&present, "hello").unwrap();
+        let message_vector = Arc::new(Mutex::new(vec![
+            MessageType::Info("Sending missing".to_string()),
+            MessageType::Info("Sending present".to_string()),
+        ]));
+        spawn_file_send(
+            &state,
+            Arc::clone(&message_vector),
+            vec![
+                (missing.display().to_string(), 0),
+                (present.display().to_string(), 1),
+            ],
+        );
+
+        let started = Instant::now();
+        while message_vector.lock().unwrap().len() < 4 && started.elapsed() < Duration::from_secs(5)
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_file(&present).unwrap();
+        let messages = message_vector.lock().unwrap();
+        assert!(
+            matches!(&messages[0], MessageType::Error(error) if error.starts_with("Failed to read"))
+        );
+        assert_eq!(
+            messages[1],
+            MessageType::Info(format!(
+                "Sending {} (5 B): 100%",
+                file_name_only(&present.display().to_string())
+            ))
+        );
+        assert_eq!(
+            messages[3],
+            MessageType::Info("Sent 1 of 2 files".to_string())
+        );
+    }
+
+    #[test]
+    fn test_newer_releases() {
+        assert!(is_newer_release("0.13.0", "0.12.6"));
+        assert!(is_newer_release("1.0.0", "0.12.6"));
+        assert!(is_newer_release("2.0.0", "1.9.3"));
+        assert!(!is_newer_release("0.12.9", "0.12.6"));
+        assert!(!is_newer_release("1.4.0", "1.2.0"));
+        assert!(!is_newer_release("0.11.2", "0.12.6"));
+        assert!(!is_newer_release("0.12.7-beta.1", "0.12.6"));
+    }
+
+    #[test]
+    fn test_scrollback_drops_oldest_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let mut state = ClientState::new(stream);
+        state.scrollback = 3;
+
+        let mut messages: Vec<MessageType> =
+            (0..5).map(|n| MessageType::Info(n.to_string())).collect();
+        assert_eq!(state.trim_scrollback(&mut messages), 2);
+        assert_eq!(messages[0], MessageType::Info("2".to_string()));
+        assert_eq!(state.trim_scrollback(&mut messages), 0);
+        assert_eq!(state.trimmed.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_repeated_messages_are_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let mut state = ClientState::new(stream);
+        state.scrollback = 3;
+        let message = |sender: &str, nonce: u64| {
+            MessageType::Message(sender.to_string(), "hi".to_string(), nonce, vec![])
+        };
+
+        assert!(state.check_received(&message("bob", 1)));
+        assert!(!state.check_received(&message("bob", 1)));
+        assert!(state.check_received(&message("carol", 1)));
+        let info = MessageType::Info("hi".to_string());
+        assert!(state.check_received(&info) && state.check_received(&info));
+
+        // Our own come back with the history of a room
+        *state.registered_as.lock().unwrap() = "alice".to_string();
+        state.outgoing(message("", 2));
+        assert!(!state.check_received(&message("alice", 2)));
+
+        // Only the last ones are remembered
+        assert!(state.check_received(&message("bob", 3)));
+        assert!(state.check_received(&message("bob", 1)));
+    }
+
+    #[test]
+    fn test_unverified_messages_are_told_apart_by_sender() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let state = ClientState::new(stream);
+
+        // Signed by someone whose key isn't known, and not signed, under the same nonce
+        let forged = MessageType::Message("bob".to_string(), "hi".to_string(), 5, vec![1; 64]);
+        let honest = MessageType::Message("carol".to_string(), "hi".to_string(), 5, vec![]);
+        let code = MessageType::Code {
+            sender: "dave".to_string(),
+            lang: String::new(),
+            body: "ls".to_string(),
+            nonce: 5,
+            signature: vec![1; 64],
+        };
+        for message in [&forged, &honest, &code] {
+            assert!(state.check_received(message));
+        }
+        assert!(state.is_unverified(&forged));
+        assert!(!state.is_unverified(&honest));
+        assert!(state.is_unverified(&code));
+    }
+
+    #[test]
+    fn test_replayed_messages_are_checked_against_the_key_they_came_with() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let state = ClientState::new(stream);
+        let key = ed25519_dalek::SigningKey::from_bytes(&rand::random());
+        let signed = |sender: &str, nonce| {
+            let message = MessageType::Message(sender.to_string(), "hi".to_string(), nonce, vec![]);
+            signing::sign_with(Some(&key), message)
+        };
+        let public = key.verifying_key().to_bytes().to_vec();
+
+        // alice signed and has left since, so only the replay carries the key
+        let kept = signed("alice", 1);
+        assert!(state.check_replayed(&kept, public.clone()));
+        assert!(!state.is_unverified(&kept));
+
+        // Without the key the signature vouches for nothing
+        let forged = signed("bob", 2);
+        assert!(state.check_replayed(&forged, vec![]));
+        assert!(state.is_unverified(&forged));
+        assert!(state.signing_keys.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_waiting_for_changes_wakes_up_on_a_change() {
+        let changes = Arc::new(Changes::default());
+        let seen = changes.current();
+        assert_eq!(changes.wait_past(seen, Duration::from_millis(10)), seen);
+
+        let marker = Arc::clone(&changes);
+        let started = Instant::now();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            marker.mark();
+        });
+        assert_eq!(changes.wait_past(seen, Duration::from_secs(10)), seen + 1);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_durations_are_compact() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(59), "59s");
+        assert_eq!(format_duration(60), "1m");
+        assert_eq!(format_duration(12 * 60 + 30), "12m");
+        assert_eq!(format_duration(3 * 3600 + 59 * 60), "3h");
+
+        assert_eq!(
+            user_entry("alice", &PresenceState::Active, 754, "reviewing PRs", false),
+            "alice · 12m — reviewing PRs"
+        );
+        assert_eq!(
+            user_entry("bob", &PresenceState::Away(None), 5, "", false),
+            "bob · 5s (away)"
+        );
+        assert_eq!(
+            user_entry("carol", &PresenceState::Active, 90, "", true),
+            "carol · 1m [spectator]"
+        );
+    }
+
+    #[test]
+    fn test_notification_levels() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let state = ClientState::new(stream);
+
+        let mention = MessageType::Message("bob".to_string(), "hey @Alice!".to_string(), 0, vec![]);
+        let chatter =
+            MessageType::Message("bob".to_string(), "alicent said hi".to_string(), 1, vec![]);
+        let own = MessageType::Message("alice".to_string(), "alice here".to_string(), 2, vec![]);
+        assert!(state.should_notify(&mention, "alice"));
+        assert!(!state.should_notify(&chatter, "alice"));
+        assert!(!state.should_notify(&own, "alice"));
+
+        *state.notify.lock().unwrap() = NotifyLevel::All;
+        assert!(state.should_notify(&chatter, "alice"));
+        assert!(!state.should_notify(&MessageType::Info("alice".to_string()), "alice"));
+
+        *state.notify.lock().unwrap() = NotifyLevel::Off;
+        assert!(!state.should_notify(&mention, "alice"));
+    }
+
+    #[test]
+    fn test_messages_are_queued_while_disconnected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let state = ClientState::new(stream.try_clone().unwrap());
+
+        let message = |n: u64| MessageType::Message("bob".to_string(), n.to_string(), n, vec![]);
+        for n in 0..MAX_QUEUED_MESSAGES as u64 {
+            assert_eq!(state.send_or_queue(&mut stream, message(n)).unwrap(), None);
+        }
+        assert_eq!(
+            state
+                .send_or_queue(&mut stream, message(MAX_QUEUED_MESSAGES as u64))
+                .unwrap(),
+            Some(message(0))
+        );
+
+        let deliveries = state.deliveries.lock().unwrap();
+        assert_eq!(deliveries.get(&0), None);
+        assert_eq!(deliveries.get(&1), Some(&Delivery::Queued));
+        assert_eq!(state.outbox.lock().unwrap().front(), Some(&message(1)));
+    }
+
+    #[test]
+    fn test_failed_messages_can_be_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broken = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        broken.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut broken = Connection::Tcp(broken);
+        let _broken_side = listener.accept().unwrap();
+        let mut stream = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let (mut server_side, _) = listener.accept().unwrap();
+        let state = ClientState::new(stream.try_clone().unwrap());
+        state.registered.store(true, Ordering::SeqCst);
+
+        let message = MessageType::Message(String::new(), "hello".to_string(), 3, vec![]);
+        assert!(state.send_or_queue(&mut broken, message.clone()).is_err());
+        assert_eq!(
+            state.deliveries.lock().unwrap().get(&3),
+            Some(&Delivery::Failed)
+        );
+
+        let shown = [MessageType::Message(
+            "alice".to_string(),
+            "hello".to_string(),
+            3,
+            vec![],
+        )];
+        assert_eq!(state.retry_failed(&mut stream, &shown).unwrap(), 1);
+        assert_eq!(receive_message(&mut server_side).unwrap(), message);
+        assert_eq!(state.retry_failed(&mut stream, &shown).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_messages_wait_for_the_welcome() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let (mut server_side, _) = listener.accept().unwrap();
+        let state = ClientState::new(stream.try_clone().unwrap());
+        state.reader_alive.store(true, Ordering::SeqCst);
+
+        let message = MessageType::Message(String::new(), "first!".to_string(), 7, vec![]);
+        state.send_or_queue(&mut stream, message.clone()).unwrap();
+        assert_eq!(state.outbox.lock().unwrap().len(), 1);
+
+        assert_eq!(state.mark_registered(), 1);
+        assert_eq!(receive_message(&mut server_side).unwrap(), message);
+        assert!(matches!(
+            state.deliveries.lock().unwrap().get(&7),
+            Some(Delivery::Pending(_))
+        ));
+    }
+}
@@ -0,0 +1,220 @@
+//! The connection between a client and the server, over TCP or, on unix, a Unix domain socket.
+//!
+//! Messages are framed the same way over both, so everything above this module only deals with `Read` and
+//! `Write` and doesn't care which one it is.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::*;
+use socket2::{SockRef, TcpKeepalive};
+
+use crate::networking::error::ChatError;
+use crate::networking::messaging::{send_message, MessageType};
+
+/// Where the server can be reached.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf), // Path of the socket file
+}
+
+impl Endpoint {
+    /// Opens a new connection to the server.
+    pub fn connect(&self) -> io::Result<Connection> {
+        match self {
+            Endpoint::Tcp(addr) => TcpStream::connect(addr).map(|stream| {
+                tune_tcp(&stream);
+                Connection::Tcp(stream)
+            }),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => UnixStream::connect(path).map(Connection::Unix),
+        }
+    }
+}
+
+/// How long a connection can be silent before the OS starts checking that the other end is still there.
+const KEEPALIVE_TIME: Duration = Duration::from_secs(30);
+
+/// Time between the checks once they started, after a few unanswered ones the connection is dropped.
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Turns off Nagle's algorithm, so that a short chat message goes out at once instead of waiting
+/// for the previous one to be acknowledged. Messages are written as whole frames, so this doesn't
+/// split them into more packets.
+///
+/// Also turns on TCP keepalive, so that a peer gone without closing the connection, e.g. off Wi-Fi,
+/// makes reads fail within a minute or two instead of never.
+pub fn tune_tcp(stream: &TcpStream) {
+    if let Err(e) = stream.set_nodelay(true) {
+        warn!("Failed to set TCP_NODELAY: {}", e);
+    }
+
+    let keepalive = TcpKeepalive::new().with_time(KEEPALIVE_TIME);
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+    let keepalive = keepalive.with_interval(KEEPALIVE_INTERVAL);
+    if let Err(e) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+        warn!("Failed to turn on TCP keepalive: {}", e);
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// An open connection to the server.
+#[derive(Debug)]
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Connection {
+    /// Returns another handle to the same connection, e.g. to read from it on another thread.
+    pub fn try_clone(&self) -> io::Result<Connection> {
+        match self {
+            Connection::Tcp(stream) => stream.try_clone().map(Connection::Tcp),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.try_clone().map(Connection::Unix),
+        }
+    }
+
+    /// Returns the address of this end, shown to the user. Unix sockets of clients have no path, so they're
+    /// just called that.
+    pub fn local_addr(&self) -> io::Result<String> {
+        match self {
+            Connection::Tcp(stream) => stream.local_addr().map(|addr| addr.to_string()),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Ok(stream
+                .local_addr()?
+                .as_pathname()
+                .map_or("unix socket".to_string(), |path| path.display().to_string())),
+        }
+    }
+
+    /// Closes the connection in both directions, which also stops the thread reading from it.
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.shutdown(Shutdown::Both),
+        }
+    }
+
+    /// Sends a message, closing the connection if it broke partway through. The server would otherwise read the
+    /// next message as the rest of the cut off one, so the reader thread sees the connection drop and reconnects.
+    pub fn send(&mut self, message: &MessageType) -> Result<(), ChatError> {
+        let result = send_message(self, message);
+        if let Err(e @ ChatError::PartialWrite { .. }) = &result {
+            warn!("Closing the connection to the server: {}", e);
+            let _ = self.shutdown();
+        }
+        result
+    }
+
+    /// Returns where the other end of the connection can be reached.
+    pub fn peer_endpoint(&self) -> io::Result<Endpoint> {
+        match self {
+            Connection::Tcp(stream) => stream.peer_addr().map(Endpoint::Tcp),
+            #[cfg(unix)]
+            Connection::Unix(stream) => match stream.peer_addr()?.as_pathname() {
+                Some(path) => Ok(Endpoint::Unix(path.to_path_buf())),
+                None => Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "the other end of the socket has no path",
+                )),
+            },
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl From<TcpStream> for Connection {
+    fn from(stream: TcpStream) -> Self {
+        Connection::Tcp(stream)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::net::UnixListener;
+
+    use super::*;
+    use crate::networking::messaging::{receive_message, send_message, MessageType};
+
+    #[test]
+    fn test_messages_go_over_unix_sockets() {
+        let path = std::env::temp_dir().join(format!("lan-chat-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let mut client = Endpoint::Unix(path.clone()).connect().unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        assert_eq!(
+            client.peer_endpoint().unwrap(),
+            Endpoint::Unix(path.clone())
+        );
+        assert_eq!(client.local_addr().unwrap(), "unix socket");
+
+        let message = MessageType::Pseudonym("alice".to_string());
+        send_message(&mut client, &message).unwrap();
+        assert_eq!(receive_message(&mut server).unwrap(), message);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tcp_connections_are_tuned() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let Connection::Tcp(stream) = client else {
+            panic!("expected a TCP connection");
+        };
+        assert!(stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+    }
+}
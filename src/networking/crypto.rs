@@ -0,0 +1,553 @@
+//! Encryption of message bodies and file contents with a passphrase shared by the clients, for `--passphrase`.
+//!
+//! Clients started with the same passphrase seal the text of chat messages, the body of code blocks and the
+//! bytes and checksums of files and images with ChaCha20-Poly1305 before sending them. The server relays them
+//! as they are without being able to read them, and clients without the passphrase, or with another one, show
+//! "(unable to decrypt)" in their place. Everything else, names, rooms, polls and the like, is still sent in the
+//! clear since the server needs it.
+//!
+//! Every client derives its key from the passphrase with Argon2 and a salt of its own, which goes along with
+//! everything it seals, so that guesses at the passphrase can't be worked out once for every chat. The salt is
+//! also announced in a `Salt` once the client has joined, and the keys of others are only derived from announced
+//! salts, never from the ones found on sealed bytes, as each derivation takes a while on purpose. What the
+//! server could otherwise swap around, like the sender and nonce of a message or the position of a file chunk,
+//! is bound to the sealed bytes as associated data, so that they only open where they were sent.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Mutex, OnceLock};
+
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use crate::networking::messaging::{MessageType, MAX_TEXT_LENGTH};
+
+/// Shown in place of a message that can't be decrypted.
+pub const UNDECRYPTABLE: &str = "(unable to decrypt)";
+
+/// Environment variable the passphrase is read from, so that it isn't typed each time nor seen on the command line.
+pub const PASSPHRASE_VAR: &str = "LAN_CHAT_PASSPHRASE";
+
+/// Starts sealed file contents, followed by the salt, the nonce, the ciphertext and the tag.
+const MAGIC: &[u8] = b"LCE2";
+/// Starts sealed text, followed by the sealed bytes in base64. The control character keeps it from being typed.
+const TEXT_PREFIX: &str = "\u{1}LCE2:";
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+/// Bytes added to the contents by sealing them.
+const OVERHEAD: usize = MAGIC.len() + SALT_LENGTH + NONCE_LENGTH + TAG_LENGTH;
+
+/// The keys derived from the passphrase, set once when encryption starts.
+static KEYRING: OnceLock<Keyring> = OnceLock::new();
+
+/// The keys of other clients by their pseudonym, each with the salt it was derived from.
+type KnownKeys = HashMap<String, Vec<([u8; SALT_LENGTH], ChaCha20Poly1305)>>;
+
+/// Our key, and the keys of the other clients by their pseudonym, derived as they announce their salts.
+pub struct Keyring {
+    passphrase: String,
+    salt: [u8; SALT_LENGTH],
+    key: ChaCha20Poly1305,
+    known: Mutex<KnownKeys>,
+}
+
+impl Keyring {
+    /// Derives our key from the passphrase with a new salt.
+    pub fn new(passphrase: &str) -> Keyring {
+        let salt = rand::random();
+        Keyring {
+            passphrase: passphrase.to_string(),
+            salt,
+            key: derive(passphrase, &salt),
+            known: Mutex::default(),
+        }
+    }
+
+    /// Derives the key of a client from the salt it announced, unless it is known already.
+    fn learn(&self, nick: &str, salt: &[u8]) {
+        let Ok(salt) = <[u8; SALT_LENGTH]>::try_from(salt) else {
+            return;
+        };
+        let is_known = |known: &KnownKeys| {
+            known
+                .get(nick)
+                .is_some_and(|keys| keys.iter().any(|(s, _)| *s == salt))
+        };
+        if salt == self.salt || is_known(&self.known.lock().unwrap()) {
+            return;
+        }
+        // Without holding the lock, as it takes a while
+        let key = derive(&self.passphrase, &salt);
+        let mut known = self.known.lock().unwrap();
+        if !is_known(&known) {
+            known.entry(nick.to_string()).or_default().push((salt, key));
+        }
+    }
+
+    /// Forgets the keys of a client that has left.
+    fn forget(&self, nick: &str) {
+        self.known.lock().unwrap().remove(nick);
+    }
+
+    /// Returns the key of the client that sealed with the salt, if it has announced it.
+    fn key_for(&self, salt: &[u8; SALT_LENGTH]) -> Option<ChaCha20Poly1305> {
+        if *salt == self.salt {
+            return Some(self.key.clone());
+        }
+        self.known
+            .lock()
+            .unwrap()
+            .values()
+            .flatten()
+            .find(|(s, _)| s == salt)
+            .map(|(_, key)| key.clone())
+    }
+}
+
+/// Derives a key from the passphrase with Argon2. This is slow on purpose, to make guessing the passphrase slow.
+fn derive(passphrase: &str, salt: &[u8; SALT_LENGTH]) -> ChaCha20Poly1305 {
+    let mut key = [0; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .expect("salt and key lengths are within Argon2's limits");
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+/// Starts sealing the messages sent with `seal` and opening the ones received with `open`.
+pub fn start(passphrase: &str) -> io::Result<()> {
+    KEYRING
+        .set(Keyring::new(passphrase))
+        .map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "encryption already started"))
+}
+
+/// The salt of our key, to announce, if encryption was started.
+pub fn salt() -> Option<Vec<u8>> {
+    KEYRING.get().map(|keyring| keyring.salt.to_vec())
+}
+
+/// Derives the key of another client from the salt it announced, if encryption was started.
+pub fn learn(nick: &str, salt: &[u8]) {
+    if let Some(keyring) = KEYRING.get() {
+        keyring.learn(nick, salt);
+    }
+}
+
+/// Forgets the keys of a client that has left, if encryption was started.
+pub fn forget(nick: &str) {
+    if let Some(keyring) = KEYRING.get() {
+        keyring.forget(nick);
+    }
+}
+
+/// Longest text that still fits in a message once sealed, if encryption was started.
+pub fn max_text_length() -> usize {
+    match KEYRING.get() {
+        Some(_) => (MAX_TEXT_LENGTH - TEXT_PREFIX.len()) / 4 * 3 - OVERHEAD,
+        None => MAX_TEXT_LENGTH,
+    }
+}
+
+/// Seals the private parts of a message about to be sent, if encryption was started.
+///
+/// A chat message or code block must already carry our registered pseudonym, as that is what it is bound to.
+pub fn seal(message: MessageType) -> MessageType {
+    seal_with(KEYRING.get(), message)
+}
+
+/// Opens the sealed parts of a received message, with the keys if encryption was started.
+pub fn open(message: MessageType) -> MessageType {
+    open_with(KEYRING.get(), message)
+}
+
+/// The associated data of a sealed part: what kind of part it is, followed by what it is bound to, each
+/// preceded by its length so that no two lists of parts give the same bytes.
+fn associated_data(kind: &str, parts: &[&[u8]]) -> Vec<u8> {
+    let mut data = vec![];
+    for part in [kind.as_bytes()].iter().chain(parts) {
+        data.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        data.extend_from_slice(part);
+    }
+    data
+}
+
+fn message_data(sender: &str, nonce: u64) -> Vec<u8> {
+    associated_data("message", &[sender.as_bytes(), &nonce.to_be_bytes()])
+}
+
+fn code_data(sender: &str, nonce: u64, lang: &str) -> Vec<u8> {
+    associated_data(
+        "code",
+        &[sender.as_bytes(), &nonce.to_be_bytes(), lang.as_bytes()],
+    )
+}
+
+fn file_data(kind: &str, file_name: &str) -> Vec<u8> {
+    associated_data(kind, &[file_name.as_bytes()])
+}
+
+fn chunk_data(transfer_id: u64, file_name: &str, seq: u32, total: u32) -> Vec<u8> {
+    associated_data(
+        "chunk",
+        &[
+            &transfer_id.to_be_bytes(),
+            file_name.as_bytes(),
+            &seq.to_be_bytes(),
+            &total.to_be_bytes(),
+        ],
+    )
+}
+
+fn seal_with(keyring: Option<&Keyring>, message: MessageType) -> MessageType {
+    let Some(keyring) = keyring else {
+        return message;
+    };
+    match message {
+        MessageType::Message(sender, text, nonce, signature) => {
+            let text = seal_text(keyring, &text, &message_data(&sender, nonce));
+            MessageType::Message(sender, text, nonce, signature)
+        }
+        MessageType::Code {
+            sender,
+            lang,
+            body,
+            nonce,
+            signature,
+        } => {
+            let body = seal_text(keyring, &body, &code_data(&sender, nonce, &lang));
+            MessageType::Code {
+                sender,
+                lang,
+                body,
+                nonce,
+                signature,
+            }
+        }
+        MessageType::File(file_name, contents, checksum) => {
+            let aad = file_data("file", &file_name);
+            MessageType::File(
+                file_name,
+                seal_bytes(keyring, &contents, &aad),
+                seal_text(keyring, &checksum, &aad),
+            )
+        }
+        MessageType::Image(image_name, contents) => {
+            let contents = seal_bytes(keyring, &contents, &file_data("image", &image_name));
+            MessageType::Image(image_name, contents)
+        }
+        MessageType::FileChunk {
+            transfer_id,
+            file_name,
+            seq,
+            total,
+            data,
+            checksum,
+        } => {
+            let aad = chunk_data(transfer_id, &file_name, seq, total);
+            MessageType::FileChunk {
+                transfer_id,
+                data: seal_bytes(keyring, &data, &aad),
+                checksum: checksum.map(|checksum| seal_text(keyring, &checksum, &aad)),
+                file_name,
+                seq,
+                total,
+            }
+        }
+        message => message,
+    }
+}
+
+/// Opens a received message. Unsealed parts are left as they are, so that clients without a passphrase can
+/// still be read. A file chunk that can't be opened is left sealed, for the checksum of the file to catch.
+fn open_with(keyring: Option<&Keyring>, message: MessageType) -> MessageType {
+    match message {
+        MessageType::Message(sender, text, nonce, signature) => {
+            let text = open_text(keyring, text, &message_data(&sender, nonce));
+            MessageType::Message(sender, text, nonce, signature)
+        }
+        MessageType::Code {
+            sender,
+            lang,
+            body,
+            nonce,
+            signature,
+        } => {
+            let body = open_text(keyring, body, &code_data(&sender, nonce, &lang));
+            MessageType::Code {
+                sender,
+                lang,
+                body,
+                nonce,
+                signature,
+            }
+        }
+        MessageType::File(file_name, contents, checksum) if contents.starts_with(MAGIC) => {
+            let aad = file_data("file", &file_name);
+            match keyring.and_then(|keyring| open_bytes(keyring, &contents, &aad)) {
+                Some(contents) => {
+                    let checksum = open_text(keyring, checksum, &aad);
+                    MessageType::File(file_name, contents, checksum)
+                }
+                None => MessageType::Error(format!("{} {}", file_name, UNDECRYPTABLE)),
+            }
+        }
+        MessageType::Image(image_name, contents) if contents.starts_with(MAGIC) => {
+            let aad = file_data("image", &image_name);
+            match keyring.and_then(|keyring| open_bytes(keyring, &contents, &aad)) {
+                Some(contents) => MessageType::Image(image_name, contents),
+                None => MessageType::Error(format!("{} {}", image_name, UNDECRYPTABLE)),
+            }
+        }
+        MessageType::FileChunk {
+            transfer_id,
+            file_name,
+            seq,
+            total,
+            data,
+            checksum,
+        } => {
+            let aad = chunk_data(transfer_id, &file_name, seq, total);
+            let data = keyring
+                .and_then(|keyring| open_bytes(keyring, &data, &aad))
+                .unwrap_or(data);
+            let checksum = checksum.map(|checksum| open_text(keyring, checksum, &aad));
+            MessageType::FileChunk {
+                transfer_id,
+                file_name,
+                seq,
+                total,
+                data,
+                checksum,
+            }
+        }
+        message => message,
+    }
+}
+
+fn seal_text(keyring: &Keyring, text: &str, aad: &[u8]) -> String {
+    format!(
+        "{}{}",
+        TEXT_PREFIX,
+        base64_encode(&seal_bytes(keyring, text.as_bytes(), aad)[MAGIC.len()..])
+    )
+}
+
+fn open_text(keyring: Option<&Keyring>, text: String, aad: &[u8]) -> String {
+    let Some(encoded) = text.strip_prefix(TEXT_PREFIX) else {
+        return text;
+    };
+    let opened = keyring
+        .zip(base64_decode(encoded))
+        .and_then(|(keyring, bytes)| {
+            let sealed = [MAGIC, &bytes].concat();
+            open_bytes(keyring, &sealed, aad).and_then(|bytes| String::from_utf8(bytes).ok())
+        });
+    opened.unwrap_or_else(|| UNDECRYPTABLE.to_string())
+}
+
+fn seal_bytes(keyring: &Keyring, contents: &[u8], aad: &[u8]) -> Vec<u8> {
+    let nonce: [u8; NONCE_LENGTH] = rand::random();
+    let ciphertext = keyring
+        .key
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: contents, aad })
+        .expect("contents are within ChaCha20-Poly1305's limits");
+    let mut sealed = Vec::with_capacity(contents.len() + OVERHEAD);
+    sealed.extend_from_slice(MAGIC);
+    sealed.extend_from_slice(&keyring.salt);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    sealed
+}
+
+/// Opens sealed bytes, or returns `None` if they weren't sealed with the passphrase and associated data, were
+/// tampered with, or come from a client that hasn't announced its salt.
+fn open_bytes(keyring: &Keyring, sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    let sealed = sealed.strip_prefix(MAGIC)?;
+    if sealed.len() < SALT_LENGTH + NONCE_LENGTH + TAG_LENGTH {
+        return None;
+    }
+    let (salt, sealed) = sealed.split_at(SALT_LENGTH);
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    keyring
+        .key_for(salt.try_into().unwrap())?
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .ok()
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &byte)| n | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(encoded.len() / 4 * 3);
+    for chunk in encoded.as_bytes().chunks(4) {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 {
+            return None;
+        }
+        let mut n = 0u32;
+        for (i, &c) in chunk[..4 - padding].iter().enumerate() {
+            let value = BASE64_ALPHABET.iter().position(|&a| a == c)?;
+            n |= (value as u32) << (18 - 6 * i);
+        }
+        bytes.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::messaging::{receive_message, send_message};
+
+    #[test]
+    fn test_sealed_messages_round_trip() {
+        let keyring = Keyring::new("correct horse battery staple");
+        // Another client with the same passphrase, and so another salt
+        let reader = Keyring::new("correct horse battery staple");
+        reader.learn("alice", &keyring.salt);
+        let wrong_keyring = Keyring::new("hunter2");
+        wrong_keyring.learn("alice", &keyring.salt);
+        let message =
+            MessageType::Message("alice".to_string(), "meet at 5 ☕".to_string(), 3, vec![]);
+        let image = MessageType::Image("cat.png".to_string(), vec![7; 100]);
+
+        let mut wire = vec![];
+        send_message(&mut wire, &seal_with(Some(&keyring), message.clone())).unwrap();
+        send_message(&mut wire, &seal_with(Some(&keyring), image.clone())).unwrap();
+        let mut reader_end = wire.as_slice();
+        let sealed = receive_message(&mut reader_end).unwrap();
+        let sealed_image = receive_message(&mut reader_end).unwrap();
+        assert_ne!(sealed, message);
+        assert_ne!(sealed_image, image);
+
+        assert_eq!(open_with(Some(&reader), sealed.clone()), message);
+        assert_eq!(open_with(Some(&reader), sealed_image.clone()), image);
+        let undecryptable =
+            MessageType::Message("alice".to_string(), UNDECRYPTABLE.to_string(), 3, vec![]);
+        assert_eq!(
+            open_with(Some(&wrong_keyring), sealed.clone()),
+            undecryptable
+        );
+        assert_eq!(open_with(None, sealed), undecryptable);
+        assert_eq!(
+            open_with(None, sealed_image),
+            MessageType::Error(format!("cat.png {}", UNDECRYPTABLE))
+        );
+        // Messages from clients without a passphrase are still shown
+        assert_eq!(open_with(Some(&keyring), message.clone()), message);
+    }
+
+    #[test]
+    fn test_sealed_parts_only_open_where_they_were_sent() {
+        let keyring = Keyring::new("correct horse battery staple");
+        let MessageType::Message(_, text, ..) = seal_with(
+            Some(&keyring),
+            MessageType::Message("alice".to_string(), "hi".to_string(), 3, vec![]),
+        ) else {
+            panic!("not a message");
+        };
+
+        // Put on another sender or nonce by the server
+        for (sender, nonce) in [("mallory", 3), ("alice", 4)] {
+            let moved = MessageType::Message(sender.to_string(), text.clone(), nonce, vec![]);
+            assert_eq!(
+                open_with(Some(&keyring), moved),
+                MessageType::Message(sender.to_string(), UNDECRYPTABLE.to_string(), nonce, vec![])
+            );
+        }
+
+        // Moved to another place in the file
+        let chunk = |seq: u32, data: Vec<u8>| MessageType::FileChunk {
+            transfer_id: 1,
+            file_name: "notes.txt".to_string(),
+            seq,
+            total: 2,
+            data,
+            checksum: None,
+        };
+        let MessageType::FileChunk { data, .. } = seal_with(Some(&keyring), chunk(0, vec![1, 2]))
+        else {
+            panic!("not a chunk");
+        };
+        assert_eq!(
+            open_with(Some(&keyring), chunk(1, data.clone())),
+            chunk(1, data)
+        );
+    }
+
+    #[test]
+    fn test_file_checksums_are_sealed() {
+        let keyring = Keyring::new("correct horse battery staple");
+        let checksum =
+            "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08".to_string();
+        let file = MessageType::File("test.txt".to_string(), b"test".to_vec(), checksum.clone());
+
+        let sealed = seal_with(Some(&keyring), file.clone());
+        let MessageType::File(_, _, sealed_checksum) = &sealed else {
+            panic!("not a file");
+        };
+        assert!(!sealed_checksum.contains(&checksum));
+        assert_eq!(open_with(Some(&keyring), sealed), file);
+    }
+
+    #[test]
+    fn test_clients_salt_their_keys() {
+        let alice = Keyring::new("correct horse battery staple");
+        let bob = Keyring::new("correct horse battery staple");
+        assert_ne!(alice.salt, bob.salt);
+
+        let sealed = seal_bytes(&alice, b"hi", b"");
+        assert_eq!(sealed[MAGIC.len()..][..SALT_LENGTH], alice.salt);
+
+        // Keys are only derived from announced salts, not from whatever salt comes on the wire
+        assert_eq!(open_bytes(&bob, &sealed, b""), None);
+        assert!(bob.known.lock().unwrap().is_empty());
+        bob.learn("alice", &alice.salt);
+        bob.learn("alice", &alice.salt);
+        bob.learn("mallory", &[0; SALT_LENGTH + 1]);
+        assert_eq!(open_bytes(&bob, &sealed, b"").unwrap(), b"hi");
+        assert_eq!(bob.known.lock().unwrap()["alice"].len(), 1);
+        assert_eq!(bob.known.lock().unwrap().len(), 1);
+
+        bob.forget("alice");
+        assert_eq!(open_bytes(&bob, &sealed, b""), None);
+    }
+
+    #[test]
+    fn test_base64() {
+        for bytes in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba", b"foobar"] {
+            assert_eq!(base64_decode(&base64_encode(bytes)).unwrap(), bytes);
+        }
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_decode("Zm8"), None);
+    }
+}
@@ -0,0 +1,123 @@
+//! Lets clients find servers on the LAN without being told their address.
+//!
+//! Servers broadcast a small UDP beacon with their address and rooms every few seconds, which
+//! clients started with `--discover` listen for before offering the servers they heard from.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::networking::messaging::PROTOCOL_VERSION;
+
+/// UDP port beacons are broadcast to.
+pub const DISCOVERY_PORT: u16 = 47_474;
+
+/// How often a server broadcasts its beacon.
+pub const BEACON_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long a client listens for beacons, a bit more than one interval to hear every server.
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Marks a datagram as a lan-chat beacon, so that stray traffic on the port is ignored.
+const BEACON_MAGIC: &[u8] = b"LANCHAT";
+
+/// What a server advertises about itself.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Beacon {
+    pub version: u32,       // Protocol version the server speaks
+    pub addr: String,       // Address the server accepts connections on
+    pub rooms: Vec<String>, // Rooms with someone in them
+}
+
+impl Beacon {
+    pub fn new(addr: SocketAddr, rooms: Vec<String>) -> Self {
+        Beacon {
+            version: PROTOCOL_VERSION,
+            addr: addr.to_string(),
+            rooms,
+        }
+    }
+
+    fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = BEACON_MAGIC.to_vec();
+        bytes.extend(bincode::serialize(self).map_err(io::Error::other)?);
+        Ok(bytes)
+    }
+
+    /// Parses a datagram, returning `None` if it isn't a beacon.
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes.strip_prefix(BEACON_MAGIC)?).ok()
+    }
+}
+
+/// Opens the socket a server broadcasts its beacons from.
+pub fn beacon_socket() -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    Ok(socket)
+}
+
+/// Broadcasts a beacon to the whole LAN.
+pub fn send_beacon(socket: &UdpSocket, beacon: &Beacon) -> io::Result<()> {
+    socket.send_to(&beacon.to_bytes()?, (Ipv4Addr::BROADCAST, DISCOVERY_PORT))?;
+    Ok(())
+}
+
+/// Listens for beacons for the given time, returning every compatible server heard from once.
+pub fn discover(timeout: Duration) -> io::Result<Vec<Beacon>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT))?;
+    let deadline = Instant::now() + timeout;
+    let mut servers: HashMap<String, Beacon> = HashMap::new();
+    let mut buffer = [0; 1024];
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        match socket.recv_from(&mut buffer) {
+            Ok((size, _)) => {
+                if let Some(beacon) = Beacon::from_bytes(&buffer[..size]) {
+                    if beacon.version == PROTOCOL_VERSION {
+                        servers.insert(beacon.addr.clone(), beacon);
+                    }
+                }
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mut servers: Vec<Beacon> = servers.into_values().collect();
+    servers.sort_by(|a, b| a.addr.cmp(&b.addr));
+    Ok(servers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_beacon_round_trip() {
+        let beacon = Beacon::new(
+            "192.168.1.20:40123".parse().unwrap(),
+            vec!["general".to_string(), "games".to_string()],
+        );
+        let bytes = beacon.to_bytes().unwrap();
+
+        assert_eq!(Beacon::from_bytes(&bytes), Some(beacon));
+        assert_eq!(Beacon::from_bytes(b"not a beacon"), None);
+        assert_eq!(Beacon::from_bytes(&bytes[..bytes.len() - 1]), None);
+    }
+}
@@ -0,0 +1,116 @@
+//! The error returned when talking to the other end of a connection, or serving it, fails.
+//!
+//! Its variants tell apart failures worth reconnecting over, like the connection closing, from messages that
+//! can never be sent or understood, so that callers can handle each the way it deserves.
+
+use std::io;
+use std::sync::PoisonError;
+
+use thiserror::Error;
+
+use crate::networking::transfer::format_size;
+
+/// Why sending or receiving a message failed.
+#[derive(Debug, Error)]
+pub enum ChatError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("Couldn't encode message: {0}")]
+    Serialize(#[source] bincode::Error),
+    #[error("Couldn't decode message: {0}")]
+    Deserialize(#[source] bincode::Error),
+    #[error("Message of {} is too large", format_size(*.0))]
+    TooLarge(usize), // Size of the message in bytes
+    #[error("Connection closed by peer")]
+    Disconnected, // The other end closed the connection between two messages
+    #[error("Invalid message: {0}")]
+    InvalidMessage(String),
+    /// Writing failed partway through a message, leaving the other end in the middle of a frame it will never
+    /// see the end of. The connection can't be used anymore.
+    #[error("Connection broke after {written} of {total} bytes of a message: {error}")]
+    PartialWrite {
+        written: usize, // Bytes of the frame written
+        total: usize,   // Bytes in the whole frame
+        #[source]
+        error: io::Error,
+    },
+    /// The server can't listen on its address, with what to do about it.
+    #[error("{message}")]
+    Bind {
+        message: String,
+        #[source]
+        error: io::Error,
+    },
+    /// A thread panicked while holding a lock, so what it guards may be left half updated.
+    #[error("A thread panicked while holding a lock")]
+    Poisoned,
+}
+
+impl ChatError {
+    /// Whether the connection is gone, so that reconnecting is the only way forward.
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            ChatError::Disconnected | ChatError::PartialWrite { .. } => true,
+            ChatError::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::NotConnected
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Lets `?` be used on locks, whose guard can't be kept in the error.
+impl<T> From<PoisonError<T>> for ChatError {
+    fn from(_: PoisonError<T>) -> Self {
+        ChatError::Poisoned
+    }
+}
+
+/// Lets functions returning `io::Result` keep using `?` on messages sent or received.
+impl From<ChatError> for io::Error {
+    fn from(e: ChatError) -> Self {
+        match e {
+            ChatError::Io(e) => e,
+            ChatError::Disconnected => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disconnects_are_told_apart() {
+        assert!(ChatError::Disconnected.is_disconnect());
+        assert!(ChatError::from(io::Error::from(io::ErrorKind::ConnectionReset)).is_disconnect());
+        assert!(!ChatError::TooLarge(70_000).is_disconnect());
+
+        let io_error: io::Error = ChatError::TooLarge(70_000).into();
+        assert_eq!(io_error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(io_error.to_string(), "Message of 68.4 KB is too large");
+    }
+
+    #[test]
+    fn test_poisoned_locks_become_errors() {
+        let lock = std::sync::Arc::new(std::sync::Mutex::new(()));
+        let poisoner = lock.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("poisoning the lock");
+        })
+        .join();
+
+        let locked = || -> Result<(), ChatError> {
+            let _guard = lock.lock()?;
+            Ok(())
+        };
+        assert!(matches!(locked(), Err(ChatError::Poisoned)));
+    }
+}
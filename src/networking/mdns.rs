@@ -0,0 +1,84 @@
+//! Resolves `.local` hostnames over multicast DNS, for platforms whose resolver doesn't.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use mdns_sd::{HostnameResolutionEvent, ServiceDaemon};
+
+/// How long to wait for an answer before giving up.
+pub const MDNS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Resolves a `host:port` server address, using mDNS for `.local` hosts the system resolver can't find.
+pub fn resolve_server_addr(addr: &str) -> io::Result<SocketAddr> {
+    let system_error = match addr.to_socket_addrs() {
+        Ok(mut addrs) => match addrs.next() {
+            Some(addr) => return Ok(addr),
+            None => io::Error::new(io::ErrorKind::NotFound, "no addresses found"),
+        },
+        Err(e) => e,
+    };
+
+    let Some((host, port)) = addr.rsplit_once(':') else {
+        return Err(system_error);
+    };
+    let host = host.trim_end_matches('.');
+    if !host.to_lowercase().ends_with(".local") {
+        return Err(system_error);
+    }
+
+    let port: u16 = port
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid port"))?;
+    let ip = resolve_local(host, MDNS_TIMEOUT).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Couldn't resolve {} over mDNS: {}", host, e),
+        )
+    })?;
+    Ok(SocketAddr::from((ip, port)))
+}
+
+/// Asks the LAN for the address of a `.local` host, preferring IPv4.
+pub fn resolve_local(host: &str, timeout: Duration) -> io::Result<IpAddr> {
+    let daemon = ServiceDaemon::new().map_err(io::Error::other)?;
+    // The daemon only looks up fully qualified names
+    let events = daemon
+        .resolve_hostname(&format!("{}.", host), Some(timeout.as_millis() as u64))
+        .map_err(io::Error::other)?;
+    let found = loop {
+        match events.recv() {
+            Ok(HostnameResolutionEvent::AddressesFound(_, addrs)) if !addrs.is_empty() => {
+                break addrs
+                    .iter()
+                    .find(|ip| ip.is_ipv4())
+                    .or(addrs.iter().next())
+                    .copied();
+            }
+            Ok(
+                HostnameResolutionEvent::SearchTimeout(_)
+                | HostnameResolutionEvent::SearchStopped(_),
+            )
+            | Err(_) => break None,
+            Ok(_) => continue,
+        }
+    };
+    let _ = daemon.shutdown();
+    found.ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no answer on the LAN"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_addresses_are_resolved() {
+        assert_eq!(
+            resolve_server_addr("127.0.0.1:4000").unwrap(),
+            SocketAddr::from(([127, 0, 0, 1], 4000))
+        );
+        // Checked before anything is asked on the LAN
+        assert!(resolve_server_addr("my-laptop.local:port").is_err());
+        assert!(resolve_server_addr("my-laptop.local").is_err());
+    }
+}
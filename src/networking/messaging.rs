@@ -1,74 +1,518 @@
 //! Contains the message type and functions to send and receive messages between clients and the server.
 
-use serde::{Deserialize, Serialize};
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::networking::error::ChatError;
+use crate::networking::transcript::{self, Direction};
+
+/// Version of the wire protocol spoken by this build, bumped on incompatible changes to `MessageType`.
+pub const PROTOCOL_VERSION: u32 = 21;
+
+/// The room clients are placed in until they join another one.
+pub const DEFAULT_ROOM: &str = "general";
+
+/// Longest pseudonym allowed by default, in characters.
+pub const DEFAULT_MAX_NAME_LENGTH: usize = 16;
+
+/// No pseudonym is ever longer than this many characters, whatever limit is configured.
+pub const NAME_LENGTH_CEILING: usize = 32;
+
+/// First and second halves of the pseudonyms given to users who don't pick one, e.g. `QuietOtter`.
+const NAME_ADJECTIVES: &[&str] = &[
+    "Quiet", "Brave", "Sunny", "Swift", "Clever", "Gentle", "Lucky", "Jolly", "Calm", "Bold",
+    "Witty", "Merry", "Keen", "Shy", "Proud", "Happy",
+];
+const NAME_ANIMALS: &[&str] = &[
+    "Otter", "Fox", "Panda", "Owl", "Koala", "Badger", "Heron", "Lynx", "Yak", "Falcon", "Gecko",
+    "Moose", "Puffin", "Wombat", "Bee", "Seal",
+];
 
 /// Message size in bytes (max packet size in TCP is 65535 bytes)
 pub const MAX_MESSAGE_SIZE: usize = 65_000;
 
+/// Longest text in bytes sent in a single `Message`, leaving room for the pseudonym and the rest of it.
+pub const MAX_TEXT_LENGTH: usize = MAX_MESSAGE_SIZE - 1_000;
+
+/// Longest status in characters, longer ones are cut short by the server.
+pub const MAX_STATUS_LENGTH: usize = 60;
+
 /// A message that can be sent between clients and the server.
 ///
 /// The numerous types of messages are categorized to help display the same in a better manner.
 /// Info, Leave, Error and Command (in progress) just need the text
-/// Message requires the content and the sender information
+/// Message requires the content, the sender information and a nonce picked by the sender, which the server acknowledges with an Ack telling how many others it reached.
+/// The sender information is filled in by the server from the pseudonym the connection registered with, clients leave it empty
 /// Pseudonym is used to initiliaze or update a pseuodonym
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// JoinRoom moves the client to another room, messages are only delivered within a room
+/// Presence announces a change in a user's presence state, the server fills in the nick the same way
+/// ListUsers asks the server for the users in the current room, which it answers with a UserList in the order they joined
+/// FileChunk carries a part of a file, FileResend asks the sender of a file for the chunks that never arrived
+/// Notice is an informational line of a specific kind, so that it can be told apart from plain Info
+/// SavedFile and SavedImage are never sent, they take the place of a received File or Image in the client's messages
+/// once it is saved, so that its contents don't stay in memory
+/// Code is a block of code in some language, its sender filled in by the server and its nonce and signature passed on like for Message
+/// Poll asks the room a question, clients send it with no id and the server broadcasts it with one
+/// Vote picks an option of a poll, which the server answers by broadcasting the new counts in a PollUpdate
+/// Welcome answers a Pseudonym with the pseudonym the server registered the client under, clients hold back their messages until then
+/// Status sets a short tagline shown next to the user in the UserList, empty to clear it, announced to the room like Presence
+/// The text users write is read with any invalid UTF-8 in it replaced, see `lossy_string`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum MessageType {
-    Info(String),            // Info message by server
-    Leave(String),           // Leaving message
-    Message(String, String), // Pseudonym and the message itself
-    Error(String),           // Error message by server
-    Command(String),         // Not yet implemented
-    Pseudonym(String),       // User pseudonym
-    File(String, Vec<u8>),   // File name, file content. This will be downloaded on client
-    Image(String, Vec<u8>),  // Image name, image content. Will be shown in sixel format on client
-                             // ? can prolly add an incomplete message, to get message larger than MAX_MESSAGE_SIZE
+    Info(#[serde(deserialize_with = "lossy_string")] String), // Info message by server
+    Leave(#[serde(deserialize_with = "lossy_string")] String), // Sent by a client as it quits, relayed by the server with the pseudonym of who left
+    Message(
+        #[serde(deserialize_with = "lossy_string")] String,
+        #[serde(deserialize_with = "lossy_string")] String,
+        u64,
+        Vec<u8>,
+    ), // Pseudonym, the message itself, its nonce and its signature, empty unless the sender signs, see `signing`
+    Error(#[serde(deserialize_with = "lossy_string")] String), // Error message by server
+    Command(String),                                           // Not yet implemented
+    Pseudonym(String),                                         // User pseudonym
+    File(String, Vec<u8>, String), // File name, file content, SHA-256 of the content. This will be downloaded on client
+    Image(String, Vec<u8>), // Image name, image content. Saved and previewed inline on client
+    JoinRoom(String),       // Room to switch to
+    Presence {
+        nick: String,
+        state: PresenceState,
+    },
+    ListUsers,
+    UserList(Vec<(String, PresenceState, u64, String, bool)>), // Pseudonym, presence, seconds connected, status and whether spectating of every user in the room
+    FileChunk {
+        transfer_id: u64,
+        file_name: String,
+        seq: u32,
+        total: u32,
+        data: Vec<u8>,
+        checksum: Option<String>, // SHA-256 of the whole file, sent with the final chunk
+    },
+    FileResend {
+        transfer_id: u64,
+        missing: Vec<u32>,
+    },
+    Ack {
+        nonce: u64,      // Nonce of the message that was broadcast
+        recipients: u32, // Clients other than the sender it was sent to
+    },
+    Notice(
+        NoticeKind,
+        #[serde(deserialize_with = "lossy_string")] String,
+    ), // Kind of notice and its text
+    SavedFile {
+        file_name: String, // Name the file was saved under
+        size: u64,
+        checksum: String,
+    },
+    SavedImage {
+        image_name: String, // Name the image was sent with
+        size: u64,
+    },
+    Welcome(String), // Pseudonym the client is registered under, which may not be the one it asked for
+    Code {
+        #[serde(deserialize_with = "lossy_string")]
+        sender: String,
+        #[serde(deserialize_with = "lossy_string")]
+        lang: String, // Language the code is in, empty if not given
+        #[serde(deserialize_with = "lossy_string")]
+        body: String,
+        nonce: u64,
+        signature: Vec<u8>, // Empty unless the sender signs, see `signing`
+    },
+    Poll {
+        id: u64,
+        #[serde(deserialize_with = "lossy_string")]
+        question: String,
+        options: Vec<String>,
+    },
+    Vote {
+        id: u64,       // Id of the poll
+        option: usize, // Counted from 0
+    },
+    PollUpdate {
+        id: u64,
+        counts: Vec<u32>, // Votes for each option
+    },
+    Status {
+        #[serde(deserialize_with = "lossy_string")]
+        nick: String,
+        #[serde(deserialize_with = "lossy_string")]
+        text: String,
+    },
+    /// Given by the server on registering, and sent back first when reconnecting so that the server replaces
+    /// the registration it may still hold for the old connection instead of counting the pseudonym as taken.
+    Resume {
+        token: u64,
+    },
+    /// Sent by the sender of a file to call off its transfer, relayed so that the receivers drop the partial file.
+    FileCancel {
+        transfer_id: u64,
+    },
+    /// Sent before registering by clients that only watch, the server then refuses anything they try to post.
+    Spectate,
+    /// Sent by the server right before it closes a connection it won't serve, with the reason. Clients told
+    /// this exit instead of reconnecting.
+    Rejected(#[serde(deserialize_with = "lossy_string")] String),
+    /// Sent by the server first thing on every connection, with its program version. Kept as it is from now on,
+    /// so that clients of any later version can still read it and tell when they are too old.
+    Hello {
+        version: String,
+        protocol: u32,
+    },
+    /// Sent to the rest of the room as a user registers or moves in, clients joining get a whole `UserList` instead.
+    UserJoined(String),
+    /// Sent to the rest of the room as a user leaves it, next to the `Leave` shown to people.
+    UserLeft(String),
+    /// The public key a client signs its messages with, sent with an empty nick after joining. The server fills
+    /// in the nick and passes it on to the room, and to clients joining the room later.
+    SigningKey {
+        nick: String,
+        key: Vec<u8>,
+    },
+    /// The salt a client derives its key for `--passphrase` from, sent with an empty nick after joining. Passed
+    /// on like a `SigningKey`, but only the first one of a connection, as clients derive a key for each.
+    Salt {
+        nick: String,
+        salt: Vec<u8>,
+    },
+    /// A chat message or code block replayed from the history of a room, with the key its sender signed with at
+    /// the time, empty if it didn't sign. The sender may have left since, and taken its `SigningKey` along.
+    Replayed {
+        message: Box<MessageType>,
+        key: Vec<u8>,
+    },
+}
+
+/// What a `Notice` is about, which decides how it is displayed.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum NoticeKind {
+    Server, // Announced by the server operator or the server itself
+    Tip,    // Usage tip shown by the client
+    #[serde(other)]
+    Info, // Kinds unknown to this build are shown as plain info
+}
+
+impl NoticeKind {
+    /// The label shown in front of a notice of this kind.
+    pub fn prefix(self) -> &'static str {
+        match self {
+            NoticeKind::Server => "[server] ",
+            NoticeKind::Tip => "[tip] ",
+            NoticeKind::Info => "",
+        }
+    }
+}
+
+/// Whether a user is around to chat. Everyone starts out as `Active`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub enum PresenceState {
+    #[default]
+    Active,
+    Away(Option<String>),         // Optional reason
+    DoNotDisturb(Option<String>), // Optional reason
+}
+
+impl std::fmt::Display for PresenceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (state, reason) = match self {
+            PresenceState::Active => return write!(f, "active"),
+            PresenceState::Away(reason) => ("away", reason),
+            PresenceState::DoNotDisturb(reason) => ("do not disturb", reason),
+        };
+
+        match reason {
+            Some(reason) => write!(f, "{}: {}", state, reason),
+            None => write!(f, "{}", state),
+        }
+    }
 }
 
 /// Responsible for sending a message given stream and message enum
-pub fn send_message(stream: &mut TcpStream, message: &MessageType) -> std::io::Result<()> {
-    let bytes = bincode::serialize(&message)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+///
+/// Every message is framed with its length as a big endian `u32`, so that the receiving end can
+/// tell messages apart no matter how the bytes are split or merged on the way.
+pub fn send_message<W: Write + ?Sized>(
+    stream: &mut W,
+    message: &MessageType,
+) -> Result<(), ChatError> {
+    let bytes = bincode::serialize(&message).map_err(ChatError::Serialize)?;
 
     if bytes.len() > MAX_MESSAGE_SIZE && !matches!(message, MessageType::Leave(_)) {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Message is too large to send",
-        ));
+        return Err(ChatError::TooLarge(bytes.len()));
     }
 
-    stream.write_all(&bytes)?;
+    let mut frame = Vec::with_capacity(4 + bytes.len());
+    frame.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&bytes);
+
+    // Written by hand rather than with `write_all`, to tell a frame cut short apart from one never started
+    let mut written = 0;
+    while written < frame.len() {
+        let error = match stream.write(&frame[written..]) {
+            Ok(0) => io::Error::from(io::ErrorKind::WriteZero),
+            Ok(n) => {
+                written += n;
+                continue;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => e,
+        };
+        return Err(if written == 0 {
+            ChatError::Io(error)
+        } else {
+            ChatError::PartialWrite {
+                written,
+                total: frame.len(),
+                error,
+            }
+        });
+    }
     stream.flush()?;
+    transcript::record(Direction::Sent, message, bytes.len());
 
     Ok(())
 }
 
 /// Responsible for receiving a message given stream
-pub fn receive_message(stream: &mut TcpStream) -> Result<MessageType, Box<dyn std::error::Error>> {
-    // BUG Files with larger sizes may give issues
-    let mut buffer = [0; MAX_MESSAGE_SIZE];
-    match stream.read(&mut buffer) {
-        Ok(_) => {}
-        Err(e) => {
-            return Err(format!(
-                "Couldn't read from stream properly. Receiving from: {}. Read error: {}",
-                stream.peer_addr()?,
-                e
-            )
-            .into());
+///
+/// A clean end of the stream before a new message is reported as `ChatError::Disconnected`.
+pub fn receive_message<R: Read + ?Sized>(stream: &mut R) -> Result<MessageType, ChatError> {
+    let mut length = [0; 4];
+    // A read of zero bytes means the peer has closed the connection
+    let first_read = loop {
+        match stream.read(&mut length[..1]) {
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            result => break result,
         }
+    };
+    if let Ok(0) = first_read {
+        return Err(ChatError::Disconnected);
     }
+    first_read.and_then(|_| stream.read_exact(&mut length[1..]))?;
 
-    let message: MessageType = bincode::deserialize(&buffer)?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length == 0 {
+        return Err(ChatError::InvalidMessage("empty frame".to_string()));
+    }
+    if length > MAX_MESSAGE_SIZE {
+        return Err(ChatError::TooLarge(length));
+    }
+
+    let mut buffer = vec![0; length];
+    stream.read_exact(&mut buffer)?;
+
+    let message: MessageType = bincode::deserialize(&buffer).map_err(ChatError::Deserialize)?;
+    transcript::record(Direction::Received, &message, length);
     Ok(message)
 }
 
+/// Reads a string field, replacing invalid UTF-8 with U+FFFD instead of failing the whole message.
+///
+/// Text comes from other users, whose clients may be broken or hostile, and a bad sequence in it shouldn't
+/// cost the connection. Strings are written as their bytes, so reading them as bytes works on the same frames.
+fn lossy_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    struct LossyString;
+
+    impl serde::de::Visitor<'_> for LossyString {
+        type Value = String;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a string")
+        }
+
+        fn visit_str<E: serde::de::Error>(self, text: &str) -> Result<String, E> {
+            Ok(text.to_string())
+        }
+
+        fn visit_string<E: serde::de::Error>(self, text: String) -> Result<String, E> {
+            Ok(text)
+        }
+
+        fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<String, E> {
+            Ok(String::from_utf8_lossy(bytes).into_owned())
+        }
+
+        fn visit_byte_buf<E: serde::de::Error>(self, bytes: Vec<u8>) -> Result<String, E> {
+            Ok(String::from_utf8(bytes)
+                .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned()))
+        }
+    }
+
+    deserializer.deserialize_byte_buf(LossyString)
+}
+
+/// Length of a pseudonym as counted against the limit, in characters rather than bytes.
+pub fn name_length(name: &str) -> usize {
+    name.chars().count()
+}
+
+/// A friendly pseudonym of at most `max_length` characters, made of an adjective and an animal.
+/// Returns None when the limit is too short for any of them.
+pub fn random_pseudonym(max_length: usize) -> Option<String> {
+    let names: Vec<String> = NAME_ADJECTIVES
+        .iter()
+        .flat_map(|adjective| {
+            NAME_ANIMALS
+                .iter()
+                .map(move |animal| format!("{}{}", adjective, animal))
+        })
+        .filter(|name| name_length(name) <= max_length)
+        .collect();
+    names.choose(&mut rand::thread_rng()).cloned()
+}
+
+/// Splits text into pieces of at most `max_length` bytes, at the last whitespace before the limit when there
+/// is one in the second half of the piece, and otherwise between two characters.
+///
+/// The whitespace the text is split at is dropped, so the pieces read as the text when shown one per line.
+pub fn split_text(text: &str, max_length: usize) -> Vec<&str> {
+    let mut pieces = vec![];
+    let mut rest = text;
+    while rest.len() > max_length {
+        let mut cut = max_length;
+        while !rest.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        if let Some(space) = rest[..cut].rfind(char::is_whitespace) {
+            if space > max_length / 2 {
+                cut = space;
+            }
+        }
+        pieces.push(rest[..cut].trim_end());
+        rest = rest[cut..].trim_start();
+    }
+    pieces.push(rest);
+    pieces.retain(|piece| !piece.is_empty());
+    pieces
+}
+
+/// Whether a character shows nothing: whitespace, control characters and zero-width characters.
+fn is_invisible(c: char) -> bool {
+    c.is_whitespace()
+        || c.is_control()
+        || matches!(
+            c,
+            '\u{200B}'..='\u{200D}' | '\u{2060}' | '\u{FEFF}' | '\u{180E}'
+        )
+}
+
+/// Whether a chat message shows nothing, being only whitespace, control characters and zero-width characters.
+pub fn is_blank(text: &str) -> bool {
+    text.chars().all(is_invisible)
+}
+
+/// Whether text looks like binary data pasted by accident, with control characters other than line breaks and
+/// tabs, or replacement characters left by invalid UTF-8, making up at least a tenth of it.
+///
+/// Control characters include terminal escapes, which can garble the screens of the whole room.
+pub fn looks_binary(text: &str) -> bool {
+    let total = text.chars().count();
+    let unprintable = text
+        .chars()
+        .filter(|&c| (c.is_control() && !matches!(c, '\n' | '\r' | '\t')) || c == '\u{FFFD}')
+        .count();
+    unprintable > 0 && unprintable * 10 >= total
+}
+
+/// Makes a status fit on one line of the user list: invisible characters become single spaces and it is cut
+/// to `MAX_STATUS_LENGTH` characters.
+pub fn clean_status(text: &str) -> String {
+    let words: Vec<&str> = text.split(is_invisible).filter(|w| !w.is_empty()).collect();
+    let status: String = words.join(" ").chars().take(MAX_STATUS_LENGTH).collect();
+    status.trim_end().to_string()
+}
+
 #[cfg(test)]
 mod tests {
-    use std::net::TcpListener;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
 
     use super::*;
+
+    #[test]
+    fn test_invalid_utf8_is_replaced() {
+        let message = MessageType::Message("bob".to_string(), "hi there".to_string(), 1, vec![]);
+        let mut frame = vec![];
+        send_message(&mut frame, &message).unwrap();
+        let text = frame.windows(8).position(|w| w == b"hi there").unwrap();
+        frame[text..text + 3].copy_from_slice(&[0xff, 0xc3, b' ']); // A stray byte and a cut off sequence
+
+        assert_eq!(
+            receive_message(&mut frame.as_slice()).unwrap(),
+            MessageType::Message(
+                "bob".to_string(),
+                "\u{FFFD}\u{FFFD} there".to_string(),
+                1,
+                vec![]
+            )
+        );
+    }
+
+    #[test]
+    fn test_random_pseudonyms_fit_the_limit() {
+        for _ in 0..100 {
+            let name = random_pseudonym(DEFAULT_MAX_NAME_LENGTH).unwrap();
+            assert!(name_length(&name) <= DEFAULT_MAX_NAME_LENGTH);
+        }
+        // Only the shortest halves fit
+        assert!(random_pseudonym(6).unwrap().starts_with("Shy"));
+        assert_eq!(random_pseudonym(5), None);
+    }
+
+    #[test]
+    fn test_long_text_is_split() {
+        assert_eq!(split_text("hello", 10), vec!["hello"]);
+        assert_eq!(
+            split_text("the quick brown fox jumps", 10),
+            vec!["the quick", "brown fox", "jumps"]
+        );
+        assert_eq!(split_text("abcdefghijkl", 5), vec!["abcde", "fghij", "kl"]);
+        // Never in the middle of a character
+        assert_eq!(split_text("ééé", 3), vec!["é", "é", "é"]);
+
+        let wall = "lorem ipsum ".repeat(20_000);
+        let pieces = split_text(&wall, MAX_TEXT_LENGTH);
+        assert_eq!(pieces.len(), 4);
+        assert!(pieces.iter().all(|piece| piece.len() <= MAX_TEXT_LENGTH));
+        assert!(pieces.join(" ") == wall);
+        let mut frame = vec![];
+        let longest = MessageType::Message("a".repeat(32), pieces[0].to_string(), 0, vec![]);
+        assert!(send_message(&mut frame, &longest).is_ok());
+    }
+
+    #[test]
+    fn test_blank_messages() {
+        assert!(is_blank(""));
+        assert!(is_blank("\t"));
+        assert!(is_blank("   \n "));
+        assert!(is_blank("\u{200B}"));
+        assert!(is_blank(" \u{200B}\u{FEFF}\u{7}\t"));
+        assert!(!is_blank("\u{200B}hi"));
+        assert!(!is_blank("👍"));
+    }
+
+    #[test]
+    fn test_binary_looking_text() {
+        assert!(looks_binary("\u{1b}[2J"));
+        assert!(looks_binary("PK\u{3}\u{4}\u{14}\u{0}\u{8}\u{0}"));
+        assert!(looks_binary("\u{FFFD}\u{FFFD}ELF\u{2}\u{1}"));
+        assert!(!looks_binary("line one\r\n\tline two"));
+        assert!(!looks_binary(
+            "a long message with one stray bell\u{7} in it"
+        ));
+        assert!(!looks_binary(""));
+    }
+
+    #[test]
+    fn test_statuses_are_cleaned() {
+        assert_eq!(clean_status("  reviewing\n\tPRs\u{200B} "), "reviewing PRs");
+        assert_eq!(clean_status("\u{7}\u{FEFF}"), "");
+        let long = clean_status(&"é ".repeat(MAX_STATUS_LENGTH));
+        assert_eq!(long.chars().count(), MAX_STATUS_LENGTH - 1);
+    }
+
     #[test]
     fn test_send_message() {
         let sender = TcpListener::bind("127.0.0.1:0").unwrap();
@@ -103,4 +547,144 @@ mod tests {
             output_message
         );
     }
+
+    #[test]
+    fn test_presence_state_display() {
+        assert_eq!(PresenceState::Active.to_string(), "active");
+        assert_eq!(PresenceState::Away(None).to_string(), "away");
+        assert_eq!(
+            PresenceState::DoNotDisturb(Some("in a meeting".to_string())).to_string(),
+            "do not disturb: in a meeting"
+        );
+    }
+
+    #[test]
+    fn test_unknown_notice_kind_is_info() {
+        // A kind added by a newer build, encoded as its variant index
+        let bytes = bincode::serialize(&7u32).unwrap();
+        let kind: NoticeKind = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(kind, NoticeKind::Info);
+
+        let bytes = bincode::serialize(&NoticeKind::Tip).unwrap();
+        assert_eq!(
+            bincode::deserialize::<NoticeKind>(&bytes).unwrap(),
+            NoticeKind::Tip
+        );
+    }
+
+    /// A connection that takes the first `left` bytes and then breaks.
+    struct Breaking {
+        left: usize,
+    }
+
+    impl Write for Breaking {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.left == 0 {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            let n = buf.len().min(self.left).min(7);
+            self.left -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_broken_partway_is_reported() {
+        let message =
+            MessageType::Message("alice".to_string(), "hello there".to_string(), 0, vec![]);
+
+        let error = send_message(&mut Breaking { left: 20 }, &message).unwrap_err();
+        let ChatError::PartialWrite { written, total, .. } = error else {
+            panic!("expected a partial write, got {:?}", error);
+        };
+        assert_eq!(written, 20);
+        assert!(total > written);
+
+        let error = send_message(&mut Breaking { left: 0 }, &message).unwrap_err();
+        assert!(matches!(error, ChatError::Io(_)));
+        assert!(error.is_disconnect());
+    }
+
+    #[test]
+    fn test_closed_connection_is_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let sender_stream = TcpStream::connect(addr).unwrap();
+        let (mut receiver_stream, _) = listener.accept().unwrap();
+        sender_stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let error = receive_message(&mut receiver_stream).unwrap_err();
+        assert!(matches!(error, ChatError::Disconnected));
+    }
+
+    #[test]
+    fn test_back_to_back_messages_are_received_separately() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let sender_handle = std::thread::spawn(move || {
+            let mut sender_stream = TcpStream::connect(addr).unwrap();
+            send_message(&mut sender_stream, &MessageType::Info("first".to_string())).unwrap();
+            send_message(&mut sender_stream, &MessageType::Info("second".to_string())).unwrap();
+        });
+
+        let (mut receiver_stream, _) = listener.accept().unwrap();
+        sender_handle.join().unwrap();
+
+        assert_eq!(
+            receive_message(&mut receiver_stream).unwrap(),
+            MessageType::Info("first".to_string())
+        );
+        assert_eq!(
+            receive_message(&mut receiver_stream).unwrap(),
+            MessageType::Info("second".to_string())
+        );
+    }
+
+    #[test]
+    fn test_concurrent_senders_sharing_a_locked_stream() {
+        const SENDERS: u64 = 8;
+        const MESSAGES: u64 = 50;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = Arc::new(Mutex::new(TcpStream::connect(addr).unwrap()));
+        let (mut receiver_stream, _) = listener.accept().unwrap();
+
+        // Large enough messages that a single write can be split up by the socket
+        let senders: Vec<_> = (0..SENDERS)
+            .map(|sender| {
+                let stream = Arc::clone(&stream);
+                std::thread::spawn(move || {
+                    for n in 0..MESSAGES {
+                        let text = sender.to_string().repeat(20_000);
+                        let message = MessageType::Message(String::new(), text, n, vec![]);
+                        send_message(&mut *stream.lock().unwrap(), &message).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        let mut next = [0; SENDERS as usize];
+        for _ in 0..SENDERS * MESSAGES {
+            let MessageType::Message(_, text, n, _) =
+                receive_message(&mut receiver_stream).unwrap()
+            else {
+                panic!("expected a chat message");
+            };
+            let sender: usize = text[..1].parse().unwrap();
+            assert_eq!(text, sender.to_string().repeat(20_000));
+            assert_eq!(n, next[sender]);
+            next[sender] += 1;
+        }
+
+        for sender in senders {
+            sender.join().unwrap();
+        }
+    }
 }
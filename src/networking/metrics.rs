@@ -0,0 +1,81 @@
+//! Counters of the traffic relayed by the server, logged periodically when `--metrics` is given.
+//!
+//! Every relayed message adds its size and the time it took to send it to the whole room (its fan-out).
+//! The counters are atomics so that client threads never wait on each other to update them.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Traffic relayed since the counters were last taken.
+#[derive(Default)]
+pub struct Metrics {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+    fan_out_nanos: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts a relayed message of `bytes` bytes that took `fan_out` to send to its room.
+    pub fn record(&self, bytes: u64, fan_out: Duration) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.fan_out_nanos
+            .fetch_add(fan_out.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the counters and starts them over.
+    pub fn take(&self) -> Snapshot {
+        Snapshot {
+            messages: self.messages.swap(0, Ordering::Relaxed),
+            bytes: self.bytes.swap(0, Ordering::Relaxed),
+            fan_out: Duration::from_nanos(self.fan_out_nanos.swap(0, Ordering::Relaxed)),
+        }
+    }
+}
+
+/// The counters over one interval.
+#[derive(Debug, PartialEq)]
+pub struct Snapshot {
+    messages: u64,
+    bytes: u64,
+    fan_out: Duration, // Total over all messages
+}
+
+impl Snapshot {
+    /// Formats the counters as `key=value` pairs, with rates over the given interval.
+    pub fn summary(&self, interval: Duration, clients: usize) -> String {
+        let seconds = interval.as_secs_f64().max(f64::EPSILON);
+        let average_fan_out = match self.messages {
+            0 => Duration::ZERO,
+            messages => self.fan_out / messages as u32,
+        };
+        format!(
+            "clients={} messages_per_sec={:.1} bytes_per_sec={:.0} avg_fan_out_ms={:.3}",
+            clients,
+            self.messages as f64 / seconds,
+            self.bytes as f64 / seconds,
+            average_fan_out.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_summary_and_reset() {
+        let metrics = Metrics::default();
+        metrics.record(100, Duration::from_millis(2));
+        metrics.record(300, Duration::from_millis(4));
+
+        assert_eq!(
+            metrics.take().summary(Duration::from_secs(2), 3),
+            "clients=3 messages_per_sec=1.0 bytes_per_sec=200 avg_fan_out_ms=3.000"
+        );
+        assert_eq!(
+            metrics.take().summary(Duration::from_secs(2), 0),
+            "clients=0 messages_per_sec=0.0 bytes_per_sec=0 avg_fan_out_ms=0.000"
+        );
+    }
+}
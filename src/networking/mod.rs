@@ -1,5 +1,17 @@
 //! Contains logic for network communication between clients and the server.
 
 pub mod client;
+pub mod connection;
+pub mod crypto;
+pub mod discovery;
+pub mod error;
+pub mod mdns;
 pub mod messaging;
-pub mod server;
\ No newline at end of file
+pub mod metrics;
+pub mod poll;
+pub mod server;
+pub mod signing;
+pub mod spam;
+pub mod stats;
+pub mod transcript;
+pub mod transfer;
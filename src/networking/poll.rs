@@ -0,0 +1,114 @@
+//! Polls: quick votes among the users of a room.
+//!
+//! The server keeps the votes of every poll by pseudonym, so that each user has a single vote they can
+//! change, and sends the room the new counts after every vote.
+
+use std::collections::HashMap;
+
+/// Most options a poll can have.
+pub const MAX_POLL_OPTIONS: usize = 10;
+
+/// The votes cast in a poll.
+pub struct Poll {
+    pub room: String, // Room the poll was started in, where its counts are sent
+    options: usize,
+    votes: HashMap<String, usize>, // Pseudonym to the option it voted for
+}
+
+impl Poll {
+    pub fn new(room: String, options: usize) -> Self {
+        Poll {
+            room,
+            options,
+            votes: HashMap::new(),
+        }
+    }
+
+    /// Records the vote of a user for an option, counted from 0, replacing their earlier vote.
+    pub fn vote(&mut self, voter: &str, option: usize) -> Result<(), String> {
+        if option >= self.options {
+            return Err(format!(
+                "There is no option {}, pick one from 1 to {}",
+                option + 1,
+                self.options
+            ));
+        }
+        self.votes.insert(voter.to_string(), option);
+        Ok(())
+    }
+
+    /// Returns the number of votes for each option.
+    pub fn counts(&self) -> Vec<u32> {
+        let mut counts = vec![0; self.options];
+        for option in self.votes.values() {
+            counts[*option] += 1;
+        }
+        counts
+    }
+}
+
+/// Checks that a poll has a question and between 2 and `MAX_POLL_OPTIONS` options.
+pub fn check_poll(question: &str, options: &[String]) -> Result<(), String> {
+    if question.trim().is_empty() {
+        return Err("A poll needs a question".to_string());
+    }
+    if !(2..=MAX_POLL_OPTIONS).contains(&options.len()) {
+        return Err(format!(
+            "A poll needs between 2 and {} options",
+            MAX_POLL_OPTIONS
+        ));
+    }
+    Ok(())
+}
+
+/// Parses the arguments of `/poll`, a question and its options, each a single word or words in double quotes.
+pub fn parse_poll_args(args: &str) -> Result<(String, Vec<String>), String> {
+    let mut words = vec![];
+    let mut rest = args.trim_start();
+    while !rest.is_empty() {
+        let (word, after) = match rest.strip_prefix('"') {
+            Some(quoted) => quoted
+                .split_once('"')
+                .ok_or_else(|| "Missing closing quote".to_string())?,
+            None => rest.split_once(char::is_whitespace).unwrap_or((rest, "")),
+        };
+        words.push(word.to_string());
+        rest = after.trim_start();
+    }
+
+    if words.is_empty() {
+        return Err("Usage: /poll \"question\" option1 option2 ...".to_string());
+    }
+    let question = words.remove(0);
+    check_poll(&question, &words)?;
+    Ok((question, words))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_votes_can_be_changed() {
+        let mut poll = Poll::new("general".to_string(), 3);
+        poll.vote("alice", 0).unwrap();
+        poll.vote("bob", 0).unwrap();
+        poll.vote("alice", 2).unwrap();
+        assert_eq!(poll.counts(), vec![1, 0, 1]);
+        assert!(poll.vote("carol", 3).is_err());
+    }
+
+    #[test]
+    fn test_poll_args_are_parsed() {
+        assert_eq!(
+            parse_poll_args(r#""Lunch where?" pizza "the usual place""#),
+            Ok((
+                "Lunch where?".to_string(),
+                vec!["pizza".to_string(), "the usual place".to_string()]
+            ))
+        );
+        assert!(parse_poll_args(r#""Lunch?" pizza"#).is_err());
+        assert!(parse_poll_args(r#""Lunch? pizza tacos"#).is_err());
+        assert!(parse_poll_args("").is_err());
+    }
+}
@@ -3,220 +3,3002 @@
 //! It listens for incoming connections and broadcasts messages to all the clients.
 //! and maintains a list of clients from which it can remove them.
 
-use std::io::{self, Write};
-use std::net::{TcpListener, TcpStream};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 extern crate if_addrs;
 use if_addrs::get_if_addrs;
 use log::*;
 
-use crate::networking::messaging::{receive_message, send_message, MessageType};
+use crate::networking::connection::tune_tcp;
+use crate::networking::discovery::{beacon_socket, send_beacon, Beacon, BEACON_INTERVAL};
+use crate::networking::error::ChatError;
+use crate::networking::messaging::{
+    clean_status, is_blank, name_length, receive_message, send_message, MessageType, NoticeKind,
+    PresenceState, DEFAULT_MAX_NAME_LENGTH, DEFAULT_ROOM, NAME_LENGTH_CEILING, PROTOCOL_VERSION,
+};
+use crate::networking::metrics::Metrics;
+use crate::networking::poll::{check_poll, Poll};
+use crate::networking::spam::{SpamGuard, Verdict, MUTE_DURATION};
+use crate::networking::transfer::{format_size, FILE_CHUNK_SIZE};
+use crate::timezone;
+
+/// Pseudonym a client is registered with until it sends its own.
+const UNNAMED_PSEUDONYM: &str = "[blank]";
+
+/// A client coming back under the same pseudonym within this long is not announced again.
+const JOIN_DEBOUNCE: Duration = Duration::from_secs(10);
+
+/// Most transfers relayed in full whose sender is kept for resend requests.
+const MAX_FINISHED_TRANSFERS: usize = 64;
+
+/// Default limit on the size of the files relayed by the server, in bytes.
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 50 * 1024 * 1024;
+
+/// Default number of messages kept per room for clients joining later.
+pub const DEFAULT_HISTORY: usize = 50;
+
+/// Exit code of a server that can't listen on its address, e.g. when started twice on the same port.
+pub const BIND_FAILED_EXIT_CODE: i32 = 5;
+
+/// Policies set by the operator of the server.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Port to listen on, 0 lets the system pick a free one.
+    pub port: u16,
+    /// Files larger than this many bytes are rejected instead of being relayed.
+    pub max_file_size: u64,
+    /// Longer pseudonyms are shortened to this many characters, up to `NAME_LENGTH_CEILING`.
+    pub max_name_length: usize,
+    /// Number of recent messages replayed to clients joining a room, 0 keeps no history.
+    pub history: usize,
+    /// How often traffic metrics are logged, if at all.
+    pub metrics_interval: Option<Duration>,
+    /// Whether files are relayed, otherwise their senders are told file transfer is disabled.
+    pub allow_files: bool,
+    /// Whether images are relayed, otherwise their senders are told image sharing is disabled.
+    pub allow_images: bool,
+    /// What to do when a client asks for the pseudonym of another one connected from the same machine.
+    pub duplicates: Duplicates,
+    /// The only pseudonyms clients may register under, ignoring case, if restricted.
+    pub allowed_nicks: Option<Vec<String>>,
+}
+
+/// What happens to a second connection from the same machine under the same pseudonym.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Duplicates {
+    /// It is given the first free pseudonym made by adding a number, like any other taken pseudonym.
+    #[default]
+    Rename,
+    /// It is told the pseudonym is already connected and disconnected.
+    Reject,
+    /// The first connection is closed and the second one takes its place.
+    Replace,
+}
+
+impl FromStr for Duplicates {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rename" => Ok(Duplicates::Rename),
+            "reject" => Ok(Duplicates::Reject),
+            "replace" => Ok(Duplicates::Replace),
+            _ => Err(format!(
+                "unknown duplicate handling '{}', expected rename, reject or replace",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Duplicates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Duplicates::Rename => write!(f, "rename"),
+            Duplicates::Reject => write!(f, "reject"),
+            Duplicates::Replace => write!(f, "replace"),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            port: 0,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_name_length: DEFAULT_MAX_NAME_LENGTH,
+            history: DEFAULT_HISTORY,
+            metrics_interval: None,
+            allow_files: true,
+            allow_images: true,
+            duplicates: Duplicates::default(),
+            allowed_nicks: None,
+        }
+    }
+}
+
+/// A connected client: connection, address, pseudonym and room.
+type Client = (Box<dyn ClientConn>, String, String, String);
+
+/// The users of a room: pseudonym, presence, seconds connected and status.
+type UserList = Vec<(String, PresenceState, u64, String, bool)>;
+
+/// The end of a connection the server writes to a client through.
+///
+/// Implemented by `TcpStream` and `UnixStream` when serving, so that the logic of the server can be tested over other connections.
+trait ClientConn: Write + Send {
+    /// Closes the connection, which also stops the thread reading from it.
+    fn close(&self) -> io::Result<()>;
+
+    /// Sends a message, closing the connection if it broke partway through. The client then sees the
+    /// connection drop and reconnects instead of waiting for the rest of a frame that will never come.
+    fn send(&mut self, message: &MessageType) -> Result<(), ChatError> {
+        let result = send_message(self, message);
+        if let Err(e @ ChatError::PartialWrite { .. }) = &result {
+            warn!("Closing a client connection: {}", e);
+            let _ = self.close();
+        }
+        result
+    }
+}
+
+impl ClientConn for TcpStream {
+    fn close(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl ClientConn for UnixStream {
+    fn close(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+}
+
+/// A chunked file transfer going through the server.
+#[derive(Default)]
+struct RelayedTransfer {
+    bytes: u64, // Bytes relayed so far
+    rejected: bool,
+}
+
+/// A chat message or code block kept in the history of a room, with the key its sender signed with and the salt
+/// it announced, so that it still checks out and opens for clients joining after the sender has left.
+struct Remembered {
+    message: MessageType,
+    key: Vec<u8>,
+    salt: Option<MessageType>,
+}
 
 /// The chat server. Contains a list of clients and can broadcast messages to all of them.
+///
+/// Every thread can write to any client, so writes only ever go through the streams in `clients` with the
+/// list locked. This keeps each message frame whole, as the receiving end can't make sense of interleaved ones.
 #[derive(Clone)]
 struct Server {
-    clients: Arc<Mutex<Vec<(TcpStream, String, String)>>>, // Stream, address, pseudonym
+    config: ServerConfig,
+    clients: Arc<Mutex<Vec<Client>>>,
+    presence: Arc<Mutex<HashMap<String, PresenceState>>>, // Address to presence
+    connected_at: Arc<Mutex<HashMap<String, Instant>>>,   // Address to when it connected
+    statuses: Arc<Mutex<HashMap<String, String>>>,        // Address to status
+    transfers: Arc<Mutex<HashMap<(String, u64), RelayedTransfer>>>, // Address of the sender and transfer id to transfer
+    finished_transfers: Arc<Mutex<VecDeque<(String, u64)>>>, // Address of the sender and id of the transfers relayed in full lately
+    spam: Arc<Mutex<HashMap<String, SpamGuard>>>,            // Address to spam guard
+    history: Arc<Mutex<HashMap<String, VecDeque<Remembered>>>>, // Room to its recent messages
+    recent_joins: Arc<Mutex<HashMap<(String, String), Instant>>>, // IP and pseudonym to when they last joined
+    metrics: Arc<Metrics>,
+    alone: Arc<Mutex<HashSet<String>>>, // Addresses told nobody else is in their room
+    polls: Arc<Mutex<HashMap<u64, Poll>>>, // Poll id to poll
+    sessions: Arc<Mutex<HashMap<u64, String>>>, // Session token to the address of the client given it
+    spectators: Arc<Mutex<HashSet<String>>>,    // Addresses of the clients only watching
+    signing_keys: Arc<Mutex<HashMap<String, Vec<u8>>>>, // Address to the public key its messages are signed with
+    salts: Arc<Mutex<HashMap<String, Vec<u8>>>>, // Address to the salt its key for `--passphrase` is derived from
 }
 
 impl Server {
-    fn new() -> Self {
+    fn new(config: ServerConfig) -> Self {
         Server {
+            config,
             clients: Arc::new(Mutex::new(Vec::new())),
+            presence: Arc::new(Mutex::new(HashMap::new())),
+            connected_at: Arc::default(),
+            statuses: Arc::default(),
+            transfers: Arc::new(Mutex::new(HashMap::new())),
+            finished_transfers: Arc::default(),
+            spam: Arc::new(Mutex::new(HashMap::new())),
+            history: Arc::new(Mutex::new(HashMap::new())),
+            recent_joins: Arc::new(Mutex::new(HashMap::new())),
+            metrics: Arc::default(),
+            alone: Arc::default(),
+            polls: Arc::default(),
+            sessions: Arc::default(),
+            spectators: Arc::default(),
+            signing_keys: Arc::default(),
+            salts: Arc::default(),
         }
     }
 
     /// Add a new client to the server. On addition it broadcasts a message to all the existing clients that a new client has joined and logs to server.
     fn add_client(
         &self,
-        client: TcpStream,
+        client: Box<dyn ClientConn>,
         addr: String,
         pseudonym: String,
-    ) -> Result<(), Box<dyn std::error::Error + '_>> {
+        room: String,
+    ) -> Result<(), ChatError> {
         let mut clients = self.clients.lock()?;
-        clients.push((client, addr, pseudonym.clone()));
+        self.connected_at
+            .lock()?
+            .insert(addr.clone(), Instant::now());
+        clients.push((client, addr, pseudonym.clone(), room));
 
         Ok(())
     }
 
-    /// Implementation of broadcasting a message to all the clients. Also logs the message to the server.
-    fn broadcast(&self, message: &MessageType) -> Result<(), Box<dyn std::error::Error + '_>> {
+    /// Implementation of broadcasting a message from `sender` to all the clients in a room, except the one at
+    /// `exclude`. Also logs the message to the server. Returns the number of clients reached other than the sender.
+    ///
+    /// Chat messages leave out their sender, who shows them from the moment they are sent and tracks them with the
+    /// `Ack`. Presence and status changes go to the sender as well, as that is how it learns they took effect.
+    fn broadcast(
+        &self,
+        message: &MessageType,
+        room: &str,
+        sender: &str,
+        exclude: Option<&str>,
+    ) -> Result<usize, ChatError> {
         let mut clients = self.clients.lock()?;
-        // println!("In broadcast: {:?}", clients);
-        match message {
-            MessageType::Message(pseudonym, ref message_string) => {
-                for (client, _, _) in clients.iter_mut() {
-                    send_message(client, message)?;
-                }
-                info!("({}): {}", pseudonym, message_string);
+        let recipients = clients
+            .iter()
+            .filter(|(_, a, _, r)| r == room && a != sender)
+            .count();
+        let reached = |(_, a, _, r): &&mut Client| r == room && Some(a.as_str()) != exclude;
+        let dead_clients = match message {
+            MessageType::Message(_, ref message_string, nonce, ref signature) => {
+                // The pseudonym is the one the sender registered with, whatever name it put in the message
+                let pseudonym = pseudonym_of(&clients, sender).to_string();
+                let message = MessageType::Message(
+                    pseudonym.clone(),
+                    message_string.clone(),
+                    *nonce,
+                    signature.clone(),
+                );
+
+                let started = Instant::now();
+                let dead_clients = send_each(clients.iter_mut().filter(reached), &message);
+                info!("[{}] ({}): {}", room, pseudonym, message_string);
+                log_broadcast(sender, &pseudonym, room, &message);
+                self.record_relay(&message, started);
+                self.remember(room, sender, message)?;
+                dead_clients
             }
-            MessageType::Leave(addr) => {
-                self.remove_client(addr)?;
+            MessageType::Code {
+                lang,
+                body,
+                nonce,
+                signature,
+                ..
+            } => {
+                let pseudonym = pseudonym_of(&clients, sender).to_string();
+                let code = MessageType::Code {
+                    sender: pseudonym.clone(),
+                    lang: lang.clone(),
+                    body: body.clone(),
+                    nonce: *nonce,
+                    signature: signature.clone(),
+                };
+
+                let started = Instant::now();
+                let dead_clients = send_each(clients.iter_mut().filter(reached), &code);
+                info!("[{}] ({}) {} code: {:?}", room, pseudonym, lang, body);
+                log_broadcast(sender, &pseudonym, room, &code);
+                self.record_relay(&code, started);
+                self.remember(room, sender, code)?;
+                dead_clients
+            }
+            MessageType::Presence { nick, state } => {
+                let started = Instant::now();
+                let dead_clients = send_each(clients.iter_mut().filter(reached), message);
+                info!("[{}] {} is now {}", room, nick, state);
+                log_broadcast(sender, nick, room, message);
+                self.record_relay(message, started);
+                dead_clients
+            }
+            MessageType::Status { nick, text } => {
+                let started = Instant::now();
+                let dead_clients = send_each(clients.iter_mut().filter(reached), message);
+                info!("[{}] {} set their status to {:?}", room, nick, text);
+                log_broadcast(sender, nick, room, message);
+                self.record_relay(message, started);
+                dead_clients
+            }
+            MessageType::SigningKey { nick, .. } => {
+                info!("[{}] {} signs their messages", room, nick);
+                send_each(clients.iter_mut().filter(reached), message)
+            }
+            MessageType::Salt { nick, .. } => {
+                info!("[{}] {} seals their messages", room, nick);
+                send_each(clients.iter_mut().filter(reached), message)
+            }
+            _ => vec![],
+        };
+        drop(clients);
+
+        let lost = dead_clients.iter().filter(|a| *a != sender).count();
+        self.remove_dead(dead_clients);
+        Ok(recipients - lost)
+    }
+
+    /// Keeps a chat message or code block in the history of its room, for `replay_history`.
+    fn remember(&self, room: &str, sender: &str, message: MessageType) -> Result<(), ChatError> {
+        if self.config.history == 0 {
+            return Ok(());
+        }
+        let salt = match &message {
+            MessageType::Message(nick, ..) | MessageType::Code { sender: nick, .. } => self
+                .salts
+                .lock()?
+                .get(sender)
+                .map(|salt| MessageType::Salt {
+                    nick: nick.clone(),
+                    salt: salt.clone(),
+                }),
+            _ => None,
+        };
+        let key = self
+            .signing_keys
+            .lock()?
+            .get(sender)
+            .cloned()
+            .unwrap_or_default();
+        let mut history = self.history.lock()?;
+        let room_history = history.entry(room.to_string()).or_default();
+        room_history.push_back(Remembered { message, key, salt });
+        if room_history.len() > self.config.history {
+            room_history.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Updates the presence of a client and announces it to the room. Does nothing if the presence is unchanged.
+    fn set_presence(&self, addr: &str, state: PresenceState) -> Result<(), ChatError> {
+        if self.presence.lock()?.get(addr) == Some(&state) {
+            return Ok(());
+        }
+        self.presence
+            .lock()?
+            .insert(addr.to_string(), state.clone());
+
+        let Some((nick, room)) = self
+            .clients
+            .lock()?
+            .iter()
+            .find(|(_, a, _, _)| a == addr)
+            .map(|(_, _, p, r)| (p.clone(), r.clone()))
+        else {
+            return Ok(());
+        };
+
+        self.broadcast(&MessageType::Presence { nick, state }, &room, addr, None)
+            .map(|_| ())
+    }
+
+    /// Sets the status of a client, cleaned up to fit the user list, and announces it to the room. An empty
+    /// status clears it.
+    fn set_status(&self, addr: &str, text: &str) -> Result<(), ChatError> {
+        let text = clean_status(text);
+        if text.is_empty() {
+            self.statuses.lock()?.remove(addr);
+        } else {
+            self.statuses.lock()?.insert(addr.to_string(), text.clone());
+        }
+
+        let Some((nick, room)) = self
+            .clients
+            .lock()?
+            .iter()
+            .find(|(_, a, _, _)| a == addr)
+            .map(|(_, _, p, r)| (p.clone(), r.clone()))
+        else {
+            return Ok(());
+        };
+
+        self.broadcast(&MessageType::Status { nick, text }, &room, addr, None)
+            .map(|_| ())
+    }
+
+    /// Keeps the key a client signs its messages with, and passes it on to its room under its pseudonym. The
+    /// signatures themselves are relayed with the messages and never checked here.
+    fn set_signing_key(&self, addr: &str, key: Vec<u8>) -> Result<(), ChatError> {
+        let Some((nick, room)) = self
+            .clients
+            .lock()?
+            .iter()
+            .find(|(_, a, p, _)| a == addr && p != UNNAMED_PSEUDONYM)
+            .map(|(_, _, p, r)| (p.clone(), r.clone()))
+        else {
+            return Ok(());
+        };
+        self.signing_keys
+            .lock()?
+            .insert(addr.to_string(), key.clone());
+
+        let signing_key = MessageType::SigningKey { nick, key };
+        self.broadcast(&signing_key, &room, addr, Some(addr))
+            .map(|_| ())
+    }
+
+    /// Keeps the salt a client derives its key for `--passphrase` from, and passes it on to its room under its
+    /// pseudonym. Only the first salt of a connection is taken, as the other clients derive a key for each.
+    fn set_salt(&self, addr: &str, salt: Vec<u8>) -> Result<(), ChatError> {
+        let Some((nick, room)) = self
+            .clients
+            .lock()?
+            .iter()
+            .find(|(_, a, p, _)| a == addr && p != UNNAMED_PSEUDONYM)
+            .map(|(_, _, p, r)| (p.clone(), r.clone()))
+        else {
+            return Ok(());
+        };
+        let mut salts = self.salts.lock()?;
+        if salts.contains_key(addr) {
+            warn!("{} announced another salt", addr);
+            return Ok(());
+        }
+        salts.insert(addr.to_string(), salt.clone());
+        drop(salts);
+
+        let salt = MessageType::Salt { nick, salt };
+        self.broadcast(&salt, &room, addr, Some(addr)).map(|_| ())
+    }
+
+    /// The keys a client has published, under its pseudonym: the one it signs with and the salt it seals with.
+    fn keys_of(&self, addr: &str, nick: &str) -> Result<Vec<MessageType>, ChatError> {
+        let signing_key = self
+            .signing_keys
+            .lock()?
+            .get(addr)
+            .map(|key| MessageType::SigningKey {
+                nick: nick.to_string(),
+                key: key.clone(),
+            });
+        let salt = self.salts.lock()?.get(addr).map(|salt| MessageType::Salt {
+            nick: nick.to_string(),
+            salt: salt.clone(),
+        });
+        Ok(signing_key.into_iter().chain(salt).collect())
+    }
+
+    /// Returns the pseudonym, presence, time connected, status and whether spectating of every client in the room.
+    fn user_list(&self, room: &str) -> Result<UserList, ChatError> {
+        let clients = self.clients.lock()?;
+        let presence = self.presence.lock()?;
+        let connected_at = self.connected_at.lock()?;
+        let statuses = self.statuses.lock()?;
+        let spectators = self.spectators.lock()?;
+
+        // Clients are kept in the order they connected, so the longest connected come first
+        Ok(clients
+            .iter()
+            .filter(|(_, _, p, r)| r == room && p != UNNAMED_PSEUDONYM)
+            .map(|(_, a, p, _)| {
+                let connected = connected_at.get(a).map_or(0, |at| at.elapsed().as_secs());
+                let status = statuses.get(a).cloned().unwrap_or_default();
+                (
+                    p.clone(),
+                    presence.get(a).cloned().unwrap_or_default(),
+                    connected,
+                    status,
+                    spectators.contains(a),
+                )
+            })
+            .collect())
+    }
+
+    /// Sends a client the users of its room, which it then keeps up to date from `UserJoined` and `UserLeft`,
+    /// followed by the keys of those who sign or seal their messages.
+    fn send_roster(&self, addr: &str, room: &str) -> Result<(), ChatError> {
+        let user_list = MessageType::UserList(self.user_list(room)?);
+        self.send_to(addr, &user_list)?;
+
+        let mut clients = self.clients.lock()?;
+        let mut keys = vec![];
+        for (_, a, p, _) in clients.iter().filter(|(_, a, _, r)| r == room && a != addr) {
+            keys.extend(self.keys_of(a, p)?);
+        }
+        if let Some((stream, _, _, _)) = clients.iter_mut().find(|(_, a, _, _)| a == addr) {
+            for key in &keys {
+                stream.send(key)?;
             }
-            _ => {}
         }
+        Ok(())
+    }
 
+    /// Tells the rest of a room that a client has registered in it.
+    fn announce_arrival(&self, addr: &str, pseudonym: &str, room: &str) -> Result<(), ChatError> {
+        let joined = MessageType::UserJoined(pseudonym.to_string());
+        let dead_clients = send_each(
+            self.clients
+                .lock()?
+                .iter_mut()
+                .filter(|(_, a, _, r)| r == room && a != addr),
+            &joined,
+        );
+        self.remove_dead(dead_clients);
         Ok(())
     }
 
-    /// Removes a client from the server. Also broadcasts a message to all the clients that the client has left and logs to server.
-    fn remove_client(&self, addr: &str) -> Result<(), Box<dyn std::error::Error + '_>> {
+    /// Removes a client from the server and closes its connection. Also broadcasts a message to all the clients that the client has left and logs to server.
+    fn remove_client(&self, addr: &str) -> Result<(), ChatError> {
         let mut clients = self.clients.lock()?;
         // Find and remove the client by address
-        if let Some(index) = clients.iter().position(|(_, a, _)| a == addr) {
-            let (_, _, p) = clients.remove(index);
-            // Notify all clients about the departure
-            for (client, _, _) in &mut *clients {
-                send_message(client, &MessageType::Leave(p.clone()))?;
-                client.flush()?;
+        if let Some(index) = clients.iter().position(|(_, a, _, _)| a == addr) {
+            let (client, _, p, room) = clients.remove(index);
+            let _ = client.close();
+            self.presence.lock()?.remove(addr);
+            self.connected_at.lock()?.remove(addr);
+            self.statuses.lock()?.remove(addr);
+            self.spam.lock()?.remove(addr);
+            self.alone.lock()?.remove(addr);
+            self.transfers
+                .lock()?
+                .retain(|(sender, _), _| sender != addr);
+            self.finished_transfers
+                .lock()?
+                .retain(|(sender, _)| sender != addr);
+            self.sessions.lock()?.retain(|_, a| a != addr);
+            self.spectators.lock()?.remove(addr);
+            self.signing_keys.lock()?.remove(addr);
+            self.salts.lock()?.remove(addr);
+            if p == UNNAMED_PSEUDONYM {
+                return Ok(());
+            }
+            // The room is told about the departure, so it should be told when they come back too
+            self.recent_joins.lock()?.remove(&(ip_of(addr), p.clone()));
+
+            // Notify all clients in the same room about the departure
+            for (client, a, _, _) in clients.iter_mut().filter(|(_, _, _, r)| r == &room) {
+                let left = client
+                    .send(&MessageType::Leave(p.clone()))
+                    .and_then(|_| client.send(&MessageType::UserLeft(p.clone())));
+                if let Err(e) = left {
+                    error!("Failed to tell {} that {} left: {}", a, p, e);
+                }
             }
             warn!("{} (pseudonym: {}) has left the chat.", addr, p);
         }
         Ok(())
     }
-}
 
-// TODO when server is SIGTERM kick all clients and close
-/// Runs the server. The server listens for incoming connections and spawns a new thread for each one.
-pub fn run_server(server_ip: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let server = Server::new();
+    /// Returns the session token of a client, making one up if it has none yet.
+    fn session_token(&self, addr: &str) -> Result<u64, ChatError> {
+        let mut sessions = self.sessions.lock()?;
+        if let Some((&token, _)) = sessions.iter().find(|(_, a)| *a == addr) {
+            return Ok(token);
+        }
+        let token = rand::random();
+        sessions.insert(token, addr.to_string());
+        Ok(token)
+    }
 
-    // let listener = TcpListener::bind(format!("{server_ip}:0")).unwrap();
-    let listener = TcpListener::bind(format!("{server_ip}:0"))?;
-    println!("Server listening on {}", listener.local_addr()?);
-    println!(
-        "To join the chat, use the following command: lan-chat -s {}",
-        listener.local_addr()?
-    );
-    println!(
-        "Running program version {}, Created by {}",
-        env!("CARGO_PKG_VERSION"),
-        env!("CARGO_PKG_AUTHORS")
-    );
+    /// Hands the session of a reconnecting client over to its new connection. The old connection, if the
+    /// server still has it, is closed without telling the room, and its status and connection time carry over.
+    ///
+    /// Unknown tokens are ignored, the client then registers like any other.
+    fn resume(&self, addr: &str, token: u64) -> Result<(), ChatError> {
+        let Some(stale) = self.sessions.lock()?.remove(&token) else {
+            return Ok(());
+        };
+        self.sessions.lock()?.insert(token, addr.to_string());
+        if stale == addr {
+            return Ok(());
+        }
 
-    for stream in listener.incoming() {
-        let mut stream = stream?;
-        let server = server.clone();
-        let client_addr = stream.peer_addr()?.to_string();
-        let client_addr_clone = client_addr.clone();
+        if let Some(pseudonym) = self.take_over(addr, &stale)? {
+            info!(
+                "{} has resumed the session of {} (pseudonym: {})",
+                addr, stale, pseudonym
+            );
+        }
+        Ok(())
+    }
 
-        server
-            .add_client(
-                stream.try_clone().unwrap(),
-                client_addr.clone(),
-                "[blank]".to_string(),
-            )
-            .unwrap();
-        thread::spawn(move || {
-            let server = server.clone();
-            while let Ok(message) = receive_message(&mut stream) {
-                match message {
-                    MessageType::Leave(addr) => {
-                        if let Err(e) = server.remove_client(&addr) {
-                            error!(
-                                "Failed to remove client: {}. Client removal error: {}",
-                                addr, e
-                            );
-                            std::process::exit(1);
-                        }
-                    }
-                    MessageType::Message(_, _) => {
-                        if let Err(e) = server.broadcast(&message) {
-                            error!("Failed to broadcast message. Broadcasting error: {}", e);
-                            std::process::exit(1);
-                        }
+    /// Closes the connection of a client and gives its status and connection time to another one, without
+    /// telling the room. Returns the pseudonym of the closed client, if it was still there.
+    fn take_over(&self, addr: &str, stale: &str) -> Result<Option<String>, ChatError> {
+        let mut clients = self.clients.lock()?;
+        let Some(index) = clients.iter().position(|(_, a, _, _)| *a == stale) else {
+            return Ok(None);
+        };
+        let (client, _, pseudonym, _) = clients.remove(index);
+        let _ = client.close();
+        let mut connected_at = self.connected_at.lock()?;
+        if let Some(at) = connected_at.remove(stale) {
+            connected_at.insert(addr.to_string(), at);
+        }
+        let mut statuses = self.statuses.lock()?;
+        if let Some(status) = statuses.remove(stale) {
+            statuses.insert(addr.to_string(), status);
+        }
+        drop((connected_at, statuses));
+        self.presence.lock()?.remove(stale);
+        self.spam.lock()?.remove(stale);
+        self.alone.lock()?.remove(stale);
+        self.transfers
+            .lock()?
+            .retain(|(sender, _), _| sender != stale);
+        self.finished_transfers
+            .lock()?
+            .retain(|(sender, _)| sender != stale);
+        self.sessions.lock()?.retain(|_, a| a != stale);
+        self.spectators.lock()?.remove(stale);
+        self.salts.lock()?.remove(stale);
+        Ok(Some(pseudonym))
+    }
+
+    /// Whether a pseudonym may be registered, being on the guest list if there is one.
+    fn is_allowed(&self, pseudonym: &str) -> bool {
+        let pseudonym = pseudonym.to_lowercase();
+        self.config
+            .allowed_nicks
+            .as_ref()
+            .is_none_or(|allowed| allowed.iter().any(|nick| nick.to_lowercase() == pseudonym))
+    }
+
+    /// Returns the address of another client on the same machine registered under the same pseudonym.
+    fn duplicate_of(&self, addr: &str, pseudonym: &str) -> Option<String> {
+        let ip = ip_of(addr);
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, a, p, _)| a != addr && p.eq_ignore_ascii_case(pseudonym) && ip_of(a) == ip)
+            .map(|(_, a, _, _)| a.clone())
+    }
+
+    /// Returns whether a client taking a pseudonym should be announced to the room.
+    ///
+    /// The placeholder pseudonym is never announced, nor is a pseudonym that joined from the same IP moments ago
+    /// and hasn't been seen leaving since, so that connections replacing each other don't flood the room with
+    /// join notices.
+    fn should_announce_join(&self, addr: &str, pseudonym: &str) -> Result<bool, ChatError> {
+        if pseudonym.is_empty() || pseudonym == UNNAMED_PSEUDONYM {
+            return Ok(false);
+        }
+
+        let mut recent_joins = self.recent_joins.lock()?;
+        let now = Instant::now();
+        recent_joins.retain(|_, seen| now.duration_since(*seen) < JOIN_DEBOUNCE);
+
+        Ok(recent_joins
+            .insert((ip_of(addr), pseudonym.to_string()), now)
+            .is_none())
+    }
+
+    /// Tells a client that just sent a message when nobody else is in its room.
+    ///
+    /// It is only told once, and again after someone else has been in the room when it sent a message.
+    fn note_if_alone(&self, addr: &str, room: &str) -> Result<(), ChatError> {
+        let others = self
+            .clients
+            .lock()?
+            .iter()
+            .filter(|(_, a, _, r)| r == room && a != addr)
+            .count();
+
+        let mut alone = self.alone.lock()?;
+        if others > 0 {
+            alone.remove(addr);
+            return Ok(());
+        }
+        if alone.insert(addr.to_string()) {
+            drop(alone);
+            let notice = "You're the only one here, nobody else got that message";
+            self.send_to(
+                addr,
+                &MessageType::Notice(NoticeKind::Server, notice.to_string()),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Returns the pseudonym cut down to the longest one allowed, if it is longer.
+    fn shorten_pseudonym(&self, pseudonym: &str) -> Option<String> {
+        let limit = self.config.max_name_length.min(NAME_LENGTH_CEILING);
+        (name_length(pseudonym) > limit).then(|| pseudonym.chars().take(limit).collect())
+    }
+
+    /// Gives a client its pseudonym, or when another client has it already, the first free one made by adding a
+    /// number to it. Returns the pseudonym given and the room of the client.
+    ///
+    /// Checked and set with `clients` locked, so that two clients asking for the same pseudonym at once can't
+    /// both get it.
+    fn register(&self, addr: &str, pseudonym: &str) -> Result<(String, String), ChatError> {
+        let mut clients = self.clients.lock()?;
+        let taken = |name: &str| {
+            name.eq_ignore_ascii_case(UNNAMED_PSEUDONYM)
+                || clients
+                    .iter()
+                    .any(|(_, a, p, _)| a != addr && p.eq_ignore_ascii_case(name))
+        };
+
+        let limit = self.config.max_name_length.min(NAME_LENGTH_CEILING);
+        let mut given = pseudonym.to_string();
+        let mut number = 2;
+        while taken(&given) {
+            let suffix = number.to_string();
+            let base: String = pseudonym
+                .chars()
+                .take(limit.saturating_sub(suffix.len()))
+                .collect();
+            given = format!("{}{}", base, suffix);
+            number += 1;
+        }
+
+        let room = match clients.iter_mut().find(|(_, a, _, _)| a == addr) {
+            Some(client) => {
+                client.2.clone_from(&given);
+                client.3.clone()
+            }
+            None => DEFAULT_ROOM.to_string(),
+        };
+        Ok((given, room))
+    }
+
+    /// Starts a poll in the room of a client and shows it to everyone there.
+    fn start_poll(
+        &self,
+        addr: &str,
+        question: String,
+        options: Vec<String>,
+    ) -> Result<(), ChatError> {
+        if let Err(e) = check_poll(&question, &options) {
+            return self.send_to(addr, &MessageType::Error(e));
+        }
+
+        let room = self
+            .room_of(addr)
+            .unwrap_or_else(|| DEFAULT_ROOM.to_string());
+        let id = {
+            let mut polls = self.polls.lock()?;
+            let id = polls.keys().max().map_or(1, |id| id + 1);
+            polls.insert(id, Poll::new(room.clone(), options.len()));
+            id
+        };
+        info!("[{}] {} started poll #{}: {}", room, addr, id, question);
+
+        self.send_to_room(
+            &MessageType::Poll {
+                id,
+                question,
+                options,
+            },
+            &room,
+        )
+    }
+
+    /// Records the vote of a client, replacing its earlier one, and shows the new counts to the room of the poll.
+    fn vote(&self, addr: &str, id: u64, option: usize) -> Result<(), ChatError> {
+        let pseudonym = pseudonym_of(&self.clients.lock()?, addr).to_string();
+        let room = self.room_of(addr);
+
+        let mut polls = self.polls.lock()?;
+        let result = match polls.get_mut(&id) {
+            Some(poll) if Some(&poll.room) == room.as_ref() => poll
+                .vote(&pseudonym, option)
+                .map(|_| (poll.room.clone(), poll.counts())),
+            _ => Err(format!("There is no poll #{} in this room", id)),
+        };
+        drop(polls);
+
+        match result {
+            Ok((room, counts)) => self.send_to_room(&MessageType::PollUpdate { id, counts }, &room),
+            Err(e) => self.send_to(addr, &MessageType::Error(e)),
+        }
+    }
+
+    /// Returns the room the client with the given address is currently in.
+    fn room_of(&self, addr: &str) -> Option<String> {
+        let clients = self.clients.lock().ok()?;
+        clients
+            .iter()
+            .find(|(_, a, _, _)| a == addr)
+            .map(|(_, _, _, room)| room.clone())
+    }
+
+    /// Returns the default room and every room with someone in it, sorted by name.
+    fn rooms(&self) -> Vec<String> {
+        let mut rooms = vec![DEFAULT_ROOM.to_string()];
+        if let Ok(clients) = self.clients.lock() {
+            rooms.extend(clients.iter().map(|(_, _, _, room)| room.clone()));
+        }
+        rooms.sort();
+        rooms.dedup();
+        rooms
+    }
+
+    /// Moves a client to another room, announcing the departure to the old room and the arrival to the new one.
+    fn join_room(&self, addr: &str, room: &str) -> Result<(), ChatError> {
+        let mut clients = self.clients.lock()?;
+        let Some(index) = clients.iter().position(|(_, a, _, _)| a == addr) else {
+            return Ok(());
+        };
+
+        let old_room = clients[index].3.clone();
+        if old_room == room {
+            return Ok(());
+        }
+        clients[index].3 = room.to_string();
+        let pseudonym = clients[index].2.clone();
+
+        // Clients picking their initial room haven't been announced anywhere yet
+        if pseudonym == UNNAMED_PSEUDONYM {
+            return Ok(());
+        }
+        let keys = self.keys_of(addr, &pseudonym)?;
+
+        let mut dead_clients = vec![];
+        for (client, a, _, r) in clients.iter_mut() {
+            if a == addr {
+                continue;
+            }
+
+            let notified = if r == &old_room {
+                client
+                    .send(&MessageType::Leave(pseudonym.clone()))
+                    .and_then(|_| client.send(&MessageType::UserLeft(pseudonym.clone())))
+            } else if r == room {
+                client
+                    .send(&MessageType::Info(format!(
+                        "{} has entered the room.",
+                        pseudonym
+                    )))
+                    .and_then(|_| client.send(&MessageType::UserJoined(pseudonym.clone())))
+                    .and_then(|_| keys.iter().try_for_each(|key| client.send(key)))
+            } else {
+                Ok(())
+            };
+            if let Err(e) = notified {
+                error!("Failed to tell {} that {} moved: {}", a, pseudonym, e);
+                dead_clients.push(a.clone());
+            }
+        }
+        info!(
+            "{} has moved from room '{}' to '{}'",
+            pseudonym, old_room, room
+        );
+        drop(clients);
+        self.remove_dead(dead_clients);
+
+        Ok(())
+    }
+
+    /// Counts a message relayed to a room, whose sending started at `started`, in the metrics.
+    fn record_relay(&self, message: &MessageType, started: Instant) {
+        let bytes = bincode::serialized_size(message).unwrap_or_default();
+        self.metrics.record(bytes, started.elapsed());
+    }
+
+    /// Sends a message to a single client, doing nothing if it has already left.
+    fn send_to(&self, addr: &str, message: &MessageType) -> Result<(), ChatError> {
+        let mut clients = self.clients.lock()?;
+        if let Some((stream, _, _, _)) = clients.iter_mut().find(|(_, a, _, _)| a == addr) {
+            stream.send(message)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the recent messages of a room to a client that just joined it, between two notices marking them as history.
+    fn replay_history(&self, addr: &str, room: &str) -> Result<(), ChatError> {
+        let mut clients = self.clients.lock()?;
+        let Some((stream, _, _, _)) = clients.iter_mut().find(|(_, a, _, _)| a == addr) else {
+            return Ok(());
+        };
+        let history = self.history.lock()?;
+        let Some(room_history) = history.get(room).filter(|messages| !messages.is_empty()) else {
+            return Ok(());
+        };
+
+        stream.send(&MessageType::Info(format!(
+            "--- Last {} message(s) in {} ---",
+            room_history.len(),
+            room
+        )))?;
+        let mut salts_sent = vec![];
+        for Remembered { message, key, salt } in room_history {
+            // Senders who have left since aren't in the roster, so their keys come along
+            if let Some(salt) = salt.as_ref().filter(|salt| !salts_sent.contains(salt)) {
+                stream.send(salt)?;
+                salts_sent.push(salt);
+            }
+            stream.send(&MessageType::Replayed {
+                message: Box::new(message.clone()),
+                key: key.clone(),
+            })?;
+        }
+        stream.send(&MessageType::Info("--- End of history ---".to_string()))?;
+
+        Ok(())
+    }
+
+    /// Sends a message to every client in a room.
+    ///
+    /// Clients that can't be sent to are removed afterwards, so that one dead connection doesn't keep the
+    /// rest of the room from getting the message.
+    fn send_to_room(&self, message: &MessageType, room: &str) -> Result<(), ChatError> {
+        let dead_clients = send_each(
+            self.clients
+                .lock()?
+                .iter_mut()
+                .filter(|(_, _, _, r)| r == room),
+            message,
+        );
+        self.remove_dead(dead_clients);
+
+        Ok(())
+    }
+
+    /// Removes the clients that couldn't be sent to, once the list of clients is no longer locked.
+    fn remove_dead(&self, dead_clients: Vec<String>) {
+        for addr in dead_clients {
+            if let Err(e) = self.remove_client(&addr) {
+                error!("Failed to remove client {}: {}", addr, e);
+            }
+        }
+    }
+
+    /// Checks a message against the client's recent messages, and warns, mutes or kicks the client for spamming.
+    ///
+    /// Returns whether the message should be broadcast.
+    fn screen_spam(&self, addr: &str, text: &str) -> Result<bool, ChatError> {
+        let verdict = self
+            .spam
+            .lock()?
+            .entry(addr.to_string())
+            .or_default()
+            .check(text, Instant::now());
+        let Some((pseudonym, room)) = self
+            .clients
+            .lock()?
+            .iter()
+            .find(|(_, a, _, _)| a == addr)
+            .map(|(_, _, p, r)| (p.clone(), r.clone()))
+        else {
+            return Ok(false);
+        };
+
+        match verdict {
+            Verdict::Allow => return Ok(true),
+            Verdict::Warn => {
+                self.send_to(
+                    addr,
+                    &MessageType::Error(
+                        "Please stop repeating the same message, or you will be muted".to_string(),
+                    ),
+                )?;
+            }
+            Verdict::Muted(remaining) => {
+                self.send_to(
+                    addr,
+                    &MessageType::Error(format!(
+                        "You are muted for spamming, {} seconds left",
+                        remaining.as_secs() + 1
+                    )),
+                )?;
+            }
+            Verdict::Mute => {
+                self.send_to(
+                    addr,
+                    &MessageType::Error(format!(
+                        "You have been muted for {} seconds for spamming",
+                        MUTE_DURATION.as_secs()
+                    )),
+                )?;
+                self.send_to_room(
+                    &MessageType::Notice(
+                        NoticeKind::Server,
+                        format!("{} has been muted for spamming.", pseudonym),
+                    ),
+                    &room,
+                )?;
+            }
+            Verdict::Kick => {
+                self.send_to(
+                    addr,
+                    &MessageType::Error("You have been kicked for spamming".to_string()),
+                )?;
+                self.remove_client(addr)?;
+                self.send_to_room(
+                    &MessageType::Notice(
+                        NoticeKind::Server,
+                        format!("{} has been kicked for spamming.", pseudonym),
+                    ),
+                    &room,
+                )?;
+            }
+        }
+        warn!("{} ({}) is spamming: {:?}", addr, pseudonym, verdict);
+
+        Ok(false)
+    }
+
+    /// Sends an announcement from the operator of the server to every client, whatever their room.
+    fn announce(&self, text: &str) -> Result<(), ChatError> {
+        let announcement = MessageType::Notice(NoticeKind::Server, text.to_string());
+        for (client, addr, _, _) in self.clients.lock()?.iter_mut() {
+            if let Err(e) = client.send(&announcement) {
+                error!("Failed to send announcement to {}: {}", addr, e);
+            }
+        }
+        info!("[SERVER] {}", text);
+
+        Ok(())
+    }
+
+    /// Tells every client that the server is going away and disconnects them.
+    fn shutdown(&self) -> Result<(), ChatError> {
+        self.announce("The server is shutting down.")?;
+        for (client, _, _, _) in self.clients.lock()?.drain(..) {
+            let _ = client.close();
+        }
+        warn!("Server has shut down.");
+
+        Ok(())
+    }
+
+    /// Handles the messages read from a client until it leaves or its connection drops, then removes it.
+    ///
+    /// Everything is written back through `clients`, so `reader` only needs to be the reading end of the connection.
+    fn serve_client(&self, reader: &mut impl Read, client_addr: &str) {
+        loop {
+            let message = match receive_message(reader) {
+                Ok(message) => message,
+                Err(e) => {
+                    if !matches!(e, ChatError::Disconnected) {
+                        warn!("Lost connection to {}: {}", client_addr, e);
                     }
-                    MessageType::Command(command) => {
-                        info!(
-                            "Client {} has run the command '{}'",
-                            client_addr_clone, command
+                    break;
+                }
+            };
+            if !spectator_may_send(&message)
+                && self.spectators.lock().unwrap().contains(client_addr)
+            {
+                debug!("Refused a post from spectator {}", client_addr);
+                let refusal = MessageType::Error("Spectators can't send messages".to_string());
+                let _ = self.send_to(client_addr, &refusal);
+                continue;
+            }
+            match message {
+                MessageType::Leave(_) => {
+                    // Whatever the client put in there, the connection is what is leaving, and remove_client
+                    // tells the room its registered pseudonym
+                    if let Err(e) = self.remove_client(client_addr) {
+                        error!(
+                            "Failed to remove client: {}. Client removal error: {}",
+                            client_addr, e
                         );
                     }
-                    MessageType::Pseudonym(pseudonym) => {
-                        let mut clients = server.clients.lock().unwrap();
-                        if let Some(index) = clients.iter().position(|(_, a, _)| a == &client_addr)
-                        {
-                            clients[index].2.clone_from(&pseudonym);
+                    break;
+                }
+                MessageType::Message(_, ref text, ..) if is_blank(text) => {
+                    debug!("Dropped a blank message from {}", client_addr);
+                }
+                MessageType::Message(_, ref text, nonce, _)
+                | MessageType::Code {
+                    body: ref text,
+                    nonce,
+                    ..
+                } => {
+                    match self.screen_spam(client_addr, text) {
+                        Ok(true) => {}
+                        Ok(false) => continue,
+                        Err(e) => {
+                            error!("Failed to check {} for spam: {}", client_addr, e);
                         }
+                    }
 
-                        info!(
-                            "{} has entered the chat with the pseudonym '{}'",
-                            client_addr_clone, pseudonym
-                        );
-
-                        // Notify all existing clients about the new client
-                        let join_message = format!("{} has entered the chat.", pseudonym);
-                        for (existing_client, _, _) in &mut *clients {
-                            send_message(existing_client, &MessageType::Info(join_message.clone()))
-                                .unwrap();
-                        }
+                    // Sending a message means the user is back
+                    if let Err(e) = self.set_presence(client_addr, PresenceState::Active) {
+                        error!("Failed to update presence of {}: {}", client_addr, e);
                     }
-                    MessageType::File(file_name, file_contents) => {
-                        info!("{} has sent a file: {}", client_addr_clone, file_name);
-                        let mut clients = server.clients.lock().unwrap();
-                        for (client, _, _) in &mut *clients {
-                            if client.peer_addr().unwrap().to_string() == client_addr {
-                                continue;
+
+                    let room = self
+                        .room_of(client_addr)
+                        .unwrap_or_else(|| DEFAULT_ROOM.to_string());
+                    let recipients =
+                        match self.broadcast(&message, &room, client_addr, Some(client_addr)) {
+                            Ok(recipients) => recipients as u32,
+                            Err(e) => {
+                                error!("Failed to broadcast message. Broadcasting error: {}", e);
+                                0
                             }
+                        };
 
-                            send_message(
-                                client,
-                                &MessageType::File(file_name.clone(), file_contents.clone()),
-                            )
-                            .unwrap();
+                    let ack = MessageType::Ack { nonce, recipients };
+                    if let Err(e) = self.send_to(client_addr, &ack) {
+                        error!("Failed to acknowledge message from {}: {}", client_addr, e);
+                    }
+                    if let Err(e) = self.note_if_alone(client_addr, &room) {
+                        error!("Failed to tell {} it is alone: {}", client_addr, e);
+                    }
+                }
+                MessageType::Command(command) => {
+                    info!("Client {} has run the command '{}'", client_addr, command);
+                }
+                MessageType::Spectate => {
+                    info!("{} is joining as a spectator", client_addr);
+                    self.spectators
+                        .lock()
+                        .unwrap()
+                        .insert(client_addr.to_string());
+                }
+                MessageType::Resume { token } => {
+                    if let Err(e) = self.resume(client_addr, token) {
+                        error!("Failed to resume the session of {}: {}", client_addr, e);
+                    }
+                }
+                MessageType::Pseudonym(mut pseudonym) => {
+                    if !self.is_allowed(&pseudonym) {
+                        warn!(
+                            "{} tried to join as {}, who is not on the guest list",
+                            client_addr, pseudonym
+                        );
+                        let refusal = MessageType::Rejected("not on the guest list".to_string());
+                        let _ = self.send_to(client_addr, &refusal);
+                        break;
+                    }
+                    if let Some(shortened) = self.shorten_pseudonym(&pseudonym) {
+                        let notice = format!(
+                            "Pseudonyms can be at most {} characters, yours was shortened to {}",
+                            name_length(&shortened),
+                            shortened
+                        );
+                        if let Err(e) = self.send_to(
+                            client_addr,
+                            &MessageType::Notice(NoticeKind::Server, notice),
+                        ) {
+                            error!(
+                                "Failed to tell {} its pseudonym was shortened: {}",
+                                client_addr, e
+                            );
                         }
+                        pseudonym = shortened;
                     }
-                    MessageType::Image(image_name, image_contents) => {
-                        info!("{} has sent a image: {}", client_addr_clone, image_name);
-                        let mut clients = server.clients.lock().unwrap();
-                        for (client, _, _) in &mut *clients {
-                            if client.peer_addr().unwrap().to_string() == client_addr {
-                                continue;
+
+                    if let Some(other) = self.duplicate_of(client_addr, &pseudonym) {
+                        match self.config.duplicates {
+                            Duplicates::Rename => {}
+                            Duplicates::Reject => {
+                                warn!(
+                                    "{} tried to connect as {}, who is already connected from {}",
+                                    client_addr, pseudonym, other
+                                );
+                                let reason =
+                                    format!("{} is already connected from this machine", pseudonym);
+                                let _ = self.send_to(client_addr, &MessageType::Rejected(reason));
+                                break;
+                            }
+                            Duplicates::Replace => {
+                                let _ = self.send_to(
+                                    &other,
+                                    &MessageType::Rejected(format!(
+                                        "{} has connected again from this machine",
+                                        pseudonym
+                                    )),
+                                );
+                                if let Err(e) = self.take_over(client_addr, &other) {
+                                    error!("Failed to replace {}: {}", other, e);
+                                }
+                                info!(
+                                    "{} has replaced {} (pseudonym: {})",
+                                    client_addr, other, pseudonym
+                                );
                             }
+                        }
+                    }
 
-                            send_message(
-                                client,
-                                &MessageType::Image(image_name.clone(), image_contents.clone()),
-                            )
-                            .unwrap();
+                    let (given, room) = match self.register(client_addr, &pseudonym) {
+                        Ok(registered) => registered,
+                        Err(e) => {
+                            error!("Failed to register {}: {}", client_addr, e);
+                            continue;
                         }
+                    };
+                    if given != pseudonym {
+                        let info = format!("{} is taken, you are now {}", pseudonym, given);
+                        if let Err(e) = self.send_to(client_addr, &MessageType::Info(info)) {
+                            error!("Failed to tell {} its new pseudonym: {}", client_addr, e);
+                        }
+                        pseudonym = given;
                     }
-                    _ => {}
-                }
-            }
+                    self.presence
+                        .lock()
+                        .unwrap()
+                        .insert(client_addr.to_string(), PresenceState::Active);
 
-            if let Err(e) = server.remove_client(&client_addr) {
-                error!("Failed to remove client: {}. Reason: {}", client_addr, e);
-            };
-        });
-    }
+                    info!(
+                        "{} has entered the chat with the pseudonym '{}'",
+                        client_addr, pseudonym
+                    );
 
-    Ok(())
+                    match self.session_token(client_addr) {
+                        Ok(token) => {
+                            let _ = self.send_to(client_addr, &MessageType::Resume { token });
+                        }
+                        Err(e) => error!("Failed to give {} a session: {}", client_addr, e),
+                    }
+                    if let Err(e) =
+                        self.send_to(client_addr, &MessageType::Welcome(pseudonym.clone()))
+                    {
+                        error!("Failed to welcome {}: {}", client_addr, e);
+                    }
+                    if let Err(e) = self.send_roster(client_addr, &room) {
+                        error!(
+                            "Failed to send the users of '{}' to {}: {}",
+                            room, client_addr, e
+                        );
+                    }
+                    if let Err(e) = self.announce_arrival(client_addr, &pseudonym, &room) {
+                        error!("Failed to tell the room {} joined: {}", pseudonym, e);
+                    }
+
+                    // Notify all existing clients in the room about the new client
+                    if self
+                        .should_announce_join(client_addr, &pseudonym)
+                        .unwrap_or(true)
+                    {
+                        let join_message = format!(
+                            "{} has entered the chat at {}.",
+                            pseudonym,
+                            timezone::time_of_day(SystemTime::now())
+                        );
+                        if let Err(e) = self.send_to_room(&MessageType::Info(join_message), &room) {
+                            error!("Failed to announce {}: {}", pseudonym, e);
+                        }
+                    }
+
+                    if let Err(e) = self.replay_history(client_addr, &room) {
+                        error!("Failed to replay history to {}: {}", client_addr, e);
+                    }
+                }
+                MessageType::Poll {
+                    question, options, ..
+                } => {
+                    if let Err(e) = self.start_poll(client_addr, question, options) {
+                        error!("Failed to start poll for {}: {}", client_addr, e);
+                    }
+                }
+                MessageType::Vote { id, option } => {
+                    if let Err(e) = self.vote(client_addr, id, option) {
+                        error!("Failed to record vote of {}: {}", client_addr, e);
+                    }
+                }
+                MessageType::Presence { state, .. } => {
+                    if let Err(e) = self.set_presence(client_addr, state) {
+                        error!("Failed to update presence of {}: {}", client_addr, e);
+                    }
+                }
+                MessageType::Status { text, .. } => {
+                    if let Err(e) = self.set_status(client_addr, &text) {
+                        error!("Failed to update status of {}: {}", client_addr, e);
+                    }
+                }
+                MessageType::SigningKey { key, .. } => {
+                    if let Err(e) = self.set_signing_key(client_addr, key) {
+                        error!(
+                            "Failed to pass on the signing key of {}: {}",
+                            client_addr, e
+                        );
+                    }
+                }
+                MessageType::Salt { salt, .. } => {
+                    if let Err(e) = self.set_salt(client_addr, salt) {
+                        error!("Failed to pass on the salt of {}: {}", client_addr, e);
+                    }
+                }
+                MessageType::ListUsers => {
+                    let room = self
+                        .room_of(client_addr)
+                        .unwrap_or_else(|| DEFAULT_ROOM.to_string());
+                    let user_list = match self.user_list(&room) {
+                        Ok(user_list) => user_list,
+                        Err(e) => {
+                            error!("Failed to list users in room '{}': {}", room, e);
+                            continue;
+                        }
+                    };
+
+                    if let Err(e) = self.send_to(client_addr, &MessageType::UserList(user_list)) {
+                        error!("Failed to send user list to {}: {}", client_addr, e);
+                    }
+                }
+                MessageType::JoinRoom(room) => {
+                    if self.room_of(client_addr).as_ref() == Some(&room) {
+                        continue;
+                    }
+                    if let Err(e) = self.join_room(client_addr, &room) {
+                        error!("Failed to move {} to room '{}': {}", client_addr, room, e);
+                    }
+
+                    // Clients picking their initial room get the history once they have a pseudonym
+                    let has_pseudonym = self
+                        .clients
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .any(|(_, a, p, _)| a == client_addr && p != UNNAMED_PSEUDONYM);
+                    if has_pseudonym {
+                        if let Err(e) = self.send_roster(client_addr, &room) {
+                            error!(
+                                "Failed to send the users of '{}' to {}: {}",
+                                room, client_addr, e
+                            );
+                        }
+                        if let Err(e) = self.replay_history(client_addr, &room) {
+                            error!("Failed to replay history to {}: {}", client_addr, e);
+                        }
+                    }
+                }
+                MessageType::File(ref file_name, ref file_contents, _) => {
+                    if !self.config.allow_files {
+                        warn!("{} tried to send a file: {}", client_addr, file_name);
+                        let _ = self.send_to(client_addr, &files_disabled(file_name));
+                        continue;
+                    }
+                    if file_contents.len() as u64 > self.config.max_file_size {
+                        warn!(
+                            "{} tried to send a file over the size limit: {}",
+                            client_addr, file_name
+                        );
+                        let _ = self.send_to(
+                            client_addr,
+                            &file_too_large(file_name, self.config.max_file_size),
+                        );
+                        continue;
+                    }
+
+                    info!("{} has sent a file: {}", client_addr, file_name);
+                    let room = self
+                        .room_of(client_addr)
+                        .unwrap_or_else(|| DEFAULT_ROOM.to_string());
+                    let mut clients = self.clients.lock().unwrap();
+                    let started = Instant::now();
+                    let dead_clients = send_each(
+                        clients
+                            .iter_mut()
+                            .filter(|(_, a, _, r)| r == &room && a != client_addr),
+                        &message,
+                    );
+                    log_broadcast(
+                        client_addr,
+                        pseudonym_of(&clients, client_addr),
+                        &room,
+                        &message,
+                    );
+                    self.record_relay(&message, started);
+                    drop(clients);
+                    self.remove_dead(dead_clients);
+                }
+                MessageType::FileChunk {
+                    transfer_id,
+                    ref file_name,
+                    seq,
+                    total,
+                    ref data,
+                    ..
+                } => {
+                    if seq == 0 {
+                        info!(
+                            "{} has started sending a file: {} ({} chunks)",
+                            client_addr, file_name, total
+                        );
+                    }
+
+                    // Kept by sender, so that nobody else can add to a transfer or call it off
+                    let key = (client_addr.to_string(), transfer_id);
+                    let is_last = seq + 1 >= total;
+                    let max_file_size = self.config.max_file_size;
+                    let mut transfers = self.transfers.lock().unwrap();
+                    let transfer = transfers.entry(key.clone()).or_default();
+                    if transfer.rejected {
+                        if is_last {
+                            transfers.remove(&key);
+                        }
+                        continue;
+                    }
+                    if !self.config.allow_files {
+                        transfer.rejected = true;
+                        warn!("{} tried to send a file: {}", client_addr, file_name);
+                        let _ = self.send_to(client_addr, &files_disabled(file_name));
+                        continue;
+                    }
+
+                    // Reject as early as the announced number of chunks gives the size away
+                    transfer.bytes += data.len() as u64;
+                    let announced_size = (total as u64).saturating_sub(1) * FILE_CHUNK_SIZE as u64;
+                    if transfer.bytes > max_file_size || announced_size > max_file_size {
+                        transfer.rejected = true;
+                        warn!(
+                            "{} tried to send a file over the size limit: {}",
+                            client_addr, file_name
+                        );
+                        let _ =
+                            self.send_to(client_addr, &file_too_large(file_name, max_file_size));
+                        continue;
+                    }
+                    drop(transfers);
+
+                    let room = self
+                        .room_of(client_addr)
+                        .unwrap_or_else(|| DEFAULT_ROOM.to_string());
+                    let mut clients = self.clients.lock().unwrap();
+                    let started = Instant::now();
+                    let dead_clients = send_each(
+                        clients
+                            .iter_mut()
+                            .filter(|(_, a, _, r)| r == &room && a != client_addr),
+                        &message,
+                    );
+                    log_broadcast(
+                        client_addr,
+                        pseudonym_of(&clients, client_addr),
+                        &room,
+                        &message,
+                    );
+                    self.record_relay(&message, started);
+                    drop(clients);
+                    self.remove_dead(dead_clients);
+
+                    // The sender is still kept for a while, as receivers ask for what they missed once it stalls
+                    if is_last {
+                        self.transfers.lock().unwrap().remove(&key);
+                        let mut finished = self.finished_transfers.lock().unwrap();
+                        if !finished.contains(&key) {
+                            finished.push_back(key);
+                        }
+                        if finished.len() > MAX_FINISHED_TRANSFERS {
+                            finished.pop_front();
+                        }
+                    }
+                }
+                MessageType::FileResend { transfer_id, .. } => {
+                    // Only the sender of the file can answer, the resent chunks then reach the room as usual. Ids
+                    // are picked by the senders, so a request goes to each sender of a transfer with its id
+                    let mut senders: HashSet<String> = self
+                        .transfers
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter(|((_, id), transfer)| *id == transfer_id && !transfer.rejected)
+                        .map(|((sender, _), _)| sender.clone())
+                        .collect();
+                    senders.extend(
+                        self.finished_transfers
+                            .lock()
+                            .unwrap()
+                            .iter()
+                            .filter(|(_, id)| *id == transfer_id)
+                            .map(|(sender, _)| sender.clone()),
+                    );
+
+                    let mut clients = self.clients.lock().unwrap();
+                    for (sender_stream, sender, _, _) in clients
+                        .iter_mut()
+                        .filter(|(_, a, _, _)| senders.contains(a))
+                    {
+                        if let Err(e) = sender_stream.send(&message) {
+                            error!("Failed to forward resend request to {}: {}", sender, e);
+                        }
+                    }
+                }
+                MessageType::FileCancel { transfer_id } => {
+                    // Only the sender of a file can call it off, any chunks still coming are then dropped
+                    match self
+                        .transfers
+                        .lock()
+                        .unwrap()
+                        .get_mut(&(client_addr.to_string(), transfer_id))
+                    {
+                        Some(transfer) if !transfer.rejected => transfer.rejected = true,
+                        _ => continue,
+                    }
+                    info!("{} cancelled sending a file", client_addr);
+
+                    let room = self
+                        .room_of(client_addr)
+                        .unwrap_or_else(|| DEFAULT_ROOM.to_string());
+                    let mut clients = self.clients.lock().unwrap();
+                    let dead_clients = send_each(
+                        clients
+                            .iter_mut()
+                            .filter(|(_, a, _, r)| r == &room && a != client_addr),
+                        &message,
+                    );
+                    drop(clients);
+                    self.remove_dead(dead_clients);
+                }
+                MessageType::Image(ref image_name, _) => {
+                    if !self.config.allow_images {
+                        warn!("{} tried to send an image: {}", client_addr, image_name);
+                        let _ = self.send_to(
+                            client_addr,
+                            &MessageType::Error(format!(
+                                "Image {} was not sent, image sharing is disabled on this server",
+                                image_name
+                            )),
+                        );
+                        continue;
+                    }
+                    info!("{} has sent a image: {}", client_addr, image_name);
+                    let room = self
+                        .room_of(client_addr)
+                        .unwrap_or_else(|| DEFAULT_ROOM.to_string());
+                    let mut clients = self.clients.lock().unwrap();
+                    let started = Instant::now();
+                    let dead_clients = send_each(
+                        clients
+                            .iter_mut()
+                            .filter(|(_, a, _, r)| r == &room && a != client_addr),
+                        &message,
+                    );
+                    log_broadcast(
+                        client_addr,
+                        pseudonym_of(&clients, client_addr),
+                        &room,
+                        &message,
+                    );
+                    self.record_relay(&message, started);
+                    drop(clients);
+                    self.remove_dead(dead_clients);
+                }
+                _ => {}
+            }
+        }
+
+        if let Err(e) = self.remove_client(client_addr) {
+            error!("Failed to remove client: {}. Reason: {}", client_addr, e);
+        };
+    }
 }
 
-/// Returns the local IPv4 address of the machine.
-pub fn get_local_ipv4() -> io::Result<String> {
-    if let Ok(interfaces) = get_if_addrs() {
-        for interface in interfaces {
-            if !interface.is_loopback() && !interface.addr.is_link_local() {
-                if let if_addrs::IfAddr::V4(ref addr) = interface.addr {
-                    return Ok(addr.ip.to_string());
+/// Reads announcements typed into the server's terminal and broadcasts them.
+///
+/// Empty lines are ignored and `/shutdown` disconnects every client and stops the server.
+fn spawn_console(server: Server) {
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            let line = line.trim();
+
+            if line.is_empty() {
+                continue;
+            } else if line == "/shutdown" {
+                if let Err(e) = server.shutdown() {
+                    error!("Failed to shut down cleanly: {}", e);
                 }
+                std::process::exit(0);
+            } else if let Err(e) = server.announce(line) {
+                error!("Failed to send announcement: {}", e);
+            }
+        }
+    });
+}
+
+/// Advertises the server on the LAN so that clients can find it with `--discover`.
+fn spawn_beacon(server: Server, addr: SocketAddr) {
+    thread::spawn(move || {
+        let socket = match beacon_socket() {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("Clients won't be able to discover this server: {}", e);
+                return;
+            }
+        };
+        loop {
+            if let Err(e) = send_beacon(&socket, &Beacon::new(addr, server.rooms())) {
+                debug!("Failed to broadcast beacon: {}", e);
             }
+            thread::sleep(BEACON_INTERVAL);
+        }
+    });
+}
+
+/// Logs a summary of the traffic relayed by the server every `interval`.
+fn spawn_metrics(server: Server, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let clients = server
+            .clients
+            .lock()
+            .map(|clients| clients.len())
+            .unwrap_or_default();
+        info!(target: "metrics", "{}", server.metrics.take().summary(interval, clients));
+    });
+}
+
+/// Prints how to use the server and starts the threads running next to the one accepting clients.
+///
+/// Announcements are only read from the terminal with `console`, as it may be used to chat instead, and only
+/// when stdin is a terminal.
+fn start(server: &Server, join_command: String, console: bool) {
+    println!(
+        "To join the chat, use the following command: {}",
+        join_command
+    );
+    println!(
+        "Running program version {}, Created by {}",
+        env!("CARGO_PKG_VERSION"),
+        env!("CARGO_PKG_AUTHORS")
+    );
+    // Run as a service, stdin is closed or not ours to read
+    if console && io::stdin().is_terminal() {
+        println!("Type a line to announce it to everyone, or /shutdown to stop the server");
+        spawn_console(server.clone());
+    } else if console {
+        info!("Not reading announcements, stdin is not a terminal");
+    }
+    if let Some(interval) = server.config.metrics_interval {
+        spawn_metrics(server.clone(), interval);
+    }
+}
+
+/// Registers a newly connected client and serves it on its own thread, reading from `reader` and writing to `writer`.
+fn accept_client<S: Read + ClientConn + 'static>(
+    server: &Server,
+    mut reader: S,
+    writer: S,
+    client_addr: String,
+) {
+    let server = server.clone();
+    server
+        .add_client(
+            Box::new(writer),
+            client_addr.clone(),
+            UNNAMED_PSEUDONYM.to_string(),
+            DEFAULT_ROOM.to_string(),
+        )
+        .unwrap();
+    let hello = MessageType::Hello {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        protocol: PROTOCOL_VERSION,
+    };
+    if let Err(e) = server.send_to(&client_addr, &hello) {
+        error!("Failed to greet {}: {}", client_addr, e);
+    }
+    thread::spawn(move || server.serve_client(&mut reader, &client_addr));
+}
+
+// TODO when server is SIGTERM kick all clients and close
+/// Runs the server. The server listens for incoming connections and spawns a new thread for each one.
+pub fn run_server(server_ip: &str, config: ServerConfig) -> Result<(), ChatError> {
+    let listener = bind(server_ip, config.port)?;
+    serve(listener, config, true)
+}
+
+/// Starts listening on the address, or explains why it can't in a `ChatError::Bind`.
+pub fn bind(server_ip: &str, port: u16) -> Result<TcpListener, ChatError> {
+    TcpListener::bind((server_ip, port)).map_err(|error| ChatError::Bind {
+        message: bind_error(&error, server_ip, port),
+        error,
+    })
+}
+
+/// What to tell the operator when the server can't listen on the address, with what to do about it.
+fn bind_error(e: &io::Error, server_ip: &str, port: u16) -> String {
+    match e.kind() {
+        io::ErrorKind::AddrInUse => format!(
+            "Port {} is already in use, maybe by another lan-chat server. Stop the other server, or use --port 0 for a random port",
+            port
+        ),
+        io::ErrorKind::AddrNotAvailable => format!(
+            "{} is not an address of this machine. Use --bind <ip> to listen on another one",
+            server_ip
+        ),
+        io::ErrorKind::PermissionDenied => format!(
+            "Not allowed to listen on port {}, ports below 1024 usually need root. Use --port 0 for a random port",
+            port
+        ),
+        _ => format!("Failed to listen on {}:{}: {}", server_ip, port, e),
+    }
+}
+
+/// Runs the server on a listener bound beforehand, e.g. to know its address before it starts serving.
+///
+/// Announcements are only read from the terminal with `console`.
+pub fn serve(listener: TcpListener, config: ServerConfig, console: bool) -> Result<(), ChatError> {
+    let server = Server::new(config);
+
+    println!("Server listening on {}", listener.local_addr()?);
+    start(
+        &server,
+        format!("lan-chat -s {}", listener.local_addr()?),
+        console,
+    );
+    spawn_beacon(server.clone(), listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        tune_tcp(&stream);
+        let client_addr = stream.peer_addr()?.to_string();
+        accept_client(&server, stream.try_clone()?, stream, client_addr);
+    }
+
+    Ok(())
+}
+
+/// Runs the server on a Unix domain socket at the given path instead of on the network.
+///
+/// A socket file left behind by a server that is gone is replaced. Clients have no address on a Unix socket,
+/// so they are told apart as `unix:1`, `unix:2` and so on, in the order they connect.
+#[cfg(unix)]
+pub fn run_unix_server(path: &Path, config: ServerConfig) -> Result<(), ChatError> {
+    let server = Server::new(config);
+
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            let message = format!("A server is already listening on {}", path.display());
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, message).into());
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    println!("Server listening on {}", path.display());
+    start(
+        &server,
+        format!("lan-chat --socket {}", path.display()),
+        true,
+    );
+
+    for (index, stream) in listener.incoming().enumerate() {
+        let stream = stream?;
+        accept_client(
+            &server,
+            stream.try_clone()?,
+            stream,
+            format!("unix:{}", index + 1),
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns the IP part of a client address, or `unix` for clients on a Unix socket, which are all on this machine.
+fn ip_of(addr: &str) -> String {
+    addr.parse::<SocketAddr>()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|_| addr.split(':').next().unwrap_or(addr).to_string())
+}
+
+/// Whether spectators may send a message, which is only when it changes nothing the rest of the room sees
+/// besides their coming and going.
+fn spectator_may_send(message: &MessageType) -> bool {
+    matches!(
+        message,
+        MessageType::Spectate
+            | MessageType::Resume { .. }
+            | MessageType::Pseudonym(_)
+            | MessageType::JoinRoom(_)
+            | MessageType::ListUsers
+            | MessageType::FileResend { .. }
+            | MessageType::Command(_)
+            | MessageType::Leave(_)
+    )
+}
+
+/// Sends a message to each of the clients, returning the addresses of those that couldn't be sent to.
+///
+/// The clients are only collected, removing them needs the list the clients come from.
+fn send_each<'a>(
+    clients: impl Iterator<Item = &'a mut Client>,
+    message: &MessageType,
+) -> Vec<String> {
+    let mut dead_clients = vec![];
+    for (client, addr, _, _) in clients {
+        if let Err(e) = client.send(message) {
+            error!("Failed to send message to {}: {}", addr, e);
+            dead_clients.push(addr.clone());
         }
     }
-    Err(io::Error::new(
-        io::ErrorKind::Other,
-        "Failed to retrieve local IPv4 address.",
+    dead_clients
+}
+
+/// Returns the pseudonym of the client with the given address.
+fn pseudonym_of<'a>(clients: &'a [Client], addr: &str) -> &'a str {
+    clients
+        .iter()
+        .find(|(_, a, _, _)| a == addr)
+        .map_or(UNNAMED_PSEUDONYM, |(_, _, p, _)| p)
+}
+
+/// Logs a message relayed to a room as `key=value` pairs, so that the logs can be grepped.
+///
+/// File chunks are only logged at the debug level as there are many of them for every file.
+fn log_broadcast(addr: &str, pseudonym: &str, room: &str, message: &MessageType) {
+    let kind = match message {
+        MessageType::Message(..) => "message",
+        MessageType::Presence { .. } => "presence",
+        MessageType::Status { .. } => "status",
+        MessageType::File(_, _, _) => "file",
+        MessageType::FileChunk { .. } => "file_chunk",
+        MessageType::Image(_, _) => "image",
+        MessageType::Code { .. } => "code",
+        _ => "other",
+    };
+    let level = match message {
+        MessageType::FileChunk { .. } => Level::Debug,
+        _ => Level::Info,
+    };
+    let bytes = bincode::serialized_size(message).unwrap_or_default();
+
+    log!(
+        target: "broadcast",
+        level,
+        "addr={} nick={:?} room={:?} kind={} bytes={}",
+        addr,
+        pseudonym,
+        room,
+        kind,
+        bytes
+    );
+}
+
+/// Builds the error sent back to a client whose file is over the size limit.
+fn file_too_large(file_name: &str, max_file_size: u64) -> MessageType {
+    MessageType::Error(format!(
+        "File {} was not sent, this server only accepts files up to {}",
+        file_name,
+        format_size(max_file_size as usize)
+    ))
+}
+
+/// Tells the sender of a file that this server doesn't relay files.
+fn files_disabled(file_name: &str) -> MessageType {
+    MessageType::Error(format!(
+        "File {} was not sent, file transfer is disabled on this server",
+        file_name
     ))
 }
 
+/// Returns the local IPv4 address of the machine most likely to be reachable on the LAN, printing the others.
+pub fn get_local_ipv4() -> io::Result<String> {
+    let candidates = local_ipv4_candidates()?;
+    let Some((_, best)) = candidates.first() else {
+        return Err(io::Error::other("Failed to retrieve local IPv4 address."));
+    };
+
+    if candidates.len() > 1 {
+        let others: Vec<String> = candidates[1..]
+            .iter()
+            .map(|(interface, ip)| format!("{} ({})", ip, interface))
+            .collect();
+        println!(
+            "Picked {} out of several addresses, the others are: {}. Use --bind to pick another one",
+            best,
+            others.join(", ")
+        );
+    }
+
+    Ok(best.to_string())
+}
+
+/// Every IPv4 address of this machine other peers could reach, along with its interface name.
+///
+/// The addresses are sorted so that the one most likely to be on the LAN comes first.
+pub fn local_ipv4_candidates() -> io::Result<Vec<(String, Ipv4Addr)>> {
+    let mut candidates: Vec<(String, Ipv4Addr)> = get_if_addrs()?
+        .into_iter()
+        .filter(|interface| !interface.is_loopback() && !interface.addr.is_link_local())
+        .filter_map(|interface| match interface.addr {
+            if_addrs::IfAddr::V4(addr) => Some((interface.name, addr.ip)),
+            if_addrs::IfAddr::V6(_) => None,
+        })
+        .collect();
+    candidates.sort_by_key(|(interface, ip)| lan_rank(interface, *ip));
+    Ok(candidates)
+}
+
+/// How unlikely an address is to be reachable by peers on the LAN, lower is better.
+///
+/// Home and office networks mostly use 192.168/16 and 10/8, while 172.16/12 is where Docker and
+/// other virtual networks live by default. Interfaces of known virtual networks come last.
+fn lan_rank(interface: &str, ip: Ipv4Addr) -> u8 {
+    const VIRTUAL_INTERFACES: [&str; 11] = [
+        "docker",
+        "br-",
+        "veth",
+        "virbr",
+        "vmnet",
+        "vboxnet",
+        "tun",
+        "tap",
+        "wg",
+        "zt",
+        "tailscale",
+    ];
+    if VIRTUAL_INTERFACES
+        .iter()
+        .any(|prefix| interface.starts_with(prefix))
+    {
+        return 4;
+    }
+
+    match ip.octets() {
+        [192, 168, _, _] => 0,
+        [10, _, _, _] => 1,
+        [172, second, _, _] if (16..32).contains(&second) => 3,
+        _ => 2,
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
     use super::*;
 
+    /// An in-memory connection, whose reads return what the server wrote to it.
+    #[derive(Clone, Default)]
+    struct Pipe {
+        buffer: Arc<Mutex<VecDeque<u8>>>,
+        closed: Arc<AtomicBool>,
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.buffer.lock().unwrap().extend(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.buffer.lock().unwrap().read(buf)
+        }
+    }
+
+    impl ClientConn for Pipe {
+        fn close(&self) -> io::Result<()> {
+            self.closed.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    impl Pipe {
+        /// Returns every message written to the pipe since the last call.
+        fn received(&mut self) -> Vec<MessageType> {
+            std::iter::from_fn(|| receive_message(self).ok()).collect()
+        }
+    }
+
+    /// A connection that takes a few bytes and then fails, like a connection reset mid-message.
+    struct Breaking(Pipe);
+
+    impl Write for Breaking {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.0.buffer.lock().unwrap().is_empty() {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            self.0.write(&buf[..buf.len().min(5)])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ClientConn for Breaking {
+        fn close(&self) -> io::Result<()> {
+            self.0.close()
+        }
+    }
+
+    /// Encodes messages the way a client sends them.
+    fn frames(messages: &[MessageType]) -> Vec<u8> {
+        let mut bytes = vec![];
+        for message in messages {
+            send_message(&mut bytes, message).unwrap();
+        }
+        bytes
+    }
+
+    /// The end of what a client sends, which runs a check while the server still has the client.
+    struct Checkpoint<F: FnMut()>(F);
+
+    impl<F: FnMut()> Read for Checkpoint<F> {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            (self.0)();
+            Ok(0)
+        }
+    }
+
+    /// Adds a client with the given pseudonym to a room, returning its connection and address.
+    fn add_pipe(server: &Server, pseudonym: &str, room: &str) -> (Pipe, String) {
+        let pipe = Pipe::default();
+        let addr = format!("10.0.0.{}:4000", server.clients.lock().unwrap().len() + 1);
+        server
+            .add_client(
+                Box::new(pipe.clone()),
+                addr.clone(),
+                pseudonym.to_string(),
+                room.to_string(),
+            )
+            .unwrap();
+        (pipe, addr)
+    }
+
+    /// Adds a client for each pseudonym to the default room, returning their connections and addresses.
+    fn connect_clients(server: &Server, pseudonyms: &[&str]) -> Vec<(Pipe, String)> {
+        pseudonyms
+            .iter()
+            .map(|pseudonym| add_pipe(server, pseudonym, DEFAULT_ROOM))
+            .collect()
+    }
+
+    #[test]
+    fn test_same_pseudonym_at_once_is_given_once() {
+        let server = Server::new(ServerConfig::default());
+        let clients = connect_clients(&server, &[UNNAMED_PSEUDONYM, UNNAMED_PSEUDONYM]);
+        let barrier = Arc::new(std::sync::Barrier::new(clients.len()));
+
+        let threads: Vec<_> = clients
+            .iter()
+            .map(|(_, addr)| {
+                let (server, addr, barrier) = (server.clone(), addr.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    server.register(&addr, "bob").unwrap().0
+                })
+            })
+            .collect();
+        let mut given: Vec<String> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        given.sort();
+        assert_eq!(given, vec!["bob", "bob2"]);
+
+        // Latecomers are told which pseudonym they got instead
+        let (mut carol, addr) = add_pipe(&server, UNNAMED_PSEUDONYM, DEFAULT_ROOM);
+        server.serve_client(
+            &mut frames(&[MessageType::Pseudonym("Bob".to_string())]).as_slice(),
+            &addr,
+        );
+        assert_eq!(
+            carol.received()[0],
+            MessageType::Info("Bob is taken, you are now Bob3".to_string())
+        );
+    }
+
+    #[test]
+    fn test_client_alone_in_room_is_told_once() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice"]);
+        let alice = clients[0].1.clone();
+
+        server.note_if_alone(&alice, DEFAULT_ROOM).unwrap();
+        server.note_if_alone(&alice, DEFAULT_ROOM).unwrap();
+        assert!(matches!(
+            receive_message(&mut clients[0].0).unwrap(),
+            MessageType::Notice(NoticeKind::Server, _)
+        ));
+        assert!(server.alone.lock().unwrap().contains(&alice));
+
+        // Once someone else has been around, being alone again is worth telling
+        clients.extend(connect_clients(&server, &["bob"]));
+        server.note_if_alone(&alice, DEFAULT_ROOM).unwrap();
+        assert!(!server.alone.lock().unwrap().contains(&alice));
+    }
+
+    #[test]
+    fn test_long_pseudonyms_are_shortened_by_characters() {
+        let server = Server::new(ServerConfig {
+            max_name_length: 4,
+            ..Default::default()
+        });
+        assert_eq!(server.shorten_pseudonym("🦀🦀🦀🦀"), None);
+        assert_eq!(server.shorten_pseudonym("Zoë"), None);
+        assert_eq!(
+            server.shorten_pseudonym("Ferris🦀"),
+            Some("Ferr".to_string())
+        );
+
+        let server = Server::new(ServerConfig {
+            max_name_length: 1000,
+            ..Default::default()
+        });
+        assert_eq!(
+            server.shorten_pseudonym(&"a".repeat(40)),
+            Some("a".repeat(NAME_LENGTH_CEILING))
+        );
+    }
+
+    #[test]
+    fn test_disabled_transfers_are_refused_and_not_relayed() {
+        let server = Server::new(ServerConfig {
+            allow_files: false,
+            allow_images: false,
+            ..Default::default()
+        });
+        let mut clients = connect_clients(&server, &["alice", "bob"]);
+        let alice = clients[0].1.clone();
+
+        let chunk = |seq| MessageType::FileChunk {
+            transfer_id: 7,
+            file_name: "big.bin".to_string(),
+            seq,
+            total: 2,
+            data: vec![0; 10],
+            checksum: None,
+        };
+        let sent = frames(&[
+            MessageType::File("notes.txt".to_string(), b"notes".to_vec(), String::new()),
+            chunk(0),
+            chunk(1),
+            MessageType::Image("cat.png".to_string(), vec![1, 2, 3]),
+        ]);
+        server.serve_client(&mut sent.as_slice(), &alice);
+
+        let refusals: Vec<_> = clients[0]
+            .0
+            .received()
+            .into_iter()
+            .filter_map(|message| match message {
+                MessageType::Error(error) => Some(error),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            refusals,
+            vec![
+                "File notes.txt was not sent, file transfer is disabled on this server",
+                "File big.bin was not sent, file transfer is disabled on this server",
+                "Image cat.png was not sent, image sharing is disabled on this server",
+            ]
+        );
+        assert!(clients[1].0.received().iter().all(|message| !matches!(
+            message,
+            MessageType::File(..) | MessageType::FileChunk { .. } | MessageType::Image(..)
+        )));
+    }
+
+    #[test]
+    fn test_spectators_can_only_watch() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice", "bob"]);
+        let alice = clients[0].1.clone();
+
+        let sent = frames(&[
+            MessageType::Spectate,
+            MessageType::Message(String::new(), "hi".to_string(), 1, vec![]),
+            MessageType::Status {
+                nick: String::new(),
+                text: "watching".to_string(),
+            },
+            MessageType::Presence {
+                nick: String::new(),
+                state: PresenceState::Away(None),
+            },
+            MessageType::ListUsers,
+        ]);
+        server.serve_client(&mut sent.as_slice(), &alice);
+
+        let received = clients[0].0.received();
+        let refusal = MessageType::Error("Spectators can't send messages".to_string());
+        assert_eq!(
+            received
+                .iter()
+                .filter(|message| **message == refusal)
+                .count(),
+            3
+        );
+        assert!(!received
+            .iter()
+            .any(|message| matches!(message, MessageType::Ack { .. })));
+        let Some(MessageType::UserList(users)) = received
+            .iter()
+            .find(|message| matches!(message, MessageType::UserList(_)))
+        else {
+            panic!("no user list in {:?}", received);
+        };
+        let spectating: Vec<(&str, bool)> = users
+            .iter()
+            .map(|(nick, _, _, _, spectator)| (nick.as_str(), *spectator))
+            .collect();
+        assert_eq!(spectating, vec![("alice", true), ("bob", false)]);
+        assert!(clients[1].0.received().iter().all(|message| !matches!(
+            message,
+            MessageType::Message(..) | MessageType::Status { .. } | MessageType::Presence { .. }
+        )));
+    }
+
+    #[test]
+    fn test_spectators_can_not_vote() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice", "bob"]);
+        server
+            .start_poll(
+                &clients[1].1,
+                "Lunch?".to_string(),
+                vec!["Yes".to_string(), "No".to_string()],
+            )
+            .unwrap();
+        clients[1].0.received();
+
+        let sent = frames(&[
+            MessageType::Spectate,
+            MessageType::Vote { id: 1, option: 0 },
+        ]);
+        server.serve_client(&mut sent.as_slice(), &clients[0].1);
+
+        assert!(!clients[1]
+            .0
+            .received()
+            .iter()
+            .any(|message| matches!(message, MessageType::PollUpdate { .. })));
+    }
+
+    #[test]
+    fn test_cancelled_transfers_stop_being_relayed() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice", "bob"]);
+        let alice = clients[0].1.clone();
+
+        let chunk = |seq| MessageType::FileChunk {
+            transfer_id: 7,
+            file_name: "big.bin".to_string(),
+            seq,
+            total: 3,
+            data: vec![0; 10],
+            checksum: None,
+        };
+        let cancel = MessageType::FileCancel { transfer_id: 7 };
+        let sent = frames(&[chunk(0), cancel.clone(), chunk(1), cancel.clone()]);
+        server.serve_client(&mut sent.as_slice(), &alice);
+
+        let relayed: Vec<_> = clients[1]
+            .0
+            .received()
+            .into_iter()
+            .filter(|message| {
+                matches!(
+                    message,
+                    MessageType::FileChunk { .. } | MessageType::FileCancel { .. }
+                )
+            })
+            .collect();
+        assert_eq!(relayed, vec![chunk(0), cancel]);
+    }
+
+    #[test]
+    fn test_transfers_are_kept_by_sender() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice", "bob", "mallory", "carol"]);
+        let (alice, bob, mallory) = (
+            clients[0].1.clone(),
+            clients[1].1.clone(),
+            clients[2].1.clone(),
+        );
+        let chunk = |seq| MessageType::FileChunk {
+            transfer_id: 7,
+            file_name: "big.bin".to_string(),
+            seq,
+            total: 2,
+            data: vec![0; 10],
+            checksum: None,
+        };
+        let resend = MessageType::FileResend {
+            transfer_id: 7,
+            missing: vec![0],
+        };
+        // alice is halfway through sending a file
+        server
+            .transfers
+            .lock()
+            .unwrap()
+            .insert((alice.clone(), 7), RelayedTransfer::default());
+
+        // Someone else can't call it off by its id, nor answer for it
+        let sent = frames(&[MessageType::FileCancel { transfer_id: 7 }, chunk(0)]);
+        server.serve_client(&mut sent.as_slice(), &mallory);
+        assert!(!server.transfers.lock().unwrap()[&(alice.clone(), 7)].rejected);
+        assert!(!clients[3]
+            .0
+            .received()
+            .iter()
+            .any(|message| matches!(message, MessageType::FileCancel { .. })));
+        let sent = frames(std::slice::from_ref(&resend));
+        server.serve_client(&mut sent.as_slice(), &bob);
+        assert!(clients[0].0.received().contains(&resend));
+
+        // Once the last chunk is relayed the transfer is only kept for resend requests
+        let sent = frames(&[chunk(1)]);
+        let mut reader = sent.as_slice().chain(Checkpoint(|| {
+            assert!(!server
+                .transfers
+                .lock()
+                .unwrap()
+                .contains_key(&(alice.clone(), 7)));
+            let sent = frames(std::slice::from_ref(&resend));
+            server.serve_client(&mut sent.as_slice(), &clients[3].1);
+            assert!(clients[0].0.received().contains(&resend));
+        }));
+        server.serve_client(&mut reader, &alice);
+        assert!(server.finished_transfers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_messages_reach_only_the_senders_room() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice", "bob"]);
+        let (mut carol, _) = add_pipe(&server, "carol", "games");
+        let alice = clients[0].1.clone();
+
+        let sent = frames(&[
+            MessageType::Message(String::new(), "\u{200B}\t".to_string(), 2, vec![]),
+            MessageType::Message(String::new(), "hi".to_string(), 1, vec![]),
+            MessageType::File("notes.txt".to_string(), b"notes".to_vec(), String::new()),
+        ]);
+        server.serve_client(&mut sent.as_slice(), &alice);
+
+        let presence = MessageType::Presence {
+            nick: "alice".to_string(),
+            state: PresenceState::Active,
+        };
+        let message = MessageType::Message("alice".to_string(), "hi".to_string(), 1, vec![]);
+        assert_eq!(
+            clients[1].0.received(),
+            vec![
+                presence.clone(),
+                message,
+                MessageType::File("notes.txt".to_string(), b"notes".to_vec(), String::new()),
+                MessageType::Leave("alice".to_string()),
+                MessageType::UserLeft("alice".to_string()),
+            ]
+        );
+        // The sender only gets the Ack of its message, and nothing for its file
+        assert_eq!(
+            clients[0].0.received(),
+            vec![
+                presence,
+                MessageType::Ack {
+                    nonce: 1,
+                    recipients: 1
+                }
+            ]
+        );
+        assert!(clients[0].0.closed.load(Ordering::SeqCst));
+        assert_eq!(carol.received(), vec![]);
+    }
+
+    #[test]
+    fn test_code_blocks_are_handled_like_messages() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice", "bob"]);
+        let alice = clients[0].1.clone();
+
+        let code = |sender: &str, nonce| MessageType::Code {
+            sender: sender.to_string(),
+            lang: "sh".to_string(),
+            body: "ls".to_string(),
+            nonce,
+            signature: vec![],
+        };
+        // The third time in a row is spam, whatever it is wrapped in
+        let sent = frames(&[code("", 1), code("", 2), code("", 3)]);
+        server.serve_client(&mut sent.as_slice(), &alice);
+
+        let relayed: Vec<_> = clients[1]
+            .0
+            .received()
+            .into_iter()
+            .filter(|message| matches!(message, MessageType::Code { .. }))
+            .collect();
+        assert_eq!(relayed, vec![code("alice", 1), code("alice", 2)]);
+        let received = clients[0].0.received();
+        assert!(received.contains(&MessageType::Ack {
+            nonce: 2,
+            recipients: 1
+        }));
+        assert!(!received
+            .iter()
+            .any(|message| matches!(message, MessageType::Ack { nonce: 3, .. })));
+        assert!(received.iter().any(
+            |message| matches!(message, MessageType::Error(error) if error.contains("repeating"))
+        ));
+        assert_eq!(server.history.lock().unwrap()[DEFAULT_ROOM].len(), 2);
+    }
+
+    #[test]
+    fn test_poll_votes_are_tallied_per_pseudonym() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice", "bob"]);
+        let (alice, bob) = (clients[0].1.clone(), clients[1].1.clone());
+        let options = vec!["pizza".to_string(), "tacos".to_string()];
+
+        server
+            .start_poll(&alice, "Lunch?".to_string(), options.clone())
+            .unwrap();
+        server.vote(&alice, 1, 0).unwrap();
+        server.vote(&bob, 1, 0).unwrap();
+        server.vote(&alice, 1, 1).unwrap();
+        server.vote(&bob, 2, 0).unwrap();
+
+        assert_eq!(
+            clients[1].0.received(),
+            vec![
+                MessageType::Poll {
+                    id: 1,
+                    question: "Lunch?".to_string(),
+                    options
+                },
+                MessageType::PollUpdate {
+                    id: 1,
+                    counts: vec![1, 0]
+                },
+                MessageType::PollUpdate {
+                    id: 1,
+                    counts: vec![2, 0]
+                },
+                MessageType::PollUpdate {
+                    id: 1,
+                    counts: vec![1, 1]
+                },
+                MessageType::Error("There is no poll #2 in this room".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_statuses_are_listed_and_announced() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice", "bob"]);
+        let (alice, bob) = (clients[0].1.clone(), clients[1].1.clone());
+
+        server.set_status(&alice, " reviewing\nPRs ").unwrap();
+        server.set_status(&bob, "lunch").unwrap();
+        server.set_status(&bob, "").unwrap();
+
+        let statuses: Vec<(String, String)> = server
+            .user_list(DEFAULT_ROOM)
+            .unwrap()
+            .into_iter()
+            .map(|(nick, _, _, status, _)| (nick, status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("alice".to_string(), "reviewing PRs".to_string()),
+                ("bob".to_string(), String::new()),
+            ]
+        );
+        assert_eq!(
+            clients[0].0.received()[0],
+            MessageType::Status {
+                nick: "alice".to_string(),
+                text: "reviewing PRs".to_string()
+            }
+        );
+    }
+
     #[test]
     fn test_is_local_ip_ok() {
         assert!(get_local_ipv4().is_ok());
     }
+
+    #[test]
+    fn test_lan_addresses_are_preferred() {
+        let docker = lan_rank("docker0", Ipv4Addr::new(172, 17, 0, 1));
+        let bridge = lan_rank("eth1", Ipv4Addr::new(172, 20, 0, 5));
+        let office = lan_rank("eth0", Ipv4Addr::new(10, 0, 3, 14));
+        let home = lan_rank("wlan0", Ipv4Addr::new(192, 168, 1, 20));
+        let vpn = lan_rank("wg0", Ipv4Addr::new(10, 8, 0, 2));
+
+        assert!(home < office);
+        assert!(office < bridge);
+        assert!(bridge < docker);
+        assert!(office < vpn);
+    }
+
+    #[test]
+    fn test_join_announcements_are_debounced() {
+        let server = Server::new(ServerConfig::default());
+
+        assert!(!server
+            .should_announce_join("10.0.0.1:4000", UNNAMED_PSEUDONYM)
+            .unwrap());
+        assert!(server
+            .should_announce_join("10.0.0.1:4000", "alice")
+            .unwrap());
+        // Reconnecting gets a new port but comes from the same IP
+        assert!(!server
+            .should_announce_join("10.0.0.1:4001", "alice")
+            .unwrap());
+        assert!(server
+            .should_announce_join("10.0.0.2:4000", "alice")
+            .unwrap());
+    }
+
+    #[test]
+    fn test_rejoin_after_leave_is_announced() {
+        let server = Server::new(ServerConfig::default());
+        let (mut bob, _) = add_pipe(&server, "bob", DEFAULT_ROOM);
+
+        // alice's connection drops and comes right back from the same machine
+        for addr in ["10.0.0.9:4000", "10.0.0.9:4001"] {
+            server
+                .add_client(
+                    Box::new(Pipe::default()),
+                    addr.to_string(),
+                    UNNAMED_PSEUDONYM.to_string(),
+                    DEFAULT_ROOM.to_string(),
+                )
+                .unwrap();
+            let sent = frames(&[MessageType::Pseudonym("alice".to_string())]);
+            server.serve_client(&mut sent.as_slice(), addr);
+        }
+
+        let notices: Vec<_> = bob
+            .received()
+            .into_iter()
+            .filter_map(|message| match message {
+                MessageType::Info(info) if info.starts_with("alice has entered") => {
+                    Some("entered".to_string())
+                }
+                MessageType::Leave(pseudonym) => Some(pseudonym),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notices, ["entered", "alice", "entered", "alice"]);
+    }
+
+    #[test]
+    fn test_leave_carries_pseudonym() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice", "bob"]);
+
+        // alice quits
+        server.remove_client(&clients[0].1).unwrap();
+
+        assert_eq!(
+            receive_message(&mut clients[1].0).unwrap(),
+            MessageType::Leave("alice".to_string())
+        );
+    }
+
+    #[test]
+    fn test_broadcast_stamps_registered_pseudonym() {
+        let server = Server::new(ServerConfig::default());
+        let mut receivers = connect_clients(&server, &["mallory", "alice"]);
+
+        let spoofed = MessageType::Message("admin".to_string(), "hi".to_string(), 7, vec![]);
+        server
+            .broadcast(
+                &spoofed,
+                DEFAULT_ROOM,
+                &receivers[0].1,
+                Some(&receivers[0].1),
+            )
+            .unwrap();
+
+        assert_eq!(
+            receive_message(&mut receivers[1].0).unwrap(),
+            MessageType::Message("mallory".to_string(), "hi".to_string(), 7, vec![])
+        );
+        assert!(receivers[0].0.received().is_empty());
+    }
+
+    #[test]
+    fn test_client_is_closed_after_a_broken_write() {
+        let pipe = Pipe::default();
+        let mut client = Breaking(pipe.clone());
+        let error = client
+            .send(&MessageType::Info("hello".to_string()))
+            .unwrap_err();
+        assert!(matches!(error, ChatError::PartialWrite { written: 5, .. }));
+        assert!(pipe.closed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_broken_recipient_does_not_stop_the_broadcast() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice"]);
+        let broken = Pipe::default();
+        let broken_addr = "10.0.0.9:4000".to_string();
+        server
+            .add_client(
+                Box::new(Breaking(broken.clone())),
+                broken_addr.clone(),
+                "bob".to_string(),
+                DEFAULT_ROOM.to_string(),
+            )
+            .unwrap();
+        let (mut carol, _) = add_pipe(&server, "carol", DEFAULT_ROOM);
+
+        let message = MessageType::Message("alice".to_string(), "hi".to_string(), 7, vec![]);
+        let sent = frames(std::slice::from_ref(&message));
+        server.serve_client(&mut sent.as_slice(), &clients[0].1);
+
+        assert!(broken.closed.load(Ordering::SeqCst));
+        assert!(server.room_of(&broken_addr).is_none());
+        assert!(carol.received().contains(&message));
+        assert!(clients[0].0.received().contains(&MessageType::Ack {
+            nonce: 7,
+            recipients: 1
+        }));
+    }
+
+    #[test]
+    fn test_resumed_session_replaces_the_stale_connection() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice", "bob"]);
+        let (stale, stale_addr) = (clients[0].0.clone(), clients[0].1.clone());
+        server.set_status(&stale_addr, "brb").unwrap();
+        let token = server.session_token(&stale_addr).unwrap();
+        clients[1].0.received();
+
+        // The old connection is still registered when alice comes back on a new one
+        let (mut fresh, fresh_addr) = add_pipe(&server, UNNAMED_PSEUDONYM, DEFAULT_ROOM);
+        let sent = frames(&[
+            MessageType::Resume { token },
+            MessageType::Pseudonym("alice".to_string()),
+        ]);
+        server.serve_client(&mut sent.as_slice(), &fresh_addr);
+
+        assert!(stale.closed.load(Ordering::SeqCst));
+        let received = fresh.received();
+        assert!(received.contains(&MessageType::Resume { token }));
+        assert!(!received
+            .iter()
+            .any(|message| matches!(message, MessageType::Info(info) if info.contains("taken"))));
+        // bob only hears about alice leaving once the new connection ends
+        assert_eq!(
+            clients[1]
+                .0
+                .received()
+                .iter()
+                .filter(|message| **message == MessageType::Leave("alice".to_string()))
+                .count(),
+            1
+        );
+
+        // Tokens of clients that left can't be used
+        let (_, other_addr) = add_pipe(&server, UNNAMED_PSEUDONYM, DEFAULT_ROOM);
+        server.resume(&other_addr, token).unwrap();
+        assert!(server.sessions.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_roster_is_sent_and_kept_up_to_date() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice"]);
+        let (mut games, _) = add_pipe(&server, "carol", "games");
+        let (mut bob, bob_addr) = add_pipe(&server, UNNAMED_PSEUDONYM, DEFAULT_ROOM);
+
+        let sent = frames(&[
+            MessageType::Pseudonym("bob".to_string()),
+            MessageType::JoinRoom("games".to_string()),
+        ]);
+        server.serve_client(&mut sent.as_slice(), &bob_addr);
+
+        let names = |message: &MessageType| match message {
+            MessageType::UserList(users) => users.iter().map(|user| user.0.clone()).collect(),
+            _ => vec![],
+        };
+        let rosters: Vec<Vec<String>> = bob
+            .received()
+            .iter()
+            .filter(|message| matches!(message, MessageType::UserList(_)))
+            .map(names)
+            .collect();
+        assert_eq!(rosters, vec![vec!["alice", "bob"], vec!["carol", "bob"]]);
+
+        let roster_events = |received: Vec<MessageType>| -> Vec<MessageType> {
+            received
+                .into_iter()
+                .filter(|message| {
+                    matches!(
+                        message,
+                        MessageType::UserJoined(_) | MessageType::UserLeft(_)
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(
+            roster_events(clients[0].0.received()),
+            vec![
+                MessageType::UserJoined("bob".to_string()),
+                MessageType::UserLeft("bob".to_string()),
+            ]
+        );
+        assert_eq!(
+            roster_events(games.received()),
+            vec![
+                MessageType::UserJoined("bob".to_string()),
+                MessageType::UserLeft("bob".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_signing_keys_are_passed_on() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice"]);
+        server.set_signing_key(&clients[0].1, vec![1; 32]).unwrap();
+        assert!(!clients[0]
+            .0
+            .received()
+            .iter()
+            .any(|message| matches!(message, MessageType::SigningKey { .. })));
+
+        let (mut bob, bob_addr) = add_pipe(&server, UNNAMED_PSEUDONYM, DEFAULT_ROOM);
+        let sent = frames(&[MessageType::Pseudonym("bob".to_string())]);
+        server.serve_client(&mut sent.as_slice(), &bob_addr);
+
+        let keys: Vec<MessageType> = bob
+            .received()
+            .into_iter()
+            .filter(|message| matches!(message, MessageType::SigningKey { .. }))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![MessageType::SigningKey {
+                nick: "alice".to_string(),
+                key: vec![1; 32],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_salts_are_passed_on_once() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice", "bob"]);
+        let salt = |nick: &str, byte| MessageType::Salt {
+            nick: nick.to_string(),
+            salt: vec![byte; 16],
+        };
+        let message =
+            |nick: &str| MessageType::Message(nick.to_string(), "hi".to_string(), 1, vec![]);
+
+        // Every new salt would cost the room a key derivation, so only the first one counts
+        let sent = frames(&[salt("", 1), salt("", 2), message("")]);
+        server.serve_client(&mut sent.as_slice(), &clients[0].1);
+        let salts: Vec<_> = clients[1]
+            .0
+            .received()
+            .into_iter()
+            .filter(|message| matches!(message, MessageType::Salt { .. }))
+            .collect();
+        assert_eq!(salts, vec![salt("alice", 1)]);
+
+        // alice has left by now, the salt still comes with what was said
+        let mut carol = Pipe::default();
+        server
+            .add_client(
+                Box::new(carol.clone()),
+                "10.0.0.9:4000".to_string(),
+                UNNAMED_PSEUDONYM.to_string(),
+                DEFAULT_ROOM.to_string(),
+            )
+            .unwrap();
+        let sent = frames(&[MessageType::Pseudonym("carol".to_string())]);
+        server.serve_client(&mut sent.as_slice(), "10.0.0.9:4000");
+        let received = carol.received();
+        let replayed = received
+            .iter()
+            .position(|received| {
+                *received
+                    == MessageType::Replayed {
+                        message: Box::new(message("alice")),
+                        key: vec![],
+                    }
+            })
+            .unwrap();
+        assert_eq!(received[replayed - 1], salt("alice", 1));
+    }
+
+    #[test]
+    fn test_replayed_messages_carry_the_signing_key() {
+        let server = Server::new(ServerConfig::default());
+        let (_, alice_addr) = add_pipe(&server, "alice", DEFAULT_ROOM);
+        let signed = MessageType::Message("alice".to_string(), "hi".to_string(), 1, vec![2; 64]);
+        let sent = frames(&[
+            MessageType::SigningKey {
+                nick: String::new(),
+                key: vec![1; 32],
+            },
+            signed.clone(),
+        ]);
+        // alice leaves once it is sent, taking the key along
+        server.serve_client(&mut sent.as_slice(), &alice_addr);
+        assert!(server.signing_keys.lock().unwrap().is_empty());
+
+        let (mut bob, bob_addr) = add_pipe(&server, UNNAMED_PSEUDONYM, DEFAULT_ROOM);
+        let sent = frames(&[MessageType::Pseudonym("bob".to_string())]);
+        server.serve_client(&mut sent.as_slice(), &bob_addr);
+        assert!(bob.received().contains(&MessageType::Replayed {
+            message: Box::new(signed),
+            key: vec![1; 32],
+        }));
+    }
+
+    #[test]
+    fn test_only_guests_can_join() {
+        let server = Server::new(ServerConfig {
+            allowed_nicks: Some(vec!["alice".to_string(), "bob".to_string()]),
+            ..Default::default()
+        });
+        let join = |pseudonym: &str| {
+            let (mut pipe, addr) = add_pipe(&server, UNNAMED_PSEUDONYM, DEFAULT_ROOM);
+            let sent = frames(&[MessageType::Pseudonym(pseudonym.to_string())]);
+            server.serve_client(&mut sent.as_slice(), &addr);
+            pipe.received()
+        };
+
+        assert_eq!(
+            join("mallory"),
+            vec![MessageType::Rejected("not on the guest list".to_string())]
+        );
+        assert!(join("Alice").contains(&MessageType::Welcome("Alice".to_string())));
+    }
+
+    #[test]
+    fn test_duplicate_connections_are_rejected_or_replaced() {
+        let duplicate = |duplicates| {
+            let server = Server::new(ServerConfig {
+                duplicates,
+                ..Default::default()
+            });
+            let clients = connect_clients(&server, &["alice", "bob"]);
+            let first = clients[0].0.clone();
+            // Another connection from alice's machine
+            let mut second = Pipe::default();
+            let second_addr = format!("{}:5000", ip_of(&clients[0].1));
+            server
+                .add_client(
+                    Box::new(second.clone()),
+                    second_addr.clone(),
+                    UNNAMED_PSEUDONYM.to_string(),
+                    DEFAULT_ROOM.to_string(),
+                )
+                .unwrap();
+            let sent = frames(&[MessageType::Pseudonym("Alice".to_string())]);
+            server.serve_client(&mut sent.as_slice(), &second_addr);
+            (first, second.received())
+        };
+
+        let (first, received) = duplicate(Duplicates::Reject);
+        assert!(!first.closed.load(Ordering::SeqCst));
+        assert_eq!(
+            received,
+            vec![MessageType::Rejected(
+                "Alice is already connected from this machine".to_string()
+            )]
+        );
+
+        let (first, received) = duplicate(Duplicates::Replace);
+        assert!(first.closed.load(Ordering::SeqCst));
+        assert!(received.contains(&MessageType::Welcome("Alice".to_string())));
+        assert!(!received
+            .iter()
+            .any(|message| matches!(message, MessageType::Info(info) if info.contains("taken"))));
+    }
+
+    #[test]
+    fn test_dead_client_does_not_stop_join_notice() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice", "bob", "carol"]);
+
+        // bob's connection went away without a Leave
+        server.clients.lock().unwrap()[1].0.close().unwrap();
+
+        let (mut dave, dave_addr) = add_pipe(&server, UNNAMED_PSEUDONYM, DEFAULT_ROOM);
+        let sent = frames(&[MessageType::Pseudonym("dave".to_string())]);
+        server.serve_client(&mut sent.as_slice(), &dave_addr);
+
+        // bob is dropped as soon as telling it about dave fails, before dave is announced to people
+        for client in [0, 2] {
+            let received = clients[client].0.received();
+            let position =
+                |wanted: &dyn Fn(&MessageType) -> bool| received.iter().position(wanted).unwrap();
+            let joined =
+                position(&|message| *message == MessageType::UserJoined("dave".to_string()));
+            let left = position(&|message| *message == MessageType::Leave("bob".to_string()));
+            let entered = position(
+                &|message| matches!(message, MessageType::Info(info) if info.starts_with("dave has entered")),
+            );
+            assert!(joined < left && left < entered);
+        }
+        assert!(dave
+            .received()
+            .contains(&MessageType::Leave("bob".to_string())));
+        assert!(server.room_of(&clients[1].1).is_none());
+    }
+
+    #[test]
+    fn test_dead_clients_are_dropped_when_relaying_files() {
+        let chunk = MessageType::FileChunk {
+            transfer_id: 1,
+            file_name: "notes.txt".to_string(),
+            seq: 0,
+            total: 2,
+            data: b"no".to_vec(),
+            checksum: None,
+        };
+        for message in [
+            MessageType::File("notes.txt".to_string(), b"notes".to_vec(), String::new()),
+            chunk,
+            MessageType::Image("cat.png".to_string(), vec![]),
+        ] {
+            let server = Server::new(ServerConfig::default());
+            let mut clients = connect_clients(&server, &["alice", "bob", "carol"]);
+
+            // bob's connection went away without a Leave
+            server.clients.lock().unwrap()[1].0.close().unwrap();
+            let sent = frames(std::slice::from_ref(&message));
+            server.serve_client(&mut sent.as_slice(), &clients[0].1);
+
+            let received = clients[2].0.received();
+            assert!(received.contains(&message));
+            assert!(received.contains(&MessageType::Leave("bob".to_string())));
+            assert!(server.room_of(&clients[1].1).is_none());
+        }
+    }
+
+    #[test]
+    fn test_dead_client_does_not_stop_room_change_notices() {
+        let server = Server::new(ServerConfig::default());
+        let mut clients = connect_clients(&server, &["alice", "bob", "carol"]);
+        let (dave, _) = add_pipe(&server, "dave", "games");
+        let (mut erin, _) = add_pipe(&server, "erin", "games");
+
+        // bob's connection went away without a Leave, as did dave's in the room alice is moving to
+        server.clients.lock().unwrap()[1].0.close().unwrap();
+        dave.close().unwrap();
+        server.join_room(&clients[0].1, "games").unwrap();
+
+        assert!(clients[2]
+            .0
+            .received()
+            .contains(&MessageType::UserLeft("alice".to_string())));
+        assert!(erin
+            .received()
+            .contains(&MessageType::UserJoined("alice".to_string())));
+        assert!(server.room_of(&clients[1].1).is_none());
+        assert_eq!(server.user_list("games").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_bind_errors_say_what_to_do() {
+        let first = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = first.local_addr().unwrap().port();
+        let e = TcpListener::bind(("127.0.0.1", port)).unwrap_err();
+        let message = bind_error(&e, "127.0.0.1", port);
+        assert!(message.starts_with(&format!("Port {} is already in use", port)));
+        assert!(message.contains("--port 0"));
+
+        let e = io::Error::from(io::ErrorKind::AddrNotAvailable);
+        assert!(bind_error(&e, "10.9.8.7", 54321).contains("--bind"));
+
+        let Err(ChatError::Bind { message, .. }) = bind("127.0.0.1", port) else {
+            panic!("bound to a port in use");
+        };
+        assert!(message.starts_with(&format!("Port {} is already in use", port)));
+    }
 }
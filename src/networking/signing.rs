@@ -0,0 +1,225 @@
+//! Signing of chat messages with a key made for the session, for `--sign`.
+//!
+//! A client started with `--sign` makes an Ed25519 key, publishes its public half in a `SigningKey` once it has
+//! joined, and signs every message and code block it sends, as they go out on the wire. Other clients check the
+//! signatures against the key published under the sender's pseudonym, or the key replayed history comes with,
+//! and mark the messages that don't match, or that come signed from someone whose key they don't know. The
+//! server relays keys and signatures without checking them.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::OnceLock;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+use crate::networking::messaging::MessageType;
+
+/// The key of this session, made once signing starts.
+static SIGNING_KEY: OnceLock<SigningKey> = OnceLock::new();
+
+/// Starts signing the messages sent with `sign`, with a new key.
+pub fn start() -> io::Result<()> {
+    SIGNING_KEY
+        .set(SigningKey::from_bytes(&rand::random()))
+        .map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "signing already started"))
+}
+
+/// The public key of this session, if signing was started.
+pub fn public_key() -> Option<Vec<u8>> {
+    SIGNING_KEY
+        .get()
+        .map(|key| key.verifying_key().to_bytes().to_vec())
+}
+
+/// Signs a chat message or code block about to be sent, if signing was started. Other messages are left as they
+/// are.
+pub fn sign(message: MessageType) -> MessageType {
+    sign_with(SIGNING_KEY.get(), message)
+}
+
+/// Signs a chat message or code block with the given key, if any.
+pub fn sign_with(key: Option<&SigningKey>, message: MessageType) -> MessageType {
+    let Some(key) = key else {
+        return message;
+    };
+    match message {
+        MessageType::Message(sender, text, nonce, _) => {
+            let signature = key.sign(&message_bytes(nonce, &text)).to_bytes().to_vec();
+            MessageType::Message(sender, text, nonce, signature)
+        }
+        MessageType::Code {
+            sender,
+            lang,
+            body,
+            nonce,
+            ..
+        } => {
+            let signature = key
+                .sign(&code_bytes(nonce, &lang, &body))
+                .to_bytes()
+                .to_vec();
+            MessageType::Code {
+                sender,
+                lang,
+                body,
+                nonce,
+                signature,
+            }
+        }
+        message => message,
+    }
+}
+
+/// Whether a received chat message or code block can be trusted to come from its sender, given the keys published
+/// so far by pseudonym. Messages from senders who don't sign are, unless they come with a signature anyway.
+pub fn is_authentic(message: &MessageType, keys: &HashMap<String, Vec<u8>>) -> bool {
+    let (sender, signed, signature) = match message {
+        MessageType::Message(sender, text, nonce, signature) => {
+            (sender, message_bytes(*nonce, text), signature)
+        }
+        MessageType::Code {
+            sender,
+            lang,
+            body,
+            nonce,
+            signature,
+        } => (sender, code_bytes(*nonce, lang, body), signature),
+        _ => return true,
+    };
+    match keys.get(sender) {
+        Some(key) => verify(key, &signed, signature),
+        None => signature.is_empty(),
+    }
+}
+
+/// What gets signed for a message: its kind, nonce and text, each part prefixed with its length so that they
+/// can't be moved from one to the other. Repeats of a signed nonce are dropped by the receiving clients.
+fn message_bytes(nonce: u64, text: &str) -> Vec<u8> {
+    signed_bytes("message", nonce, &[text.as_bytes()])
+}
+
+/// What gets signed for a code block: the same as for a message, with the language before the body.
+fn code_bytes(nonce: u64, lang: &str, body: &str) -> Vec<u8> {
+    signed_bytes("code", nonce, &[lang.as_bytes(), body.as_bytes()])
+}
+
+fn signed_bytes(kind: &str, nonce: u64, parts: &[&[u8]]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for part in [kind.as_bytes(), &nonce.to_be_bytes()].iter().chain(parts) {
+        bytes.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(part);
+    }
+    bytes
+}
+
+/// Checks a signature. Strictly, so that a signature can't be changed into another valid one for the same message.
+fn verify(public: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let Ok(public) = <[u8; 32]>::try_from(public) else {
+        return false;
+    };
+    match (
+        VerifyingKey::from_bytes(&public),
+        Signature::from_slice(signature),
+    ) {
+        (Ok(key), Ok(signature)) => key.verify_strict(message, &signature).is_ok(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The order of the Ed25519 base point, little-endian.
+    const L: [u8; 32] = [
+        0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde,
+        0x14, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+    ];
+
+    fn keys_of(sender: &str, key: &SigningKey) -> HashMap<String, Vec<u8>> {
+        let mut keys = HashMap::new();
+        keys.insert(sender.to_string(), key.verifying_key().to_bytes().to_vec());
+        keys
+    }
+
+    #[test]
+    fn test_signed_messages_round_trip() {
+        let alice = SigningKey::from_bytes(&rand::random());
+        let mallory = SigningKey::from_bytes(&rand::random());
+        let mut keys = keys_of("alice", &alice);
+
+        let message = || MessageType::Message("alice".to_string(), "hi".to_string(), 5, vec![]);
+        let signed = sign_with(Some(&alice), message());
+        assert!(is_authentic(&signed, &keys));
+
+        // Signed by someone else, changed on the way, or missing the signature
+        assert!(!is_authentic(&sign_with(Some(&mallory), message()), &keys));
+        let MessageType::Message(sender, _, nonce, signature) = signed.clone() else {
+            unreachable!();
+        };
+        let changed =
+            MessageType::Message(sender.clone(), "bye".to_string(), nonce, signature.clone());
+        assert!(!is_authentic(&changed, &keys));
+        let renumbered = MessageType::Message(sender, "hi".to_string(), nonce + 1, signature);
+        assert!(!is_authentic(&renumbered, &keys));
+        assert!(!is_authentic(&message(), &keys));
+
+        // Senders without a key are trusted only as long as they don't claim to sign
+        keys.clear();
+        assert!(is_authentic(&message(), &keys));
+        assert!(!is_authentic(&signed, &keys));
+        assert!(is_authentic(&MessageType::Info("hi".to_string()), &keys));
+    }
+
+    #[test]
+    fn test_code_is_signed() {
+        let alice = SigningKey::from_bytes(&rand::random());
+        let keys = keys_of("alice", &alice);
+        let code = |lang: &str, body: &str| MessageType::Code {
+            sender: "alice".to_string(),
+            lang: lang.to_string(),
+            body: body.to_string(),
+            nonce: 3,
+            signature: vec![],
+        };
+
+        let signed = sign_with(Some(&alice), code("rust", "fn main() {}"));
+        assert!(is_authentic(&signed, &keys));
+        assert!(!is_authentic(&code("rust", "fn main() {}"), &keys));
+
+        // Nothing can be moved between the language and the body
+        let MessageType::Code { signature, .. } = signed else {
+            unreachable!();
+        };
+        let moved = MessageType::Code {
+            sender: "alice".to_string(),
+            lang: "rus".to_string(),
+            body: "tfn main() {}".to_string(),
+            nonce: 3,
+            signature,
+        };
+        assert!(!is_authentic(&moved, &keys));
+    }
+
+    #[test]
+    fn test_malleated_signatures_are_rejected() {
+        let alice = SigningKey::from_bytes(&rand::random());
+        let keys = keys_of("alice", &alice);
+        let MessageType::Message(sender, text, nonce, mut signature) = sign_with(
+            Some(&alice),
+            MessageType::Message("alice".to_string(), "hi".to_string(), 5, vec![]),
+        ) else {
+            unreachable!();
+        };
+
+        // S + L is the same point, so only the check that S is below L turns it away
+        let mut carry = 0;
+        for (byte, l) in signature[32..].iter_mut().zip(L) {
+            let sum = *byte as u16 + l as u16 + carry;
+            *byte = sum as u8;
+            carry = sum >> 8;
+        }
+        let malleated = MessageType::Message(sender, text, nonce, signature);
+        assert!(!is_authentic(&malleated, &keys));
+    }
+}
@@ -0,0 +1,139 @@
+//! Detects clients repeating the same message over and over.
+//!
+//! Messages are compared after normalizing case, punctuation and whitespace, so that small
+//! variations of a copy-pasted message still count as repeats. Every burst of repeats is a strike,
+//! and the action taken escalates with the strikes: a warning, then a temporary mute, then a kick.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// How far back messages are compared against.
+pub const SPAM_WINDOW: Duration = Duration::from_secs(10);
+
+/// Number of near-identical messages within the window that counts as spam.
+pub const SPAM_REPEATS: usize = 3;
+
+/// How long a client is muted on its second strike.
+pub const MUTE_DURATION: Duration = Duration::from_secs(30);
+
+/// How long strikes are remembered for.
+pub const STRIKE_MEMORY: Duration = Duration::from_secs(10 * 60);
+
+/// What to do with a message.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    Warn,
+    Mute,
+    Muted(Duration), // Still muted for this long
+    Kick,
+}
+
+/// The recent messages and strikes of a client.
+#[derive(Default)]
+pub struct SpamGuard {
+    recent: VecDeque<(Instant, u64)>, // When a message was sent and the hash of its normalized text
+    strikes: u32,
+    last_strike: Option<Instant>,
+    muted_until: Option<Instant>,
+}
+
+impl SpamGuard {
+    /// Records a message sent at `now` and decides what to do with it.
+    pub fn check(&mut self, text: &str, now: Instant) -> Verdict {
+        if let Some(remaining) = self
+            .muted_until
+            .and_then(|until| until.checked_duration_since(now))
+        {
+            return Verdict::Muted(remaining);
+        }
+
+        if self
+            .last_strike
+            .is_some_and(|last| now.duration_since(last) > STRIKE_MEMORY)
+        {
+            self.strikes = 0;
+        }
+
+        while self
+            .recent
+            .front()
+            .is_some_and(|(sent, _)| now.duration_since(*sent) > SPAM_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+
+        let hash = normalized_hash(text);
+        self.recent.push_back((now, hash));
+        let repeats = self.recent.iter().filter(|(_, h)| *h == hash).count();
+        if repeats < SPAM_REPEATS {
+            return Verdict::Allow;
+        }
+
+        // Start over so that the next strike needs a new burst of repeats
+        self.recent.clear();
+        self.strikes += 1;
+        self.last_strike = Some(now);
+        match self.strikes {
+            1 => Verdict::Warn,
+            2 => {
+                self.muted_until = Some(now + MUTE_DURATION);
+                Verdict::Mute
+            }
+            _ => Verdict::Kick,
+        }
+    }
+}
+
+/// Hashes the text ignoring case, punctuation and whitespace.
+fn normalized_hash(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for ch in text.chars().filter(|ch| ch.is_alphanumeric()) {
+        ch.to_lowercase().for_each(|ch| ch.hash(&mut hasher));
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeated_messages_escalate() {
+        let mut guard = SpamGuard::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(guard.check("buy now!", at(0)), Verdict::Allow);
+        assert_eq!(guard.check("hello", at(1)), Verdict::Allow);
+        assert_eq!(guard.check("BUY NOW", at(2)), Verdict::Allow);
+        assert_eq!(guard.check("buy  now!!", at(3)), Verdict::Warn);
+
+        for secs in 4..6 {
+            assert_eq!(guard.check("buy now", at(secs)), Verdict::Allow);
+        }
+        assert_eq!(guard.check("buy now", at(6)), Verdict::Mute);
+        assert_eq!(
+            guard.check("hello", at(16)),
+            Verdict::Muted(MUTE_DURATION - Duration::from_secs(10))
+        );
+
+        for secs in 40..42 {
+            assert_eq!(guard.check("buy now", at(secs)), Verdict::Allow);
+        }
+        assert_eq!(guard.check("buy now", at(42)), Verdict::Kick);
+    }
+
+    #[test]
+    fn test_repeats_outside_the_window_are_allowed() {
+        let mut guard = SpamGuard::default();
+        let start = Instant::now();
+
+        for n in 0..10 {
+            let now = start + SPAM_WINDOW * n;
+            assert_eq!(guard.check("good morning", now), Verdict::Allow);
+        }
+    }
+}
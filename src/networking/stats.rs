@@ -0,0 +1,143 @@
+//! Totals of what this client sent and received during the session, shown by `/stats`.
+//!
+//! Every message sent through `SessionStats::send` and every message read from the server is counted,
+//! with its size on the wire. Nothing is sent to the server, the totals only live in this client.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::networking::client::format_duration;
+use crate::networking::connection::Connection;
+use crate::networking::error::ChatError;
+use crate::networking::messaging::MessageType;
+use crate::networking::transfer::format_size;
+
+/// Bytes in front of every message on the wire, giving its length.
+const FRAME_HEADER_SIZE: u64 = 4;
+
+/// Session totals, atomics so that the threads sending and receiving never wait on each other to update them.
+pub struct SessionStats {
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    files_sent: AtomicU64,
+    files_received: AtomicU64,
+    connected_at: Mutex<Instant>, // Start of the current connection, reset when reconnecting
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        SessionStats {
+            messages_sent: AtomicU64::default(),
+            messages_received: AtomicU64::default(),
+            bytes_sent: AtomicU64::default(),
+            bytes_received: AtomicU64::default(),
+            files_sent: AtomicU64::default(),
+            files_received: AtomicU64::default(),
+            connected_at: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl SessionStats {
+    /// Sends a message on the connection, counting it if it was sent.
+    pub fn send(&self, stream: &mut Connection, message: &MessageType) -> Result<(), ChatError> {
+        stream.send(message)?;
+        self.record(message, &self.messages_sent, &self.bytes_sent);
+        Ok(())
+    }
+
+    /// Counts a message read from the server.
+    pub fn received(&self, message: &MessageType) {
+        self.record(message, &self.messages_received, &self.bytes_received);
+    }
+
+    /// Counts a file or image whose sending is complete.
+    pub fn file_sent(&self) {
+        self.files_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a file or image received in full.
+    pub fn file_received(&self) {
+        self.files_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Starts timing a new connection to the server.
+    pub fn reconnected(&self) {
+        *self.connected_at.lock().unwrap() = Instant::now();
+    }
+
+    /// Adds a message to the bytes and, if it is chat, to the messages.
+    fn record(&self, message: &MessageType, messages: &AtomicU64, bytes: &AtomicU64) {
+        if matches!(message, MessageType::Message(..) | MessageType::Code { .. }) {
+            messages.fetch_add(1, Ordering::Relaxed);
+        }
+        let size = bincode::serialized_size(message).unwrap_or_default() + FRAME_HEADER_SIZE;
+        bytes.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// Describes the totals, a line each.
+    pub fn lines(&self) -> Vec<String> {
+        let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        vec![
+            format!(
+                "Messages: {} sent, {} received",
+                count(&self.messages_sent),
+                count(&self.messages_received)
+            ),
+            format!(
+                "Data: {} sent, {} received",
+                format_size(count(&self.bytes_sent) as usize),
+                format_size(count(&self.bytes_received) as usize)
+            ),
+            format!(
+                "Files: {} sent, {} received",
+                count(&self.files_sent),
+                count(&self.files_received)
+            ),
+            format!(
+                "Connected for {}",
+                format_duration(self.connected_at.lock().unwrap().elapsed().as_secs())
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::networking::connection::Endpoint;
+    use crate::networking::messaging::receive_message;
+
+    #[test]
+    fn test_sent_and_received_messages_are_counted() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let (mut server_side, _) = listener.accept().unwrap();
+        let stats = SessionStats::default();
+
+        let message = MessageType::Message(String::new(), "hi".to_string(), 1, vec![]);
+        stats.send(&mut stream, &message).unwrap();
+        stats.send(&mut stream, &MessageType::ListUsers).unwrap();
+        let size = bincode::serialized_size(&message).unwrap() as usize + 4;
+        assert_eq!(receive_message(&mut server_side).unwrap(), message);
+
+        stats.received(&MessageType::Info("welcome".to_string()));
+        stats.file_received();
+
+        let lines = stats.lines();
+        assert_eq!(lines[0], "Messages: 1 sent, 0 received");
+        assert_eq!(
+            lines[1],
+            format!("Data: {} B sent, 23 B received", size + 8)
+        );
+        assert_eq!(lines[2], "Files: 0 sent, 1 received");
+        assert_eq!(lines[3], "Connected for 0s");
+    }
+}
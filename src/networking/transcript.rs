@@ -0,0 +1,147 @@
+//! A transcript of every message the client sends and receives, to attach to bug reports.
+//!
+//! Once started with `--transcript <path>`, `send_message` and `receive_message` append a JSON line per
+//! message with the time, the direction, the size of its frame and the message itself. The contents of files
+//! and images are left out, keeping only their size, as the bytes would drown everything else.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::*;
+
+use crate::networking::messaging::MessageType;
+
+/// The file messages are appended to, set once when the transcript starts.
+static TRANSCRIPT: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Which way a message crossed the wire.
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Starts appending every message sent and received to the file at `path`.
+pub fn start(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    TRANSCRIPT
+        .set(Mutex::new(file))
+        .map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "transcript already started"))
+}
+
+/// Appends a message to the transcript, if one was started. `bytes` is the size of its frame without the length.
+pub fn record(direction: Direction, message: &MessageType, bytes: usize) {
+    let Some(transcript) = TRANSCRIPT.get() else {
+        return;
+    };
+    let line = transcript_line(SystemTime::now(), direction, message, bytes);
+    if let Ok(mut file) = transcript.lock() {
+        // Flushed line by line so that nothing is lost when the client crashes
+        if let Err(e) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+            warn!("Failed to write to the transcript: {}", e);
+        }
+    }
+}
+
+/// Formats a message as a line of the transcript, newline included.
+fn transcript_line(
+    time: SystemTime,
+    direction: Direction,
+    message: &MessageType,
+    bytes: usize,
+) -> String {
+    let time_ms = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let direction = match direction {
+        Direction::Sent => "sent",
+        Direction::Received => "received",
+    };
+    format!(
+        "{{\"time_ms\":{},\"direction\":\"{}\",\"bytes\":{},\"message\":{}}}\n",
+        time_ms,
+        direction,
+        bytes,
+        json_string(&describe(message))
+    )
+}
+
+/// Formats a message like `Debug` does, with the size of file and image contents in place of the bytes.
+fn describe(message: &MessageType) -> String {
+    match message {
+        MessageType::File(file_name, contents, checksum) => format!(
+            "File({:?}, <{} bytes>, {:?})",
+            file_name,
+            contents.len(),
+            checksum
+        ),
+        MessageType::Image(image_name, contents) => {
+            format!("Image({:?}, <{} bytes>)", image_name, contents.len())
+        }
+        MessageType::FileChunk {
+            transfer_id,
+            file_name,
+            seq,
+            total,
+            data,
+            checksum,
+        } => format!(
+            "FileChunk {{ transfer_id: {}, file_name: {:?}, seq: {}, total: {}, data: <{} bytes>, checksum: {:?} }}",
+            transfer_id,
+            file_name,
+            seq,
+            total,
+            data.len(),
+            checksum
+        ),
+        message => format!("{:?}", message),
+    }
+}
+
+/// Quotes text as a JSON string.
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_transcript_lines() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        assert_eq!(
+            transcript_line(
+                time,
+                Direction::Received,
+                &MessageType::Message("bob".to_string(), "say \"hi\"\n".to_string(), 4, vec![]),
+                30
+            ),
+            "{\"time_ms\":1700000000123,\"direction\":\"received\",\"bytes\":30,\"message\":\"Message(\\\"bob\\\", \\\"say \\\\\\\"hi\\\\\\\"\\\\n\\\", 4, [])\"}\n"
+        );
+        assert_eq!(
+            describe(&MessageType::Image("cat.png".to_string(), vec![0; 2048])),
+            "Image(\"cat.png\", <2048 bytes>)"
+        );
+    }
+}
@@ -0,0 +1,581 @@
+//! Contains the logic for chunked file transfers.
+//!
+//! Files are split into `FileChunk`s small enough to fit in a single message. The receiver writes
+//! each chunk into a `.part` file as it arrives and keeps track of the sequence numbers it has, so
+//! that a transfer interrupted halfway can be completed by asking the sender for the missing chunks
+//! with a `FileResend`. The sender keeps the file contents around for a while to be able to answer.
+//! The final chunk carries the SHA-256 of the whole file, which the receiver verifies before handing
+//! the file over. A sender can call a transfer off halfway with a `FileCancel`, after which the
+//! receivers drop what they have of it.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::*;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use crate::networking::connection::Connection;
+use crate::networking::crypto;
+use crate::networking::messaging::MessageType;
+use crate::networking::stats::SessionStats;
+
+/// Size of the file contents carried by a single chunk, well below `MAX_MESSAGE_SIZE`.
+pub const FILE_CHUNK_SIZE: usize = 32 * 1024;
+
+/// How long an incoming transfer can stall before the missing chunks are requested again.
+pub const RESEND_AFTER: Duration = Duration::from_secs(5);
+
+/// How long an incoming transfer can stall before it is abandoned and the partial file removed.
+pub const ABANDON_AFTER: Duration = Duration::from_secs(60);
+
+/// How long the sender keeps the contents of a sent file to answer resend requests.
+pub const RETAIN_FOR: Duration = Duration::from_secs(5 * 60);
+
+/// Extensions of files that can be run directly, which are only saved once the user accepts them.
+pub const RISKY_EXTENSIONS: [&str; 4] = ["exe", "sh", "bat", "scr"];
+
+/// A file this client has sent, kept to resend chunks on request.
+struct OutgoingTransfer {
+    file_name: String,
+    contents: Vec<u8>,
+    checksum: String,
+    started: Instant,
+    sent: u32, // Chunks handed out to be sent so far
+}
+
+impl OutgoingTransfer {
+    fn in_progress(&self) -> bool {
+        self.sent < chunk_count(self.contents.len())
+    }
+}
+
+/// A file being received, written chunk by chunk into its `.part` file.
+struct IncomingTransfer {
+    file_name: String,
+    part_path: PathBuf,
+    received: Vec<bool>,
+    checksum: Option<String>,
+    last_activity: Instant,
+    resend_requested: Option<Instant>,
+}
+
+impl IncomingTransfer {
+    fn missing(&self) -> Vec<u32> {
+        (0..self.received.len() as u32)
+            .filter(|&seq| !self.received[seq as usize])
+            .collect()
+    }
+}
+
+/// Book-keeping of the file transfers of a client, in both directions.
+pub struct Transfers {
+    download_dir: PathBuf,
+    outgoing: HashMap<u64, OutgoingTransfer>,
+    incoming: HashMap<u64, IncomingTransfer>,
+    finished: HashSet<u64>, // Completed or abandoned incoming transfers, so late chunks are ignored
+}
+
+impl Default for Transfers {
+    fn default() -> Self {
+        Transfers::new(std::env::current_dir().unwrap_or_default())
+    }
+}
+
+impl Transfers {
+    /// Creates the transfer book-keeping, with partial files being written to `download_dir`.
+    pub fn new(download_dir: PathBuf) -> Self {
+        Transfers {
+            download_dir,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            finished: HashSet::new(),
+        }
+    }
+
+    /// Starts sending a file, keeping its contents around to answer resend requests.
+    ///
+    /// Returns the transfer id and the number of chunks, which are then fetched with `outgoing_chunk`
+    /// so that the transfers aren't locked while the chunks are written to the stream.
+    pub fn start_send(&mut self, file_name: String, contents: Vec<u8>) -> (u64, u32) {
+        let transfer_id = rand::thread_rng().gen::<u64>();
+        let total = chunk_count(contents.len());
+        let transfer = OutgoingTransfer {
+            file_name,
+            checksum: sha256_hex(&contents),
+            contents,
+            started: Instant::now(),
+            sent: 0,
+        };
+        self.outgoing.insert(transfer_id, transfer);
+
+        (transfer_id, total)
+    }
+
+    /// Returns the message carrying a chunk of a file being sent, or `None` once the transfer was cancelled.
+    pub fn outgoing_chunk(&mut self, transfer_id: u64, seq: u32) -> Option<MessageType> {
+        let transfer = self.outgoing.get_mut(&transfer_id)?;
+        transfer.sent = transfer.sent.max(seq + 1);
+        Some(chunk_message(transfer_id, transfer, seq))
+    }
+
+    /// Describes the transfers in progress in both directions, a line each.
+    pub fn lines(&self) -> Vec<String> {
+        let mut sending: Vec<_> = self
+            .outgoing
+            .iter()
+            .filter(|(_, transfer)| transfer.in_progress())
+            .collect();
+        sending.sort_by_key(|(&transfer_id, _)| transfer_id);
+        let mut receiving: Vec<_> = self.incoming.iter().collect();
+        receiving.sort_by_key(|(&transfer_id, _)| transfer_id);
+
+        let sending = sending.into_iter().map(|(transfer_id, transfer)| {
+            format!(
+                "{:x}: sending {} ({}), {}%",
+                transfer_id,
+                file_name_only(&transfer.file_name),
+                format_size(transfer.contents.len()),
+                transfer.sent * 100 / chunk_count(transfer.contents.len())
+            )
+        });
+        let receiving = receiving.into_iter().map(|(transfer_id, transfer)| {
+            let received = transfer
+                .received
+                .iter()
+                .filter(|&&received| received)
+                .count();
+            format!(
+                "{:x}: receiving {}, {}%",
+                transfer_id,
+                file_name_only(&transfer.file_name),
+                received * 100 / transfer.received.len()
+            )
+        });
+        sending.chain(receiving).collect()
+    }
+
+    /// Stops a transfer in progress, forgetting a file being sent or removing the partial file of one being
+    /// received. Returns the name of the file and whether it was being sent, or `None` if there is no such
+    /// transfer in progress.
+    pub fn cancel(&mut self, transfer_id: u64) -> Option<(String, bool)> {
+        if self
+            .outgoing
+            .get(&transfer_id)
+            .is_some_and(OutgoingTransfer::in_progress)
+        {
+            let transfer = self.outgoing.remove(&transfer_id)?;
+            return Some((transfer.file_name, true));
+        }
+
+        self.drop_incoming(transfer_id)
+            .map(|file_name| (file_name, false))
+    }
+
+    /// Drops an incoming transfer whose sender cancelled it, returning the name of its file.
+    ///
+    /// Chunks of it still on their way are ignored.
+    pub fn drop_incoming(&mut self, transfer_id: u64) -> Option<String> {
+        let transfer = self.incoming.remove(&transfer_id)?;
+        self.finished.insert(transfer_id);
+        let _ = std::fs::remove_file(&transfer.part_path);
+        Some(transfer.file_name)
+    }
+
+    /// Sends the requested chunks of a file sent earlier again.
+    pub fn resend(
+        &self,
+        stream: &mut Connection,
+        stats: &SessionStats,
+        transfer_id: u64,
+        missing: &[u32],
+    ) -> io::Result<()> {
+        let Some(transfer) = self.outgoing.get(&transfer_id) else {
+            warn!("Resend requested for unknown transfer {}", transfer_id);
+            return Ok(());
+        };
+
+        let total = chunk_count(transfer.contents.len());
+        for &seq in missing.iter().filter(|&&seq| seq < total) {
+            stats.send(
+                stream,
+                &crypto::seal(chunk_message(transfer_id, transfer, seq)),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes a received chunk into the partial file of its transfer.
+    ///
+    /// Once every chunk has been received, the partial file is removed and its name, contents and checksum
+    /// are returned. If the contents don't match the checksum sent by the sender, the file is discarded and
+    /// an `InvalidData` error is returned.
+    pub fn receive_chunk(
+        &mut self,
+        transfer_id: u64,
+        file_name: &str,
+        seq: u32,
+        total: u32,
+        data: &[u8],
+        checksum: Option<String>,
+    ) -> io::Result<Option<(String, Vec<u8>, String)>> {
+        if self.finished.contains(&transfer_id) || total == 0 {
+            return Ok(None);
+        }
+
+        let download_dir = &self.download_dir;
+        let transfer = self
+            .incoming
+            .entry(transfer_id)
+            .or_insert_with(|| IncomingTransfer {
+                file_name: file_name.to_string(),
+                part_path: download_dir.join(format!(
+                    "{}.{}.part",
+                    file_name_only(file_name),
+                    transfer_id
+                )),
+                received: vec![false; total as usize],
+                checksum: None,
+                last_activity: Instant::now(),
+                resend_requested: None,
+            });
+        transfer.last_activity = Instant::now();
+
+        if seq as usize >= transfer.received.len() || transfer.received[seq as usize] {
+            return Ok(None);
+        }
+
+        let mut part_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&transfer.part_path)?;
+        part_file.seek(SeekFrom::Start(seq as u64 * FILE_CHUNK_SIZE as u64))?;
+        part_file.write_all(data)?;
+        transfer.received[seq as usize] = true;
+        if checksum.is_some() {
+            transfer.checksum = checksum;
+        }
+
+        if !transfer.received.iter().all(|&received| received) {
+            return Ok(None);
+        }
+
+        let transfer = self.incoming.remove(&transfer_id).unwrap();
+        self.finished.insert(transfer_id);
+        let contents = std::fs::read(&transfer.part_path)?;
+        std::fs::remove_file(&transfer.part_path)?;
+
+        let checksum = sha256_hex(&contents);
+        if transfer.checksum.as_ref() != Some(&checksum) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("checksum mismatch for {}", transfer.file_name),
+            ));
+        }
+
+        Ok(Some((transfer.file_name, contents, checksum)))
+    }
+
+    /// Requests the missing chunks of stalled incoming transfers, abandons the ones stalled for too long
+    /// and forgets sent files that are too old to be asked for. Returns the messages to show to the user.
+    pub fn poll_stalled(
+        &mut self,
+        stream: &mut Connection,
+        stats: &SessionStats,
+    ) -> Vec<MessageType> {
+        let mut notices = vec![];
+
+        let abandoned: Vec<u64> = self
+            .incoming
+            .iter()
+            .filter(|(_, transfer)| transfer.last_activity.elapsed() > ABANDON_AFTER)
+            .map(|(&transfer_id, _)| transfer_id)
+            .collect();
+        for transfer_id in abandoned {
+            let transfer = self.incoming.remove(&transfer_id).unwrap();
+            self.finished.insert(transfer_id);
+            let _ = std::fs::remove_file(&transfer.part_path);
+            notices.push(MessageType::Error(format!(
+                "Transfer of {} was abandoned, partial file removed",
+                transfer.file_name
+            )));
+        }
+
+        for (&transfer_id, transfer) in self.incoming.iter_mut() {
+            let stalled = transfer.last_activity.elapsed() > RESEND_AFTER;
+            let recently_requested = transfer
+                .resend_requested
+                .is_some_and(|requested| requested.elapsed() < RESEND_AFTER);
+            if !stalled || recently_requested {
+                continue;
+            }
+
+            let missing = transfer.missing();
+            info!(
+                "Requesting {} missing chunks of {}",
+                missing.len(),
+                transfer.file_name
+            );
+            match stats.send(
+                stream,
+                &MessageType::FileResend {
+                    transfer_id,
+                    missing,
+                },
+            ) {
+                Ok(_) => transfer.resend_requested = Some(Instant::now()),
+                Err(e) => error!("Failed to request missing chunks: {}", e),
+            }
+        }
+
+        self.outgoing.retain(|_, transfer| {
+            let keep = transfer.in_progress() || transfer.started.elapsed() < RETAIN_FOR;
+            if !keep {
+                debug!("Forgetting sent file {}", transfer.file_name);
+            }
+            keep
+        });
+
+        notices
+    }
+}
+
+/// Whether a received file looks like something that can be run, going by its extension or a shebang.
+pub fn is_risky_file(file_name: &str, contents: &[u8]) -> bool {
+    let risky_extension = Path::new(file_name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            RISKY_EXTENSIONS
+                .iter()
+                .any(|risky| risky.eq_ignore_ascii_case(extension))
+        });
+
+    risky_extension || contents.starts_with(b"#!")
+}
+
+/// Returns the SHA-256 of the data as a lowercase hex string.
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Number of chunks needed for a file of the given size. Empty files still take one chunk.
+fn chunk_count(len: usize) -> u32 {
+    len.div_ceil(FILE_CHUNK_SIZE).max(1) as u32
+}
+
+/// Builds the message carrying the chunk `seq` of an outgoing transfer.
+fn chunk_message(transfer_id: u64, transfer: &OutgoingTransfer, seq: u32) -> MessageType {
+    let start = (seq as usize * FILE_CHUNK_SIZE).min(transfer.contents.len());
+    let end = (start + FILE_CHUNK_SIZE).min(transfer.contents.len());
+
+    let total = chunk_count(transfer.contents.len());
+
+    MessageType::FileChunk {
+        transfer_id,
+        file_name: transfer.file_name.clone(),
+        seq,
+        total,
+        data: transfer.contents[start..end].to_vec(),
+        checksum: (seq + 1 == total).then(|| transfer.checksum.clone()),
+    }
+}
+
+/// Formats a size in bytes for people, e.g. `1.5 MB`.
+pub fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next_unit in &UNITS[1..] {
+        // Compared once rounded, so that just under a unit doesn't show as 1024.0 of the one below
+        if (size * 10.0).round() < 10240.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next_unit;
+    }
+    format!("{:.1} {}", size, unit)
+}
+
+/// Extracts the file name, ignoring any path components.
+pub fn file_name_only(file_name: &str) -> &str {
+    Path::new(file_name)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("downloaded_file")
+}
+
+/// Saves a received file in the current directory, returning the name it was saved under.
+pub fn save_to_current_dir(file_name: &str, contents: &[u8]) -> io::Result<String> {
+    let file_name = file_name_only(file_name);
+    std::fs::write(std::env::current_dir()?.join(file_name), contents)?;
+    Ok(file_name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outgoing(contents: Vec<u8>) -> OutgoingTransfer {
+        OutgoingTransfer {
+            file_name: "test.bin".to_string(),
+            checksum: sha256_hex(&contents),
+            contents,
+            started: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    #[test]
+    fn test_chunk_count() {
+        assert_eq!(chunk_count(0), 1);
+        assert_eq!(chunk_count(FILE_CHUNK_SIZE), 1);
+        assert_eq!(chunk_count(FILE_CHUNK_SIZE + 1), 2);
+    }
+
+    #[test]
+    fn test_out_of_order_and_duplicate_chunks_reassemble() {
+        let contents: Vec<u8> = (0..FILE_CHUNK_SIZE * 2 + 10)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let transfer = outgoing(contents.clone());
+        let mut transfers = Transfers::new(std::env::temp_dir());
+
+        let mut result = None;
+        for seq in [2, 0, 0, 1] {
+            let MessageType::FileChunk {
+                file_name,
+                total,
+                data,
+                checksum,
+                ..
+            } = chunk_message(7, &transfer, seq)
+            else {
+                unreachable!()
+            };
+            if let Some(file) = transfers
+                .receive_chunk(7, &file_name, seq, total, &data, checksum)
+                .unwrap()
+            {
+                result = Some(file);
+            }
+        }
+
+        assert_eq!(
+            result,
+            Some(("test.bin".to_string(), contents, transfer.checksum))
+        );
+        assert!(transfers
+            .receive_chunk(7, "test.bin", 0, 3, &[1, 2, 3], None)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_missing_chunks_are_tracked() {
+        let transfer = outgoing(vec![0; FILE_CHUNK_SIZE * 3]);
+        let mut transfers = Transfers::new(std::env::temp_dir());
+
+        let MessageType::FileChunk { data, .. } = chunk_message(8, &transfer, 1) else {
+            unreachable!()
+        };
+        transfers
+            .receive_chunk(8, "test.bin", 1, 3, &data, None)
+            .unwrap();
+
+        assert_eq!(transfers.incoming[&8].missing(), vec![0, 2]);
+        let _ = std::fs::remove_file(&transfers.incoming[&8].part_path);
+    }
+
+    #[test]
+    fn test_checksum_mismatch_discards_file() {
+        let transfer = outgoing(vec![1, 2, 3]);
+        let mut transfers = Transfers::new(std::env::temp_dir());
+
+        let MessageType::FileChunk { checksum, .. } = chunk_message(9, &transfer, 0) else {
+            unreachable!()
+        };
+        let error = transfers
+            .receive_chunk(9, "test.bin", 0, 1, &[1, 2, 4], checksum)
+            .unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "checksum mismatch for test.bin");
+        assert!(!std::env::temp_dir().join("test.bin.9.part").exists());
+    }
+
+    #[test]
+    fn test_cancelled_transfers_are_dropped() {
+        let mut transfers = Transfers::new(std::env::temp_dir());
+        let (sending, total) =
+            transfers.start_send("dir/big.bin".to_string(), vec![0; FILE_CHUNK_SIZE * 4]);
+        assert_eq!(total, 4);
+        transfers.outgoing_chunk(sending, 0);
+
+        let transfer = outgoing(vec![0; FILE_CHUNK_SIZE * 2]);
+        let MessageType::FileChunk { data, .. } = chunk_message(10, &transfer, 0) else {
+            unreachable!()
+        };
+        transfers
+            .receive_chunk(10, "test.bin", 0, 2, &data, None)
+            .unwrap();
+        let part_path = transfers.incoming[&10].part_path.clone();
+        assert!(part_path.exists());
+
+        assert_eq!(
+            transfers.lines(),
+            vec![
+                format!("{:x}: sending big.bin (128.0 KB), 25%", sending),
+                "a: receiving test.bin, 50%".to_string(),
+            ]
+        );
+
+        assert_eq!(
+            transfers.cancel(sending),
+            Some(("dir/big.bin".to_string(), true))
+        );
+        assert_eq!(transfers.outgoing_chunk(sending, 1), None);
+        assert_eq!(transfers.cancel(10), Some(("test.bin".to_string(), false)));
+        assert!(!part_path.exists());
+        assert!(transfers
+            .receive_chunk(10, "test.bin", 1, 2, &data, None)
+            .unwrap()
+            .is_none());
+        assert_eq!(transfers.cancel(10), None);
+        assert!(transfers.lines().is_empty());
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1024), "1.0 KB");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(1024 * 1024 - 1), "1.0 MB");
+        assert_eq!(format_size(1024 * 1024), "1.0 MB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GB");
+    }
+
+    #[test]
+    fn test_is_risky_file() {
+        assert!(is_risky_file("setup.exe", b"MZ"));
+        assert!(is_risky_file("run.SH", b""));
+        assert!(is_risky_file("notes", b"#!/bin/sh\nrm -rf ~"));
+        assert!(!is_risky_file("notes.txt", b"hello"));
+        assert!(!is_risky_file("archive.exe.txt", b"hello"));
+    }
+}
@@ -0,0 +1,206 @@
+//! A plain, line based interface for screen readers, used instead of the TUI with `--plain`.
+//!
+//! Input is read a line at a time from stdin and incoming messages are printed to stdout as they
+//! arrive, without raw mode or the alternate screen.
+
+use std::collections::HashSet;
+use std::io::{self, BufRead};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::networking::client::{user_entry, without_contents, ClientState};
+use crate::networking::messaging::{MessageType, PresenceState};
+use crate::networking::transfer::{file_name_only, format_size};
+use crate::tui_handler::{submit_input, Submitted};
+
+/// How often new messages are looked for.
+const PRINT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Runs the plain interface until the user quits or stdin is closed.
+pub fn run_plain(
+    message_vector: Arc<Mutex<Vec<MessageType>>>,
+    client_state: &ClientState,
+    pseudonym: &str,
+) -> io::Result<()> {
+    let ignored = Arc::new(Mutex::new(HashSet::new()));
+
+    let printer_messages = Arc::clone(&message_vector);
+    let printer_ignored = Arc::clone(&ignored);
+    let printer_state = client_state.clone();
+    thread::spawn(move || {
+        // Counted from the first message ever pushed, as older ones get dropped
+        let mut printed: usize = 0;
+        loop {
+            thread::sleep(PRINT_INTERVAL);
+            let mut messages = printer_messages.lock().unwrap();
+            let ignored = printer_ignored.lock().unwrap();
+            let start = printed
+                .saturating_sub(printer_state.trimmed.load(Ordering::SeqCst))
+                .min(messages.len());
+            for message in &mut messages[start..] {
+                if let Some(line) = plain_line(message, &ignored) {
+                    if printer_state.is_unverified(message) {
+                        println!("{} (unverified)", line);
+                    } else {
+                        println!("{}", line);
+                    }
+                }
+                // Images have no thumbnail here, so their contents aren't needed once printed
+                if let Some(saved) = without_contents(message) {
+                    *message = saved;
+                }
+            }
+            if let Err(e) = printer_state.ring_bell() {
+                eprintln!("Failed to ring the bell: {}", e);
+            }
+            printer_state.trim_scrollback(&mut messages);
+            printed = printer_state.trimmed.load(Ordering::SeqCst) + messages.len();
+        }
+    });
+
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let mut messages = message_vector.lock().unwrap();
+        let mut stream = client_state.stream.lock().unwrap();
+        match submit_input(
+            &line,
+            &message_vector,
+            &mut messages,
+            &mut stream,
+            &mut ignored.lock().unwrap(),
+            client_state,
+            pseudonym,
+        ) {
+            Ok(Submitted::Quit) => return Ok(()),
+            Ok(_) => {}
+            Err(e) => messages.push(MessageType::Error(e.to_string())),
+        }
+    }
+
+    // Stdin was closed without a /quit, leave all the same
+    let mut stream = client_state.stream.lock().unwrap();
+    let local_addr = stream.local_addr()?;
+    client_state
+        .stats
+        .send(&mut stream, &MessageType::Leave(local_addr))?;
+    Ok(())
+}
+
+/// Formats a message as a single line of text.
+fn plain_line(message: &MessageType, ignored: &HashSet<String>) -> Option<String> {
+    let line = match message {
+        MessageType::Info(info) if info.is_empty() => return None,
+        MessageType::Info(info) => info.clone(),
+        MessageType::Notice(kind, text) => format!("{}{}", kind.prefix(), text),
+        MessageType::Leave(leave) => format!("{} has left the chat", leave),
+        MessageType::Message(source, ..) if ignored.contains(source) => return None,
+        MessageType::Message(source, message, ..) => format!("{}: {}", source, message),
+        MessageType::Code { sender, .. } if ignored.contains(sender) => return None,
+        MessageType::Code {
+            sender, lang, body, ..
+        } if lang.is_empty() => {
+            format!("{} (code):\n{}", sender, body)
+        }
+        MessageType::Code {
+            sender, lang, body, ..
+        } => {
+            format!("{} ({} code):\n{}", sender, lang, body)
+        }
+        MessageType::Error(error) => format!("Error: {}", error),
+        MessageType::Presence { nick, state } => match state {
+            PresenceState::Active => format!("{} is back", nick),
+            _ => format!("{} is now {}", nick, state),
+        },
+        MessageType::Status { nick, text } if text.is_empty() => {
+            format!("{} cleared their status", nick)
+        }
+        MessageType::Status { nick, text } => format!("{}'s status: {}", nick, text),
+        MessageType::UserList(users) => {
+            let users: Vec<String> = users
+                .iter()
+                .map(|(nick, state, connected, status, spectator)| {
+                    user_entry(nick, state, *connected, status, *spectator)
+                })
+                .collect();
+            format!("Users in this room: {}", users.join(", "))
+        }
+        MessageType::File(file_name, contents, _) => format!(
+            "Received file: {} ({})",
+            file_name_only(file_name),
+            format_size(contents.len())
+        ),
+        MessageType::SavedFile {
+            file_name, size, ..
+        } => format!(
+            "Received file: {} ({})",
+            file_name_only(file_name),
+            format_size(*size as usize)
+        ),
+        MessageType::Image(image_name, contents) => format!(
+            "Received image: {} ({})",
+            file_name_only(image_name),
+            format_size(contents.len())
+        ),
+        MessageType::SavedImage { image_name, size } => format!(
+            "Received image: {} ({})",
+            file_name_only(image_name),
+            format_size(*size as usize)
+        ),
+        MessageType::Poll {
+            id,
+            question,
+            options,
+        } => {
+            let options: Vec<String> = options
+                .iter()
+                .enumerate()
+                .map(|(index, option)| format!("  {}. {}", index + 1, option))
+                .collect();
+            format!(
+                "Poll #{}: {}\n{}\n  /vote {} <option> to vote",
+                id,
+                question,
+                options.join("\n"),
+                id
+            )
+        }
+        MessageType::PollUpdate { id, counts } => {
+            let counts: Vec<String> = counts
+                .iter()
+                .enumerate()
+                .map(|(index, count)| format!("{}: {}", index + 1, count))
+                .collect();
+            format!("Poll #{} votes: {}", id, counts.join(", "))
+        }
+        _ => return None,
+    };
+    Some(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_line() {
+        let ignored = HashSet::from(["mallory".to_string()]);
+        let message =
+            |source: &str| MessageType::Message(source.to_string(), "hi".to_string(), 0, vec![]);
+
+        assert_eq!(
+            plain_line(&message("alice"), &ignored),
+            Some("alice: hi".to_string())
+        );
+        assert_eq!(plain_line(&message("mallory"), &ignored), None);
+        assert_eq!(
+            plain_line(&MessageType::Error("oops".to_string()), &ignored),
+            Some("Error: oops".to_string())
+        );
+        assert_eq!(
+            plain_line(&MessageType::Info(String::new()), &ignored),
+            None
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Small inline previews of received images, shown under the "Received image" line.
+//!
+//! The message pane is drawn cell by cell, so terminals that understand sixel or the kitty
+//! graphics protocol get a full color preview made of half block characters (two pixels per
+//! cell), while every other terminal gets ASCII art.
+
+use std::collections::HashMap;
+
+use ratatui::prelude::*;
+
+/// The widest a thumbnail gets, in terminal cells.
+#[cfg(feature = "thumbnails")]
+pub const MAX_THUMBNAIL_WIDTH: u32 = 32;
+
+/// The tallest a thumbnail gets, in terminal rows.
+#[cfg(feature = "thumbnails")]
+pub const MAX_THUMBNAIL_HEIGHT: u32 = 12;
+
+/// Characters used for ASCII art, from darkest to brightest.
+#[cfg(feature = "thumbnails")]
+const ASCII_RAMP: &[u8] = b" .:-=+*#%@";
+
+/// How images can be drawn in the current terminal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GraphicsSupport {
+    /// The terminal supports sixel or the kitty graphics protocol, and thus true color.
+    Pixels,
+    Ascii,
+}
+
+impl GraphicsSupport {
+    /// Detects graphics support from `$TERM` and `$TERM_PROGRAM`.
+    pub fn detect() -> Self {
+        Self::from_env(
+            &std::env::var("TERM").unwrap_or_default(),
+            &std::env::var("TERM_PROGRAM").unwrap_or_default(),
+        )
+    }
+
+    fn from_env(term: &str, term_program: &str) -> Self {
+        let term = term.to_lowercase();
+        let graphics_terms = ["kitty", "sixel", "mlterm", "foot", "contour", "wezterm"];
+        let graphics_programs = ["wezterm", "iterm.app", "ghostty", "konsole", "mintty"];
+
+        if graphics_terms.iter().any(|name| term.contains(name))
+            || graphics_programs.contains(&term_program.to_lowercase().as_str())
+        {
+            GraphicsSupport::Pixels
+        } else {
+            GraphicsSupport::Ascii
+        }
+    }
+}
+
+/// Thumbnails of the images received so far, so that each image is only decoded once.
+pub struct Thumbnails {
+    support: GraphicsSupport,
+    rendered: HashMap<(String, usize), Vec<Line<'static>>>,
+}
+
+impl Default for Thumbnails {
+    fn default() -> Self {
+        Thumbnails::new(GraphicsSupport::detect())
+    }
+}
+
+impl Thumbnails {
+    pub fn new(support: GraphicsSupport) -> Self {
+        Thumbnails {
+            support,
+            rendered: HashMap::new(),
+        }
+    }
+
+    /// Returns the thumbnail of an image rendered earlier by `get`, empty if there is none.
+    pub fn cached(&self, image_name: &str, image_size: usize) -> &[Line<'static>] {
+        self.rendered
+            .get(&(image_name.to_string(), image_size))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Returns the lines making up the thumbnail of an image, empty if it can't be decoded.
+    pub fn get(&mut self, image_name: &str, image_contents: &[u8]) -> &[Line<'static>] {
+        let support = self.support;
+        self.rendered
+            .entry((image_name.to_string(), image_contents.len()))
+            .or_insert_with(|| render(image_contents, support))
+    }
+}
+
+#[cfg(feature = "thumbnails")]
+fn render(image_contents: &[u8], support: GraphicsSupport) -> Vec<Line<'static>> {
+    let Ok(image) = image::load_from_memory(image_contents) else {
+        return vec![];
+    };
+    // Terminal cells are about twice as tall as they are wide, so every row covers two pixels
+    let image = image
+        .thumbnail(MAX_THUMBNAIL_WIDTH, MAX_THUMBNAIL_HEIGHT * 2)
+        .to_rgb8();
+
+    let pixel = |x: u32, y: u32| {
+        let [r, g, b] = image.get_pixel(x, y.min(image.height() - 1)).0;
+        (r, g, b)
+    };
+
+    (0..image.height())
+        .step_by(2)
+        .map(|y| {
+            let spans: Vec<Span> = (0..image.width())
+                .map(|x| {
+                    let (top, bottom) = (pixel(x, y), pixel(x, y + 1));
+                    match support {
+                        GraphicsSupport::Pixels => Span::styled(
+                            "▀",
+                            Style::default()
+                                .fg(Color::Rgb(top.0, top.1, top.2))
+                                .bg(Color::Rgb(bottom.0, bottom.1, bottom.2)),
+                        ),
+                        GraphicsSupport::Ascii => {
+                            let brightness = (luma(top) + luma(bottom)) / 2;
+                            let index = brightness as usize * (ASCII_RAMP.len() - 1) / 255;
+                            Span::raw((ASCII_RAMP[index] as char).to_string())
+                        }
+                    }
+                })
+                .collect();
+            Line::from(spans)
+        })
+        .collect()
+}
+
+#[cfg(not(feature = "thumbnails"))]
+fn render(_image_contents: &[u8], _support: GraphicsSupport) -> Vec<Line<'static>> {
+    vec![]
+}
+
+/// Perceived brightness of a pixel, from 0 to 255.
+#[cfg(feature = "thumbnails")]
+fn luma((r, g, b): (u8, u8, u8)) -> u32 {
+    (299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_graphics_support_from_env() {
+        assert_eq!(
+            GraphicsSupport::from_env("xterm-kitty", ""),
+            GraphicsSupport::Pixels
+        );
+        assert_eq!(
+            GraphicsSupport::from_env("xterm-256color", "WezTerm"),
+            GraphicsSupport::Pixels
+        );
+        assert_eq!(
+            GraphicsSupport::from_env("xterm-256color", "Apple_Terminal"),
+            GraphicsSupport::Ascii
+        );
+        assert_eq!(GraphicsSupport::from_env("", ""), GraphicsSupport::Ascii);
+    }
+}
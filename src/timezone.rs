@@ -0,0 +1,136 @@
+//! The time zone times are shown and logged in, for `--timezone`.
+//!
+//! Zones are looked up by their IANA name such as `Europe/Berlin`. Without `--timezone` the system's own zone is
+//! used, named by `$TZ` or by where `/etc/localtime` points.
+
+use std::env;
+use std::fmt;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use chrono_tz::Tz;
+
+/// The zone times are shown in, set once at startup. UTC until then.
+static ZONE: OnceLock<TimeZone> = OnceLock::new();
+
+/// A time zone of the IANA database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeZone(Tz);
+
+impl TimeZone {
+    pub fn utc() -> Self {
+        TimeZone(Tz::UTC)
+    }
+
+    /// Looks up the zone with the given IANA name.
+    pub fn named(name: &str) -> Result<Self, String> {
+        if name.eq_ignore_ascii_case("UTC") {
+            return Ok(TimeZone::utc());
+        }
+        name.parse()
+            .map(TimeZone)
+            .map_err(|_| format!("unknown time zone {}", name))
+    }
+
+    /// Returns the system's own zone, from `$TZ` or `/etc/localtime`, or UTC when it has none.
+    pub fn local() -> Result<Self, String> {
+        match env::var("TZ") {
+            Ok(tz) if !tz.is_empty() => TimeZone::named(tz.strip_prefix(':').unwrap_or(&tz)),
+            _ => match iana_time_zone::get_timezone() {
+                Ok(name) => TimeZone::named(&name),
+                Err(_) => Ok(TimeZone::utc()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for TimeZone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Sets the zone times are shown in from now on. Only the first call has any effect.
+pub fn set(zone: TimeZone) {
+    let _ = ZONE.set(zone);
+}
+
+/// Returns the zone times are shown in.
+fn zone() -> &'static TimeZone {
+    ZONE.get_or_init(TimeZone::utc)
+}
+
+/// Formats the time of day as `HH:MM` followed by the zone abbreviation, e.g. `14:05 CEST`.
+pub fn time_of_day(time: SystemTime) -> String {
+    time_of_day_in(zone(), time)
+}
+
+fn time_of_day_in(zone: &TimeZone, time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .with_timezone(&zone.0)
+        .format("%H:%M %Z")
+        .to_string()
+}
+
+/// Formats a time as RFC 3339 with the offset of the zone, e.g. `2024-05-01T14:05:09+02:00`, for logs.
+pub fn timestamp(time: SystemTime) -> String {
+    timestamp_in(zone(), time)
+}
+
+fn timestamp_in(zone: &TimeZone, time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .with_timezone(&zone.0)
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn test_zones_switch_to_daylight_saving_time() {
+        let zone = TimeZone::named("Europe/Berlin").unwrap();
+        // 2024-03-31 was the last Sunday of March, clocks went forward at 01:00 UTC
+        assert_eq!(time_of_day_in(&zone, at(1_711_846_799)), "01:59 CET");
+        assert_eq!(time_of_day_in(&zone, at(1_711_846_800)), "03:00 CEST");
+        assert_eq!(
+            timestamp_in(&zone, at(1_714_572_309)),
+            "2024-05-01T16:05:09+02:00"
+        );
+        assert_eq!(
+            timestamp_in(&zone, at(1_704_067_200)),
+            "2024-01-01T01:00:00+01:00"
+        );
+        assert_eq!(zone.to_string(), "Europe/Berlin");
+
+        // Daylight saving time spans the new year south of the equator
+        let zone = TimeZone::named("Australia/Sydney").unwrap();
+        assert_eq!(time_of_day_in(&zone, at(1_704_067_200)), "11:00 AEDT");
+        assert_eq!(time_of_day_in(&zone, at(1_719_792_000)), "10:00 AEST");
+
+        let zone = TimeZone::named("Asia/Kolkata").unwrap();
+        assert_eq!(
+            timestamp_in(&zone, at(1_704_067_200)),
+            "2024-01-01T05:30:00+05:30"
+        );
+        assert_eq!(
+            timestamp_in(&TimeZone::utc(), at(0)),
+            "1970-01-01T00:00:00Z"
+        );
+    }
+
+    #[test]
+    fn test_invalid_names_are_refused() {
+        assert!(TimeZone::named("../../etc/passwd").is_err());
+        assert!(TimeZone::named("/etc/localtime").is_err());
+        assert!(TimeZone::named("Not/AZone").is_err());
+        assert_eq!(TimeZone::named("utc").unwrap(), TimeZone::utc());
+    }
+}
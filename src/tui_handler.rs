@@ -2,306 +2,1681 @@
 //!
 //! It contains functions to handle events and draw the UI.
 
+use std::collections::HashSet;
 use std::io::{self};
-use std::net::TcpStream;
+use std::ops::Range;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use crossterm::style::available_color_count;
 use ratatui::{prelude::*, widgets::*};
 use tui_textarea::{Input, Key, TextArea};
 
-use crate::networking::messaging::{send_message, MessageType};
+use crate::clipboard;
+use crate::config::{Action, Config, KeyBindings, NotifyLevel};
+use crate::export::export_html;
+use crate::highlight::{highlight, parse_code_block};
+use crate::networking::client::{
+    save_received, spawn_file_send, spawn_image_send, user_entry, without_contents, ClientState,
+    Delivery, ACK_TIMEOUT,
+};
+use crate::networking::connection::Connection;
+use crate::networking::crypto;
+use crate::networking::messaging::{
+    is_blank, looks_binary, split_text, MessageType, NoticeKind, PresenceState, MAX_MESSAGE_SIZE,
+    PROTOCOL_VERSION,
+};
+use crate::networking::poll::parse_poll_args;
+use crate::networking::transfer::{file_name_only, format_size};
+use crate::thumbnail::Thumbnails;
 
-/// The maximum length of the name of the user.
-pub const MAX_NAME_LENGTH: usize = 10;
+/// How often to redraw while messages wait for their Ack, so that the ones waiting too long get marked.
+pub const REDRAW_INTERVAL: Duration = Duration::from_secs(1);
+/// Longest time without a redraw otherwise, should anything change without telling.
+pub const IDLE_REDRAW_INTERVAL: Duration = Duration::from_secs(30);
 
-/// Handles the events for the UI. Returns true if the user wants to quit the application.
+/// Smallest terminal the chat is laid out in. Anything smaller only gets a note saying so, as the panes would
+/// have no room left for text.
+const MIN_WIDTH: u16 = 20;
+const MIN_HEIGHT: u16 = 5;
+
+/// Every command with its arguments and what it does, as listed by `/help` and the command hint.
+const COMMANDS: &[(&str, &str, &str)] = &[
+    ("help", "", "Display this message"),
+    ("quit", "", "Quit the chat"),
+    ("debug", "", "Display connection diagnostics"),
+    (
+        "reconnect",
+        "",
+        "Drop the connection to the server and connect again",
+    ),
+    ("join", "<room>", "Switch to another room"),
+    (
+        "users",
+        "",
+        "List the users in the room and how long they have been here",
+    ),
+    (
+        "stats",
+        "",
+        "Show what you sent and received since the chat started",
+    ),
+    ("ignore", "<pseudonym>", "Hide messages from a user"),
+    ("unignore", "<pseudonym>", "Show messages from a user again"),
+    ("ignores", "", "List the users you are ignoring"),
+    ("export", "<file path>", "Save the chat as an HTML file"),
+    ("clear", "", "Clear the chat on your screen, for you only"),
+    ("retry", "", "Send again the messages that failed to send"),
+    (
+        "notify",
+        "all|mentions|off",
+        "Choose which messages ring the bell",
+    ),
+    (
+        "away",
+        "[reason]",
+        "Mark yourself away until you send a message",
+    ),
+    ("dnd", "[reason]", "Mark yourself as do not disturb"),
+    (
+        "status",
+        "[text]",
+        "Set a short status shown next to your name in /users, none to clear it",
+    ),
+    (
+        "poll",
+        "\"question\" <option> <option>...",
+        "Ask the room to vote, quote anything with spaces",
+    ),
+    (
+        "vote",
+        "<poll id> <option>",
+        "Vote in a poll, again to change it",
+    ),
+    (
+        "paste",
+        "<file path>",
+        "Send the text in a file as a message",
+    ),
+    ("clip", "", "Send the text in the clipboard as a message"),
+    (
+        "copy",
+        "",
+        "Copy the last message or code snippet to the clipboard",
+    ),
+    ("file", "<file path>...", "Send the files at the file paths"),
+    (
+        "transfers",
+        "",
+        "List the files being sent and received, with their ids",
+    ),
+    (
+        "cancel",
+        "<transfer id>",
+        "Stop sending or receiving a file",
+    ),
+    ("image", "<file path>", "Send image at file path"),
+    (
+        "accept",
+        "[file name]",
+        "Save a received file held back as possibly unsafe",
+    ),
+];
+
+/// Formats how a command is typed, e.g. `/join <room>`.
+fn command_usage(name: &str, args: &str) -> String {
+    if args.is_empty() {
+        format!("/{}", name)
+    } else {
+        format!("/{} {}", name, args)
+    }
+}
+
+/// Returns the commands starting with what has been typed so far, while a command name is being typed.
+fn command_hints(input: &str) -> Vec<&'static (&'static str, &'static str, &'static str)> {
+    match input.strip_prefix('/') {
+        Some(typed) if !typed.contains(char::is_whitespace) => COMMANDS
+            .iter()
+            .filter(|(name, _, _)| name.starts_with(typed))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Scroll position of the message pane, and how many messages the user has already seen.
+///
+/// While the pane is scrolled to the bottom it follows new messages. Once scrolled up, messages
+/// arriving below the visible part are counted as unread until the user scrolls back down.
+#[derive(Default)]
+pub struct MessageView {
+    pub scroll: u16,
+    /// Largest scroll offset that still shows messages, as of the last draw.
+    max_scroll: u16,
+    /// Number of messages the user has seen.
+    seen: usize,
+}
+
+impl MessageView {
+    fn is_at_bottom(&self) -> bool {
+        self.scroll >= self.max_scroll
+    }
+
+    fn scroll_to_bottom(&mut self) {
+        self.scroll = self.max_scroll;
+    }
+
+    /// Scrolls just enough for `lines` to be shown in a pane `height` lines tall.
+    fn reveal(&mut self, lines: Range<usize>, height: u16) {
+        let start = u16::try_from(lines.start).unwrap_or(u16::MAX);
+        let end = u16::try_from(lines.end).unwrap_or(u16::MAX);
+        if start < self.scroll {
+            self.scroll = start;
+        } else if end > self.scroll + height {
+            self.scroll = (end - height).min(start);
+        }
+    }
+}
+
+/// The emoji picker popup, opened with Ctrl-E and closed with Esc.
+///
+/// Typing narrows the list down by shortcode, arrow keys move the selection and Enter inserts the
+/// selected emoji into the text area.
+#[derive(Default)]
+pub struct EmojiPicker {
+    pub is_open: bool,
+    pub filter: String,
+    pub list_state: ListState,
+}
+
+impl EmojiPicker {
+    /// Returns the emojis whose shortcodes contain the current filter, along with the matched shortcode.
+    fn matches(&self) -> Vec<(&'static str, &'static emojis::Emoji)> {
+        emojis::iter()
+            .filter_map(|emoji| {
+                emoji
+                    .shortcodes()
+                    .find(|shortcode| shortcode.contains(self.filter.as_str()))
+                    .map(|shortcode| (shortcode, emoji))
+            })
+            .collect()
+    }
+
+    fn open(&mut self) {
+        self.is_open = true;
+        self.filter.clear();
+        self.list_state.select(Some(0));
+    }
+
+    fn close(&mut self) {
+        self.is_open = false;
+    }
+
+    /// Handles a key press while the picker is open.
+    fn handle_key(&mut self, key_code: KeyCode, text_area: &mut TextArea) {
+        let match_count = self.matches().len();
+        let selected = self.list_state.selected().unwrap_or(0);
+
+        match key_code {
+            KeyCode::Esc => self.close(),
+            KeyCode::Enter => {
+                if let Some((_, emoji)) = self.matches().get(selected) {
+                    text_area.insert_str(emoji.as_str());
+                }
+                self.close();
+            }
+            KeyCode::Up => {
+                self.list_state.select(Some(selected.saturating_sub(1)));
+            }
+            KeyCode::Down => {
+                self.list_state
+                    .select(Some((selected + 1).min(match_count.saturating_sub(1))));
+            }
+            KeyCode::Backspace => {
+                self.filter.pop();
+                self.list_state.select(Some(0));
+            }
+            KeyCode::Char(c) => {
+                self.filter.push(c);
+                self.list_state.select(Some(0));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// How each kind of line is styled.
+///
+/// Without colors, lines are told apart by modifiers instead, and errors get a `!` in front.
+pub struct Theme {
+    info: Style,
+    server: Style, // Announcements by the server
+    tip: Style,
+    notice: Style, // Users coming, going and changing presence
+    message: Style,
+    error: Style,
+    error_prefix: &'static str,
+    file: Style,
+    faint: Style,
+    highlight: Style,
+    code: Style,       // Code blocks
+    code_colors: bool, // Whether code blocks are highlighted in the colors of `highlight`
+    bar: Style,        // Vote counts of polls
+    input_border: Style,
+    placeholder: Style,
+    cursor: Style,
+    banner: Style,   // Shown while the connection to the server is down
+    selected: Style, // The message highlighted in selection mode
+}
+
+impl Theme {
+    pub fn new(color_enabled: bool) -> Self {
+        if color_enabled {
+            Theme {
+                info: Style::default().fg(Color::Green),
+                server: Style::default()
+                    .fg(Color::Magenta)
+                    .add_modifier(Modifier::BOLD),
+                tip: Style::default().fg(Color::Cyan),
+                notice: Style::default().fg(Color::Yellow),
+                message: Style::default().fg(Color::White),
+                error: Style::default().fg(Color::Red),
+                error_prefix: "",
+                file: Style::default().fg(Color::Blue),
+                faint: Style::default().fg(Color::DarkGray),
+                highlight: Style::default().fg(Color::Cyan),
+                code: Style::default().fg(Color::White).bg(Color::Black),
+                code_colors: true,
+                bar: Style::default().fg(Color::Cyan),
+                input_border: Style::default().fg(Color::Cyan),
+                placeholder: Style::default().fg(Color::DarkGray),
+                cursor: Style::default().add_modifier(Modifier::REVERSED),
+                banner: Style::default()
+                    .fg(Color::White)
+                    .bg(Color::Red)
+                    .add_modifier(Modifier::BOLD),
+                selected: Style::default().add_modifier(Modifier::REVERSED),
+            }
+        } else {
+            Theme {
+                info: Style::default().add_modifier(Modifier::ITALIC),
+                server: Style::default().add_modifier(Modifier::BOLD),
+                tip: Style::default().add_modifier(Modifier::ITALIC),
+                notice: Style::default().add_modifier(Modifier::DIM),
+                message: Style::default(),
+                error: Style::default().add_modifier(Modifier::BOLD),
+                error_prefix: "! ",
+                file: Style::default().add_modifier(Modifier::UNDERLINED),
+                faint: Style::default().add_modifier(Modifier::DIM),
+                highlight: Style::default().add_modifier(Modifier::BOLD),
+                code: Style::default(),
+                code_colors: false,
+                bar: Style::default(),
+                input_border: Style::default().add_modifier(Modifier::BOLD),
+                placeholder: Style::default().add_modifier(Modifier::DIM),
+                cursor: Style::default().add_modifier(Modifier::REVERSED),
+                banner: Style::default().add_modifier(Modifier::REVERSED | Modifier::BOLD),
+                selected: Style::default().add_modifier(Modifier::REVERSED),
+            }
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme::new(true)
+    }
+}
+
+/// Returns false when `NO_COLOR` is set or the terminal can't show at least 8 colors.
+pub fn color_enabled() -> bool {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    !no_color && std::env::var("TERM").as_deref() != Ok("dumb") && available_color_count() >= 8
+}
+
+/// State of the user interface that lives across frames.
+#[derive(Default)]
+pub struct UiState {
+    pub view: MessageView,
+    pub emoji_picker: EmojiPicker,
+    pub thumbnails: Thumbnails,
+    pub theme: Theme,
+    pub key_bindings: KeyBindings,
+    /// Pseudonyms whose messages are hidden, for this session only.
+    pub ignored: HashSet<String>,
+    /// Our pseudonym, shown in the title of the input box.
+    pub pseudonym: String,
+    /// Index of the message highlighted in selection mode, None outside of it.
+    pub selected: Option<usize>,
+}
+
+/// Handles an input event for the UI. Returns true if the user wants to quit the application.
 pub fn handle_events(
+    event: Event,
     message_vector: Arc<Mutex<Vec<MessageType>>>,
     text_area: &mut TextArea,
-    stream: &mut TcpStream,
-    scroll: &mut u16,
+    ui_state: &mut UiState,
+    client_state: &ClientState,
     pseudonym: String,
 ) -> io::Result<bool> {
-    let mut message_vector = message_vector.lock().unwrap();
-    if event::poll(std::time::Duration::from_millis(50))? {
-        if let Event::Key(key) = event::read()? {
-            if key.kind == event::KeyEventKind::Press {
-                match key.code {
+    let UiState {
+        view,
+        emoji_picker,
+        key_bindings,
+        ignored,
+        selected,
+        ..
+    } = ui_state;
+    let shared_message_vector = message_vector;
+    let mut message_vector = shared_message_vector.lock().unwrap();
+    if let Event::Key(key) = event {
+        if key.kind == event::KeyEventKind::Press {
+            if emoji_picker.is_open {
+                emoji_picker.handle_key(key.code, text_area);
+                return Ok(false);
+            }
+            if let Some(index) = *selected {
+                *selected = match key.code {
+                    KeyCode::Up | KeyCode::Char('k') => {
+                        move_selection(&message_vector, ignored, index, true)
+                    }
+                    KeyCode::Down | KeyCode::Char('j') => {
+                        move_selection(&message_vector, ignored, index, false)
+                    }
                     KeyCode::Enter => {
-                        let message = text_area.lines()[0].clone();
-
-                        let message = message.trim().to_string();
-                        let message = replace_keywords_with_emojis(&message);
-
-                        if let Some(prefix) = message.strip_prefix('/') {
-                            let args: Vec<&str> = prefix.split_whitespace().collect();
-                            match args[0] {
-                                "help" => {
-                                    message_vector.push(MessageType::Info("".to_string()));
-                                    message_vector.push(MessageType::Info(format!(
-                                        "Running program version {}, Created by {}",
-                                        env!("CARGO_PKG_VERSION"),
-                                        env!("CARGO_PKG_AUTHORS")
-                                    )));
-
-                                    message_vector.push(MessageType::Info("Commands:".to_string()));
-                                    message_vector.push(MessageType::Info(
-                                        "/help - Display this message".to_string(),
-                                    ));
-                                    message_vector.push(MessageType::Info(
-                                        "/quit - Quit the chat".to_string(),
-                                    ));
-                                    message_vector.push(MessageType::Info(
-                                        "/file <file path> - Send file at file path".to_string(),
-                                    ));
-                                    message_vector.push(MessageType::Info(
-                                        "/image <file path> - Send image at file path".to_string(),
-                                    ));
-
-                                    message_vector.push(MessageType::Info("".to_string()));
-                                    message_vector.push(MessageType::Info(
-                                        "To put emojis use the ':description:' format, e.g. use :smile: to send 😊"
-                                            .to_string(),
-                                    ));
-
-                                    message_vector.push(MessageType::Info("".to_string()));
-                                }
-                                "quit" => {
-                                    send_message(
-                                        stream,
-                                        &MessageType::Leave(
-                                            stream.local_addr().unwrap().to_string(),
-                                        ),
-                                    )?;
-                                    return Ok(true);
-                                }
-                                "file" => {
-                                    if let Some(file_path) = args.get(1) {
-                                        match std::fs::read(file_path) {
-                                            Ok(file_contents) => {
-                                                message_vector.push(MessageType::Info(format!(
-                                                    "Sending file : {}",
-                                                    args[1]
-                                                )));
-
-                                                send_message(
-                                                    stream,
-                                                    &MessageType::File(
-                                                        file_path.to_string(),
-                                                        file_contents,
-                                                    ),
-                                                )?;
-                                            }
-                                            Err(e) => {
-                                                // Handle file read error
-                                                message_vector.push(MessageType::Error(format!(
-                                                    "Failed to read file: {}",
-                                                    e
-                                                )));
-                                            }
-                                        }
-                                    } else {
-                                        // Handle case where file path is not provided
-                                        message_vector.push(MessageType::Error(
-                                            "File path not provided".to_string(),
-                                        ));
-                                    }
+                        let notice =
+                            match copyable_text(&message_vector[index]).map(clipboard::write) {
+                                Some(Ok(())) => {
+                                    MessageType::Info("Copied to the clipboard".to_string())
                                 }
-                                "image" => {
-                                    if let Some(file_path) = args.get(1) {
-                                        match std::fs::read(file_path) {
-                                            Ok(file_contents) => {
-                                                message_vector.push(MessageType::Info(format!(
-                                                    "Sending image : {}",
-                                                    args[1]
-                                                )));
-
-                                                send_message(
-                                                    stream,
-                                                    &MessageType::Image(
-                                                        file_path.to_string(),
-                                                        file_contents,
-                                                    ),
-                                                )?;
-                                            }
-                                            Err(e) => {
-                                                // Handle file read error
-                                                message_vector.push(MessageType::Error(format!(
-                                                    "Failed to read image: {}",
-                                                    e
-                                                )));
-                                            }
-                                        }
-                                    } else {
-                                        // Handle case where file path is not provided
-                                        message_vector.push(MessageType::Error(
-                                            "Image path not provided".to_string(),
-                                        ));
-                                    }
-                                }
-                                _ => {
-                                    message_vector.push(MessageType::Error(
-                                        "Invalid command. Type /help for a list of commands"
-                                            .to_string(),
-                                    ));
-                                }
-                            }
-
-                            send_message(stream, &MessageType::Command(prefix.to_string()))?;
-                            message_vector.push(MessageType::Command(prefix.to_string()));
+                                Some(Err(e)) => MessageType::Error(e),
+                                None => MessageType::Error("No message to copy".to_string()),
+                            };
+                        message_vector.push(notice);
+                        None
+                    }
+                    KeyCode::Esc => None,
+                    _ => Some(index),
+                };
+                if selected.is_none() {
+                    view.scroll_to_bottom();
+                }
+                return Ok(false);
+            }
+            let mut stream = client_state.stream.lock().unwrap();
+            let stream = &mut *stream;
 
+            // Spectators have no input box, they can only scroll and quit
+            let action = key_bindings.action(&key);
+            if client_state.spectator
+                && !matches!(
+                    action,
+                    Some(
+                        Action::Quit
+                            | Action::Select
+                            | Action::ScrollUp
+                            | Action::ScrollDown
+                            | Action::ScrollToBottom
+                    )
+                )
+            {
+                return Ok(false);
+            }
+            match action {
+                Some(Action::Send) => {
+                    let input = text_area.lines().join("\n");
+                    match submit_input(
+                        &input,
+                        &shared_message_vector,
+                        &mut message_vector,
+                        stream,
+                        ignored,
+                        client_state,
+                        &pseudonym,
+                    )? {
+                        Submitted::Quit => return Ok(true),
+                        Submitted::Kept => {}
+                        Submitted::Done => {
+                            view.scroll_to_bottom();
                             while !text_area.is_empty() {
                                 text_area.delete_char();
                             }
-
-                            return Ok(false);
-                        }
-
-                        if !message.is_empty() {
-                            send_message(stream, &MessageType::Message(pseudonym, message))?;
-                            *scroll = scroll.saturating_add(1);
                         }
-
+                    }
+                }
+                // Raw mode swallows SIGINT, so Ctrl-C is bound to quitting by default
+                Some(Action::Quit) => {
+                    // Quitting goes ahead even when the connection is already gone
+                    if let Ok(addr) = stream.local_addr() {
+                        let _ = client_state.stats.send(stream, &MessageType::Leave(addr));
+                    }
+                    return Ok(true);
+                }
+                Some(Action::EmojiPicker) => {
+                    emoji_picker.open();
+                }
+                Some(Action::Newline) => {
+                    text_area.insert_newline();
+                }
+                Some(Action::Retry) => {
+                    let retried = retry_failed(&mut message_vector, stream, client_state);
+                    // The text left in the input box by the failure isn't needed anymore
+                    let input = text_area.lines().join("\n");
+                    let text =
+                        parse_code_block(&input).map_or(input.trim().to_string(), |(_, body)| body);
+                    if retried.contains(&text) {
                         while !text_area.is_empty() {
                             text_area.delete_char();
                         }
                     }
-                    KeyCode::Up => {
-                        *scroll = scroll.saturating_sub(1);
-                    }
-                    KeyCode::Down => {
-                        *scroll = scroll.saturating_add(1);
+                }
+                Some(Action::ScrollUp) => {
+                    view.scroll = view.scroll.saturating_sub(1);
+                }
+                Some(Action::ScrollDown) => {
+                    if !view.is_at_bottom() {
+                        view.scroll += 1;
                     }
-                    ref key_code => {
-                        // Handle other keys
-                        let input = Input {
-                            key: Key::from(*key_code),
-                            ctrl: key.modifiers.contains(KeyModifiers::CONTROL),
-                            shift: key.modifiers.contains(KeyModifiers::SHIFT),
-                            alt: key.modifiers.contains(KeyModifiers::ALT),
-                        };
-                        text_area.input(input);
+                }
+                Some(Action::ScrollToBottom) => {
+                    view.scroll_to_bottom();
+                }
+                Some(Action::Select) => {
+                    *selected = (0..message_vector.len())
+                        .rev()
+                        .find(|&index| is_selectable(&message_vector[index], ignored));
+                    if selected.is_none() {
+                        message_vector.push(MessageType::Error("No message to select".to_string()));
                     }
                 }
+                None => {
+                    // Handle other keys
+                    let input = Input {
+                        key: Key::from(key.code),
+                        ctrl: key.modifiers.contains(KeyModifiers::CONTROL),
+                        shift: key.modifiers.contains(KeyModifiers::SHIFT),
+                        alt: key.modifiers.contains(KeyModifiers::ALT),
+                    };
+                    text_area.input(input);
+                }
             }
         }
     }
     Ok(false)
 }
 
+/// What became of the input submitted by the user, which decides whether the input box is cleared.
+#[derive(Debug, PartialEq)]
+pub enum Submitted {
+    Done, // Sent or run, so the input box is cleared
+    Kept, // An invalid command, left in the input box to be fixed
+    Quit,
+}
+
+/// The text of a chat message or code snippet, what /copy puts in the clipboard
+pub fn copyable_text(message: &MessageType) -> Option<&str> {
+    match message {
+        MessageType::Message(_, text, ..) => Some(text),
+        MessageType::Code { body, .. } => Some(body),
+        _ => None,
+    }
+}
+
+/// Whether a message can be highlighted in selection mode, those that can be acted on.
+fn is_selectable(message: &MessageType, ignored: &HashSet<String>) -> bool {
+    match message {
+        MessageType::Message(sender, ..) | MessageType::Code { sender, .. } => {
+            !ignored.contains(sender)
+        }
+        _ => false,
+    }
+}
+
+/// The selectable message above or below `from`, or `from` itself at either end.
+fn move_selection(
+    messages: &[MessageType],
+    ignored: &HashSet<String>,
+    from: usize,
+    up: bool,
+) -> Option<usize> {
+    let found = if up {
+        (0..from)
+            .rev()
+            .find(|&index| is_selectable(&messages[index], ignored))
+    } else {
+        (from + 1..messages.len()).find(|&index| is_selectable(&messages[index], ignored))
+    };
+    Some(found.unwrap_or(from))
+}
+
+/// Shows why a command can't run and keeps it in the input box.
+fn invalid(message_vector: &mut Vec<MessageType>, error: String) -> io::Result<Submitted> {
+    message_vector.push(MessageType::Error(error));
+    Ok(Submitted::Kept)
+}
+
+/// Runs a command or sends a chat message typed by the user.
+///
+/// A failed send returns the error, which also leaves the input box as it is.
+///
+/// `message_vector` is the locked contents of `shared_message_vector`, which is handed to the commands that
+/// keep adding messages in the background.
+pub fn submit_input(
+    input: &str,
+    shared_message_vector: &Arc<Mutex<Vec<MessageType>>>,
+    message_vector: &mut Vec<MessageType>,
+    stream: &mut Connection,
+    ignored: &mut HashSet<String>,
+    client_state: &ClientState,
+    pseudonym: &str,
+) -> io::Result<Submitted> {
+    // Code is sent as it is, without looking for emojis or commands in it
+    if let Some((lang, body)) = parse_code_block(input) {
+        if body.len() > crypto::max_text_length() {
+            return invalid(
+                message_vector,
+                format!(
+                    "Code blocks can be up to {} long, use /file to send longer ones",
+                    format_size(crypto::max_text_length())
+                ),
+            );
+        }
+        if !confirm_binary(input, message_vector, client_state) {
+            return Ok(Submitted::Kept);
+        }
+        send_code(lang, body, message_vector, stream, client_state, pseudonym)?;
+        return Ok(Submitted::Done);
+    }
+
+    let message = input.trim().to_string();
+    let (message, unknown_emojis) = replace_keywords_with_emojis(&message);
+
+    if let Some(prefix) = message.strip_prefix('/') {
+        let args: Vec<&str> = prefix.split_whitespace().collect();
+        match args.first().copied().unwrap_or_default() {
+            "help" => {
+                message_vector.push(MessageType::Info("".to_string()));
+                message_vector.push(MessageType::Info(format!(
+                    "Running program version {}, Created by {}",
+                    env!("CARGO_PKG_VERSION"),
+                    env!("CARGO_PKG_AUTHORS")
+                )));
+
+                message_vector.push(MessageType::Info("Commands:".to_string()));
+                for (name, args, description) in COMMANDS {
+                    message_vector.push(MessageType::Info(format!(
+                        "{} - {}",
+                        command_usage(name, args),
+                        description
+                    )));
+                }
+
+                message_vector.push(MessageType::Info("".to_string()));
+                message_vector.push(MessageType::Info(
+                    "To put emojis use the ':description:' format, e.g. use :smile: to send 😊"
+                        .to_string(),
+                ));
+                message_vector.push(MessageType::Info(
+                    "Press Ctrl-E to pick an emoji from a list".to_string(),
+                ));
+                message_vector.push(MessageType::Info(
+                    "Share code between ``` fences, e.g. ```rust on the first line. Alt-Enter starts a new line"
+                        .to_string(),
+                ));
+                message_vector.push(MessageType::Info(
+                    "Use the arrow keys to scroll, End jumps to the newest message".to_string(),
+                ));
+
+                message_vector.push(MessageType::Info("".to_string()));
+            }
+            "quit" => {
+                if let Ok(addr) = stream.local_addr() {
+                    let _ = client_state.stats.send(stream, &MessageType::Leave(addr));
+                }
+                return Ok(Submitted::Quit);
+            }
+            "export" => {
+                if let Some(file_path) = args.get(1) {
+                    match std::fs::write(
+                        file_path,
+                        export_html(message_vector, client_state.max_name_length),
+                    ) {
+                        Ok(_) => {
+                            message_vector.push(MessageType::Info(format!(
+                                "Exported chat to: {}",
+                                file_path
+                            )));
+                        }
+                        Err(e) => {
+                            return invalid(
+                                message_vector,
+                                format!("Failed to export chat: {}", e),
+                            );
+                        }
+                    }
+                } else {
+                    return invalid(message_vector, "Export path not provided".to_string());
+                }
+            }
+            "retry" => {
+                retry_failed(message_vector, stream, client_state);
+            }
+            "clear" => {
+                client_state.clear_messages(message_vector);
+                message_vector.push(MessageType::Info("Chat cleared".to_string()));
+                // Not even the command itself is echoed
+                return Ok(Submitted::Done);
+            }
+            "notify" => match args.get(1).map(|level| level.parse::<NotifyLevel>()) {
+                Some(Ok(level)) => {
+                    *client_state.notify.lock().unwrap() = level;
+                    let saved = match &client_state.config_path {
+                        Some(path) => Config::save_setting(path, "notify", &level.to_string()),
+                        None => Ok(()),
+                    };
+                    match saved {
+                        Ok(_) => message_vector
+                            .push(MessageType::Info(format!("Notifications: {}", level))),
+                        Err(e) => message_vector.push(MessageType::Error(format!(
+                            "Notifications: {}, but failed to save the setting: {}",
+                            level, e
+                        ))),
+                    }
+                }
+                Some(Err(e)) => return invalid(message_vector, e),
+                None => message_vector.push(MessageType::Info(format!(
+                    "Notifications: {}",
+                    client_state.notify.lock().unwrap()
+                ))),
+            },
+            "reconnect" => {
+                if !client_state.reader_alive.load(Ordering::SeqCst) {
+                    return invalid(message_vector, "Already reconnecting".to_string());
+                }
+                // The thread reading from the server sees the connection close and connects again
+                client_state
+                    .reconnect_requested
+                    .store(true, Ordering::SeqCst);
+                stream.shutdown()?;
+                message_vector.push(MessageType::Info(
+                    "Reconnecting to the server...".to_string(),
+                ));
+                return Ok(Submitted::Done);
+            }
+            "stats" => {
+                for line in client_state.stats.lines() {
+                    message_vector.push(MessageType::Info(line));
+                }
+            }
+            "users" => {
+                client_state.users_requested.store(true, Ordering::SeqCst);
+                client_state.stats.send(stream, &MessageType::ListUsers)?;
+            }
+            "ignore" | "unignore" => {
+                if let Some(nick) = args.get(1) {
+                    let info = if args[0] == "ignore" {
+                        ignored.insert(nick.to_string());
+                        format!("Ignoring messages from {}", nick)
+                    } else if ignored.remove(*nick) {
+                        format!("No longer ignoring {}", nick)
+                    } else {
+                        format!("{} was not being ignored", nick)
+                    };
+                    message_vector.push(MessageType::Info(info));
+                } else {
+                    return invalid(message_vector, "Pseudonym not provided".to_string());
+                }
+            }
+            "ignores" => {
+                let mut nicks: Vec<&str> = ignored.iter().map(String::as_str).collect();
+                nicks.sort_unstable();
+                let info = if nicks.is_empty() {
+                    "You are not ignoring anyone".to_string()
+                } else {
+                    format!("Ignoring: {}", nicks.join(", "))
+                };
+                message_vector.push(MessageType::Info(info));
+            }
+            "away" | "dnd" => {
+                let reason = args[1..].join(" ");
+                let reason = (!reason.is_empty()).then_some(reason);
+                let state = if args[0] == "away" {
+                    PresenceState::Away(reason)
+                } else {
+                    PresenceState::DoNotDisturb(reason)
+                };
+
+                client_state.stats.send(
+                    stream,
+                    &MessageType::Presence {
+                        nick: String::new(),
+                        state,
+                    },
+                )?;
+            }
+            "status" => {
+                client_state.stats.send(
+                    stream,
+                    &MessageType::Status {
+                        nick: String::new(),
+                        text: args[1..].join(" "),
+                    },
+                )?;
+            }
+            "poll" => {
+                let poll_args = prefix
+                    .split_once(char::is_whitespace)
+                    .map_or("", |(_, rest)| rest);
+                match parse_poll_args(poll_args) {
+                    Ok((question, options)) => client_state.stats.send(
+                        stream,
+                        &MessageType::Poll {
+                            id: 0,
+                            question,
+                            options,
+                        },
+                    )?,
+                    Err(e) => return invalid(message_vector, e),
+                }
+            }
+            "vote" => match (
+                args.get(1)
+                    .and_then(|id| id.trim_start_matches('#').parse::<u64>().ok()),
+                args.get(2).and_then(|option| option.parse::<usize>().ok()),
+            ) {
+                (Some(id), Some(option)) if option > 0 => client_state.stats.send(
+                    stream,
+                    &MessageType::Vote {
+                        id,
+                        option: option - 1,
+                    },
+                )?,
+                _ => {
+                    return invalid(
+                        message_vector,
+                        "Usage: /vote <poll id> <option number>".to_string(),
+                    )
+                }
+            },
+            "join" => {
+                if let Some(room) = args.get(1) {
+                    client_state
+                        .stats
+                        .send(stream, &MessageType::JoinRoom(room.to_string()))?;
+                    message_vector
+                        .push(MessageType::Info(format!("You are now in room: {}", room)));
+                } else {
+                    return invalid(message_vector, "Room name not provided".to_string());
+                }
+            }
+            "debug" => {
+                let local_addr = match stream.local_addr() {
+                    Ok(addr) => addr.to_string(),
+                    Err(e) => format!("unavailable ({})", e),
+                };
+                let peer_addr = match stream.peer_endpoint() {
+                    Ok(addr) => addr.to_string(),
+                    Err(e) => format!("unavailable ({})", e),
+                };
+                let buffered_messages = message_vector.len();
+                let reader_state = if client_state.reader_alive.load(Ordering::SeqCst) {
+                    "alive"
+                } else {
+                    "stopped"
+                };
+
+                message_vector.push(MessageType::Info("".to_string()));
+                for line in [
+                    format!("Local address: {}", local_addr),
+                    format!("Server address: {}", peer_addr),
+                    format!("Protocol version: {}", PROTOCOL_VERSION),
+                    format!("Max message size: {} bytes", MAX_MESSAGE_SIZE),
+                    format!("Buffered messages: {}", buffered_messages),
+                    format!("Reader thread: {}", reader_state),
+                ] {
+                    message_vector.push(MessageType::Info(line));
+                }
+                message_vector.push(MessageType::Info("".to_string()));
+            }
+            "file" => {
+                if args.len() > 1 {
+                    // Each file gets a line of its own for its progress
+                    let files = args[1..]
+                        .iter()
+                        .map(|file_path| {
+                            message_vector.push(MessageType::Info(format!(
+                                "Sending {}: waiting",
+                                file_name_only(file_path)
+                            )));
+                            let progress_line = client_state.trimmed.load(Ordering::SeqCst)
+                                + message_vector.len()
+                                - 1;
+                            (file_path.to_string(), progress_line)
+                        })
+                        .collect();
+                    spawn_file_send(client_state, Arc::clone(shared_message_vector), files);
+                } else {
+                    // Handle case where file path is not provided
+                    return invalid(message_vector, "File path not provided".to_string());
+                }
+            }
+            "transfers" => {
+                let lines = client_state.transfers.lock().unwrap().lines();
+                if lines.is_empty() {
+                    message_vector.push(MessageType::Info(
+                        "No files are being transferred".to_string(),
+                    ));
+                }
+                for line in lines {
+                    message_vector.push(MessageType::Info(line));
+                }
+            }
+            "cancel" => {
+                let Some(id) = args.get(1) else {
+                    return invalid(
+                        message_vector,
+                        "Transfer id not provided, see /transfers".to_string(),
+                    );
+                };
+                let Ok(transfer_id) = u64::from_str_radix(id, 16) else {
+                    return invalid(
+                        message_vector,
+                        format!("{} is not a transfer id, see /transfers", id),
+                    );
+                };
+                let cancelled = client_state.transfers.lock().unwrap().cancel(transfer_id);
+                let info = match cancelled {
+                    Some((file_name, true)) => {
+                        client_state
+                            .stats
+                            .send(stream, &MessageType::FileCancel { transfer_id })?;
+                        format!("Cancelled sending {}", file_name_only(&file_name))
+                    }
+                    Some((file_name, false)) => {
+                        format!("Cancelled receiving {}", file_name_only(&file_name))
+                    }
+                    None => {
+                        return invalid(message_vector, format!("No transfer {} in progress", id))
+                    }
+                };
+                message_vector.push(MessageType::Info(info));
+            }
+            "paste" => {
+                let Some(file_path) = args.get(1) else {
+                    return invalid(message_vector, "File path not provided".to_string());
+                };
+                let text = match std::fs::read(file_path).map(String::from_utf8) {
+                    Ok(Ok(text)) => text,
+                    Ok(Err(_)) => {
+                        return invalid(
+                            message_vector,
+                            format!("{} is not UTF-8 text, use /file to send it", file_path),
+                        );
+                    }
+                    Err(e) => {
+                        return invalid(message_vector, format!("Failed to read file: {}", e));
+                    }
+                };
+                if is_blank(&text) {
+                    return invalid(message_vector, format!("{} has no text to send", file_path));
+                }
+                send_text(&text, message_vector, stream, client_state, pseudonym)?;
+            }
+            "clip" => {
+                let text = match clipboard::read() {
+                    Ok(text) => text,
+                    Err(e) => return invalid(message_vector, e),
+                };
+                if is_blank(&text) {
+                    return invalid(
+                        message_vector,
+                        "The clipboard has no text to send".to_string(),
+                    );
+                }
+                send_text(&text, message_vector, stream, client_state, pseudonym)?;
+            }
+            "copy" => {
+                let Some(text) = message_vector.iter().rev().find_map(copyable_text) else {
+                    return invalid(message_vector, "No message to copy".to_string());
+                };
+                let notice = match clipboard::write(text) {
+                    Ok(()) => MessageType::Info("Copied to the clipboard".to_string()),
+                    Err(e) => MessageType::Error(e),
+                };
+                message_vector.push(notice);
+                return Ok(Submitted::Done);
+            }
+            "accept" => {
+                let mut pending_files = client_state.pending_files.lock().unwrap();
+                let index = match args.get(1) {
+                    Some(name) => pending_files.iter().position(|file| match file {
+                        MessageType::File(file_name, _, _) => file_name_only(file_name) == *name,
+                        _ => false,
+                    }),
+                    None => pending_files.len().checked_sub(1),
+                };
+
+                match index {
+                    Some(index) => {
+                        message_vector.push(save_received(pending_files.remove(index)));
+                    }
+                    None => {
+                        return invalid(
+                            message_vector,
+                            "No such file waiting to be accepted".to_string(),
+                        );
+                    }
+                }
+            }
+            "image" => {
+                if let Some(file_path) = args.get(1) {
+                    message_vector.push(MessageType::Info(format!("Sending image : {}", args[1])));
+                    spawn_image_send(
+                        client_state,
+                        Arc::clone(shared_message_vector),
+                        file_path.to_string(),
+                    );
+                } else {
+                    // Handle case where file path is not provided
+                    return invalid(message_vector, "Image path not provided".to_string());
+                }
+            }
+            _ => {
+                return invalid(
+                    message_vector,
+                    "Invalid command. Type /help for a list of commands".to_string(),
+                );
+            }
+        }
+
+        client_state
+            .stats
+            .send(stream, &MessageType::Command(prefix.to_string()))?;
+        message_vector.push(MessageType::Command(prefix.to_string()));
+
+        return Ok(Submitted::Done);
+    }
+
+    if !confirm_binary(&message, message_vector, client_state) {
+        return Ok(Submitted::Kept);
+    }
+
+    // Stray keys can make messages that would show as blank lines
+    if !is_blank(&message) {
+        send_text(&message, message_vector, stream, client_state, pseudonym)?;
+
+        for (shortcode, suggestion) in unknown_emojis {
+            if let Some(suggestion) = suggestion {
+                message_vector.push(MessageType::Info(format!(
+                    "No emoji named :{}:, did you mean :{}:?",
+                    shortcode, suggestion
+                )));
+            }
+        }
+    }
+
+    Ok(Submitted::Done)
+}
+
+/// Whether text can be sent. Pasting a binary file by mistake would fill the room's screens with garbage, so
+/// text that looks like it is held back until it is submitted again.
+fn confirm_binary(
+    text: &str,
+    message_vector: &mut Vec<MessageType>,
+    client_state: &ClientState,
+) -> bool {
+    let mut held_back = client_state.held_back.lock().unwrap();
+    let confirmed = held_back.take().as_deref() == Some(text);
+    if looks_binary(text) && !confirmed {
+        *held_back = Some(text.to_string());
+        message_vector.push(MessageType::Error(
+            "This looks like binary data, use /file to send it instead, or press Enter again to send it anyway"
+                .to_string(),
+        ));
+        return false;
+    }
+    true
+}
+
+/// Sends a code block, or queues it while the connection is down, and shows it.
+fn send_code(
+    lang: String,
+    body: String,
+    message_vector: &mut Vec<MessageType>,
+    stream: &mut Connection,
+    client_state: &ClientState,
+    pseudonym: &str,
+) -> io::Result<()> {
+    if client_state.spectator {
+        message_vector.push(MessageType::Error(
+            "Spectators can't send messages".to_string(),
+        ));
+        return Ok(());
+    }
+
+    let nonce = rand::random();
+    let code = |sender: &str| MessageType::Code {
+        sender: sender.to_string(),
+        lang: lang.clone(),
+        body: body.clone(),
+        nonce,
+        signature: vec![],
+    };
+    // The server puts our registered pseudonym on it
+    let sent = client_state.send_or_queue(stream, code(""));
+    message_vector.push(code(pseudonym));
+    match sent {
+        Ok(Some(dropped)) => {
+            message_vector.push(MessageType::Error(format!(
+                "Too many messages waiting for the connection, dropped: {}",
+                copyable_text(&dropped).unwrap_or_default()
+            )));
+            Ok(())
+        }
+        Ok(None) => Ok(()),
+        Err(e) => Err(io::Error::other(format!(
+            "Failed to send code: {}. Use /retry to send it again",
+            e
+        ))),
+    }
+}
+
+/// Sends text as chat messages, split into as many as it takes, and shows them.
+fn send_text(
+    text: &str,
+    message_vector: &mut Vec<MessageType>,
+    stream: &mut Connection,
+    client_state: &ClientState,
+    pseudonym: &str,
+) -> io::Result<()> {
+    if client_state.spectator {
+        message_vector.push(MessageType::Error(
+            "Spectators can't send messages".to_string(),
+        ));
+        return Ok(());
+    }
+
+    // Text too long for a single message is sent as several, one after the other
+    let parts = split_text(text, crypto::max_text_length());
+    if parts.len() > 1 {
+        message_vector.push(MessageType::Info(format!(
+            "Your message is too long to send at once, sending it in {} parts",
+            parts.len()
+        )));
+    }
+
+    for part in parts {
+        // The server puts our registered pseudonym on the message
+        let nonce = rand::random();
+        let outgoing = MessageType::Message(String::new(), part.to_string(), nonce, vec![]);
+        let sent = client_state.send_or_queue(stream, outgoing);
+        message_vector.push(MessageType::Message(
+            pseudonym.to_string(),
+            part.to_string(),
+            nonce,
+            vec![],
+        ));
+        match sent {
+            Ok(Some(dropped)) => {
+                message_vector.push(MessageType::Error(format!(
+                    "Too many messages waiting for the connection, dropped: {}",
+                    copyable_text(&dropped).unwrap_or_default()
+                )));
+            }
+            Ok(_) => {}
+            // Failing leaves the message in the input box, and marked as not sent on screen
+            Err(e) => {
+                return Err(io::Error::other(format!(
+                    "Failed to send message: {}. Use /retry to send it again",
+                    e
+                )))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends again the messages that failed to send and tells the user how many were, returning their texts if
+/// they all were.
+fn retry_failed(
+    message_vector: &mut Vec<MessageType>,
+    stream: &mut Connection,
+    client_state: &ClientState,
+) -> Vec<String> {
+    let deliveries = client_state.deliveries.lock().unwrap();
+    let failed: Vec<String> = message_vector
+        .iter()
+        .filter_map(|message| match message {
+            MessageType::Message(_, text, nonce, _)
+            | MessageType::Code {
+                body: text, nonce, ..
+            } if deliveries.get(nonce) == Some(&Delivery::Failed) => Some(text.clone()),
+            _ => None,
+        })
+        .collect();
+    drop(deliveries);
+
+    match client_state.retry_failed(stream, message_vector) {
+        Ok(0) => message_vector.push(MessageType::Info("No messages to send again".to_string())),
+        Ok(retried) => message_vector.push(MessageType::Info(format!(
+            "Sent {} message(s) again",
+            retried
+        ))),
+        Err(e) => {
+            message_vector.push(MessageType::Error(format!("Failed to send again: {}", e)));
+            return vec![];
+        }
+    }
+    failed
+}
+
 /// Responsible for drawing the UI. Interfaces with the message vector of the screen.
 pub fn ui(
     frame: &mut Frame,
     message_vector: Arc<Mutex<Vec<MessageType>>>,
     text_area: &mut TextArea,
-    scroll: &mut u16,
+    ui_state: &mut UiState,
+    client_state: &ClientState,
 ) {
+    let UiState {
+        view,
+        emoji_picker,
+        thumbnails,
+        ignored,
+        theme,
+        pseudonym,
+        selected,
+        ..
+    } = ui_state;
+    let area = frame.size();
+    if area.width < MIN_WIDTH || area.height < MIN_HEIGHT {
+        frame.render_widget(
+            Paragraph::new("Terminal too small")
+                .style(theme.info)
+                .alignment(Alignment::Center)
+                .wrap(Wrap { trim: true }),
+            area,
+        );
+        return;
+    }
     // Lock the Mutex and get a reference to the Vec<Message>
-    let messages = message_vector.lock().unwrap();
+    let mut messages = message_vector.lock().unwrap();
+    let trimmed = client_state.trim_scrollback(&mut messages);
+    view.seen = view.seen.saturating_sub(trimmed);
+    view.scroll = view
+        .scroll
+        .saturating_sub(u16::try_from(trimmed).unwrap_or(u16::MAX));
+    *selected = selected
+        .and_then(|index| index.checked_sub(trimmed))
+        .filter(|&index| {
+            messages
+                .get(index)
+                .is_some_and(|message| is_selectable(message, ignored))
+        });
+    let deliveries = client_state.deliveries.lock().unwrap();
 
     // Create a new Vec and append each Message to it
     let mut message_lines = vec![];
-    for message in messages.iter() {
+    let mut drawn_images = vec![];
+    // The sender and text of the last message line, and how many times in a row it was sent
+    let mut run: Option<(&String, &String, usize)> = None;
+    // The lines of the selected message, from where it starts to where the next one does
+    let mut selected_start = None;
+    let mut selected_end = None;
+    for (index, message) in messages.iter().enumerate() {
+        if selected.is_some_and(|selected| selected + 1 == index) {
+            selected_end = Some(message_lines.len());
+        }
+        if *selected == Some(index) {
+            selected_start = Some(message_lines.len());
+        }
+        if !matches!(message, MessageType::Message(..)) {
+            run = None;
+        }
+        let unverified = client_state.is_unverified(message);
         let span = match message {
-            MessageType::Info(info) => {
-                Span::styled(info.clone(), Style::default().fg(Color::Green))
+            MessageType::Info(info) => Span::styled(info.clone(), theme.info),
+            MessageType::Notice(kind, text) => {
+                let style = match kind {
+                    NoticeKind::Server => theme.server,
+                    NoticeKind::Tip => theme.tip,
+                    NoticeKind::Info => theme.info,
+                };
+                Span::styled(format!("{}{}", kind.prefix(), text), style)
             }
             MessageType::Leave(leave) => {
                 let formatted_leave = format!("{} has left the chat", leave);
-                Span::styled(formatted_leave, Style::default().fg(Color::Yellow))
-            }
-            MessageType::Message(source, message) => {
-                let formatted_message =
-                    format!("{:^width$}: {}", source, message, width = MAX_NAME_LENGTH);
-                Span::styled(formatted_message, Style::default().fg(Color::White))
+                Span::styled(formatted_leave, theme.notice)
             }
-            MessageType::Error(error) => {
-                Span::styled(error.clone(), Style::default().fg(Color::Red))
-            }
-            MessageType::File(file_name, file_contents) => {
-                // Extract the file name, ignoring any path components
-                let file_name_only = std::path::Path::new(&file_name)
-                    .file_name()
-                    .and_then(|name| name.to_str())
-                    .unwrap_or("downloaded_file");
-
-                // Attempt to get the current directory
-                match std::env::current_dir() {
-                    Ok(current_dir) => {
-                        let full_path = current_dir.join(file_name_only);
-
-                        // Attempt to write the file_contents to the file in the current directory
-                        match std::fs::write(&full_path, file_contents) {
-                            Ok(_) => {
-                                let formatted_file = format!("Received file: {}", file_name_only);
-                                Span::styled(formatted_file, Style::default().fg(Color::Blue))
-                            }
-                            Err(e) => {
-                                let error_message = format!("Failed to write file: {}", e);
-                                Span::styled(error_message, Style::default().fg(Color::Red))
-                            }
-                        }
+            MessageType::Message(source, ..) if ignored.contains(source) => continue,
+            MessageType::Message(source, message, nonce, _) => {
+                // Identical messages in a row from the same sender are shown once, with a count
+                let repeats = match &mut run {
+                    Some((last_source, last_message, count))
+                        if *last_source == source && *last_message == message =>
+                    {
+                        message_lines.pop();
+                        // A repeat of the selected message takes its place
+                        selected_start = selected_start.map(|start| start.min(message_lines.len()));
+                        *count += 1;
+                        *count
                     }
-                    Err(e) => {
-                        let error_message = format!("Failed to get current directory: {}", e);
-                        Span::styled(error_message, Style::default().fg(Color::Red))
+                    _ => {
+                        run = Some((source, message, 1));
+                        1
                     }
+                };
+
+                let formatted_message = format!(
+                    "{:^width$}: {}",
+                    source,
+                    message,
+                    width = client_state.max_name_length
+                );
+                let mut spans = vec![Span::styled(formatted_message, theme.message)];
+                if repeats > 1 {
+                    spans.push(Span::styled(format!(" (×{})", repeats), theme.faint));
                 }
+                if unverified {
+                    spans.push(Span::styled(" ⚠ unverified", theme.error));
+                }
+
+                // Mark how far our own messages have got
+                spans.extend(delivery_spans(deliveries.get(nonce), client_state, theme));
+                message_lines.push(Line::from(spans));
+                continue;
             }
-            MessageType::Image(image_name, image_contents) => {
-                // Extract the image name, ignoring any path components
-                let image_name_only = std::path::Path::new(&image_name)
-                    .file_name()
-                    .and_then(|name| name.to_str())
-                    .unwrap_or("downloaded_image");
-
-                // Attempt to get the current directory
-                match std::env::current_dir() {
-                    Ok(current_dir) => {
-                        let full_path = current_dir.join(image_name_only);
-
-                        // Attempt to write the image_contents to the image in the current directory
-                        match std::fs::write(&full_path, image_contents) {
-                            Ok(_) => {
-                                let formatted_image = format!("Received image: {}", image_name_only);
-                                Span::styled(formatted_image, Style::default().fg(Color::Blue))
-                            }
-                            Err(e) => {
-                                let error_message = format!("Failed to write image: {}", e);
-                                Span::styled(error_message, Style::default().fg(Color::Red))
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        let error_message = format!("Failed to get current directory: {}", e);
-                        Span::styled(error_message, Style::default().fg(Color::Red))
-                    }
+            MessageType::Code { sender, .. } if ignored.contains(sender) => continue,
+            MessageType::Code {
+                sender,
+                lang,
+                body,
+                nonce,
+                ..
+            } => {
+                let header = format!("{:^width$}:", sender, width = client_state.max_name_length);
+                let mut spans = vec![Span::styled(header, theme.message)];
+                if unverified {
+                    spans.push(Span::styled(" ⚠ unverified", theme.error));
                 }
+                spans.extend(delivery_spans(deliveries.get(nonce), client_state, theme));
+                message_lines.push(Line::from(spans));
+                message_lines.extend(code_block_lines(lang, body, theme));
+                continue;
+            }
+            MessageType::Poll {
+                id,
+                question,
+                options,
+            } => {
+                let polls = client_state.polls.lock().unwrap();
+                let counts = polls.get(id).map_or(&[][..], Vec::as_slice);
+                message_lines.extend(poll_lines(*id, question, options, counts, theme));
+                continue;
+            }
+            MessageType::Error(error) => {
+                Span::styled(format!("{}{}", theme.error_prefix, error), theme.error)
+            }
+            MessageType::Presence { nick, state } => {
+                let formatted_presence = match state {
+                    PresenceState::Active => format!("{} is back", nick),
+                    _ => format!("{} is now {}", nick, state),
+                };
+                Span::styled(formatted_presence, theme.notice)
+            }
+            MessageType::Status { nick, text } => {
+                let formatted_status = if text.is_empty() {
+                    format!("{} cleared their status", nick)
+                } else {
+                    format!("{}'s status: {}", nick, text)
+                };
+                Span::styled(formatted_status, theme.notice)
+            }
+            MessageType::UserList(users) => {
+                let formatted_users = users
+                    .iter()
+                    .map(|(nick, state, connected, status, spectator)| {
+                        user_entry(nick, state, *connected, status, *spectator)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                Span::styled(
+                    format!("Users in this room: {}", formatted_users),
+                    theme.info,
+                )
+            }
+            // Received files are saved as they arrive, see `save_received`
+            MessageType::SavedFile {
+                file_name,
+                size,
+                checksum,
+            } => {
+                let formatted_file = format!(
+                    "Received file: {} ({}, sha256 {})",
+                    file_name,
+                    format_size(*size as usize),
+                    checksum.get(..8).unwrap_or(checksum)
+                );
+                Span::styled(formatted_file, theme.file)
+            }
+            MessageType::Image(image_name, image_contents) => {
+                let formatted_image = format!(
+                    "Received image: {} ({})",
+                    file_name_only(image_name),
+                    format_size(image_contents.len())
+                );
+                message_lines.push(Line::from(Span::styled(formatted_image, theme.file)));
+                message_lines.extend_from_slice(thumbnails.get(image_name, image_contents));
+                drawn_images.push(index);
+                continue;
+            }
+            MessageType::SavedImage { image_name, size } => {
+                let formatted_image = format!(
+                    "Received image: {} ({})",
+                    file_name_only(image_name),
+                    format_size(*size as usize)
+                );
+                message_lines.push(Line::from(Span::styled(formatted_image, theme.file)));
+                message_lines.extend_from_slice(thumbnails.cached(image_name, *size as usize));
+                continue;
             }
             _ => continue,
         };
         message_lines.push(Line::from(span));
     }
 
-    // Split the frame into two rows, one for the messages and one for the text area
+    // The thumbnails are cached now, so the contents of the images aren't needed anymore
+    for index in drawn_images {
+        if let Some(saved) = without_contents(&messages[index]) {
+            messages[index] = saved;
+        }
+    }
+
+    // Split the frame into two rows, one for the messages and one for the text area, which spectators don't get
+    let input_height = if client_state.spectator { 0 } else { 20 };
     let chunks = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Percentage(80), Constraint::Percentage(20)].as_ref())
+        .constraints(
+            [
+                Constraint::Percentage(100 - input_height),
+                Constraint::Percentage(input_height),
+            ]
+            .as_ref(),
+        )
         .split(frame.size());
 
+    // Follow new messages while at the bottom, otherwise count them as unread
+    let was_at_bottom = view.is_at_bottom();
+    view.max_scroll =
+        (message_lines.len() as u16).saturating_sub(chunks[0].height.saturating_sub(2));
+    if was_at_bottom {
+        view.scroll_to_bottom();
+    }
+    let selected_lines =
+        selected_start.map(|start| start..selected_end.unwrap_or(message_lines.len()));
+    if let Some(lines) = &selected_lines {
+        view.reveal(lines.clone(), chunks[0].height.saturating_sub(2));
+        for line in &mut message_lines[lines.clone()] {
+            *line = std::mem::take(line).patch_style(theme.selected);
+        }
+    }
+    if view.is_at_bottom() {
+        view.seen = messages.len();
+    }
+    let unread = messages
+        .iter()
+        .skip(view.seen)
+        .filter(|message| {
+            matches!(message, MessageType::Message(source, ..) if !ignored.contains(source))
+        })
+        .count();
+
+    let title = if !client_state.registered.load(Ordering::SeqCst) {
+        "Lan Chat 💬 (connecting…)".to_string()
+    } else {
+        let here = client_state.roster.lock().unwrap().len();
+        let spectating = if client_state.spectator {
+            " (spectating)"
+        } else {
+            ""
+        };
+        format!("Lan Chat 💬{} · {} here", spectating, here)
+    };
+    let mut block = Block::default().title(title).borders(Borders::ALL);
+    // The reader thread stops as soon as the connection drops, and runs again once reconnected
+    if !client_state.reader_alive.load(Ordering::SeqCst) {
+        block = block.title(
+            block::Title::from(Span::styled(
+                " Disconnected from server, reconnecting… ",
+                theme.banner,
+            ))
+            .alignment(Alignment::Center),
+        );
+    }
+    if selected.is_some() {
+        block = block.title(
+            block::Title::from(Span::styled(
+                " ↑/↓ or k/j to move, Enter to copy, Esc to go back ",
+                theme.highlight,
+            ))
+            .position(block::Position::Bottom)
+            .alignment(Alignment::Left),
+        );
+    }
+    if unread > 0 {
+        block = block.title(
+            block::Title::from(Span::styled(
+                format!(
+                    "↓ {} new message{}",
+                    unread,
+                    if unread == 1 { "" } else { "s" }
+                ),
+                theme.notice,
+            ))
+            .position(block::Position::Bottom)
+            .alignment(Alignment::Right),
+        );
+    }
+
     // Display the messages on the screen
     frame.render_widget(
         Paragraph::new(message_lines)
-            .scroll((*scroll, 0))
-            .block(Block::default().title("Lan Chat 💬").borders(Borders::ALL)),
+            .scroll((view.scroll, 0))
+            .block(block),
         chunks[0],
     );
+    if client_state.spectator {
+        return;
+    }
+    style_input(text_area, pseudonym, theme);
     frame.render_widget(text_area.widget(), chunks[1]);
+
+    if emoji_picker.is_open {
+        render_emoji_picker(frame, emoji_picker, chunks[0]);
+    } else {
+        render_command_hints(frame, &text_area.lines()[0], chunks[0], theme);
+    }
+}
+
+/// Frames the input box with our pseudonym in its title, so it's clear where typing goes.
+fn style_input(text_area: &mut TextArea, pseudonym: &str, theme: &Theme) {
+    text_area.set_block(
+        Block::default()
+            .title(format!("You: {}", pseudonym))
+            .borders(Borders::ALL)
+            .border_style(theme.input_border),
+    );
+    text_area.set_placeholder_style(theme.placeholder);
+    text_area.set_cursor_style(theme.cursor);
 }
 
-fn replace_keywords_with_emojis(text: &str) -> String {
+/// Marks how far one of our messages has got, nothing for the messages of others.
+fn delivery_spans(
+    delivery: Option<&Delivery>,
+    client_state: &ClientState,
+    theme: &Theme,
+) -> Vec<Span<'static>> {
+    let mut spans = vec![];
+    match delivery {
+        Some(Delivery::Delivered(recipients)) => {
+            spans.push(Span::styled(" ✓", theme.info));
+            if client_state.show_recipients {
+                spans.push(Span::styled(
+                    format!(" delivered to {}", recipients),
+                    theme.faint,
+                ));
+            }
+        }
+        Some(Delivery::Pending(sent)) if sent.elapsed() > ACK_TIMEOUT => {
+            spans.push(Span::styled(" ⚠ not delivered", theme.error));
+        }
+        Some(Delivery::Pending(_)) => {
+            spans.push(Span::styled(" ◷", theme.faint));
+        }
+        Some(Delivery::Queued) => {
+            spans.push(Span::styled(" ◷ queued", theme.faint));
+        }
+        Some(Delivery::Failed) => {
+            spans.push(Span::styled(" ✗ not sent, /retry", theme.error));
+        }
+        None => {}
+    }
+    spans
+}
+
+/// Draws a code block in a frame, highlighted if its language is known.
+fn code_block_lines(lang: &str, body: &str, theme: &Theme) -> Vec<Line<'static>> {
+    let title = if lang.is_empty() { "code" } else { lang };
+    let mut lines = vec![Line::from(Span::styled(
+        format!("┌─ {} {}", title, "─".repeat(20)),
+        theme.faint,
+    ))];
+    // Without colors, or in a language that isn't known, code is shown as it is
+    let highlighted = theme.code_colors.then(|| highlight(lang, body)).flatten();
+    for (index, line) in body.lines().enumerate() {
+        let mut spans = vec![Span::styled("│ ", theme.faint)];
+        match highlighted.as_ref().and_then(|lines| lines.get(index)) {
+            Some(pieces) => spans.extend(
+                pieces
+                    .iter()
+                    .map(|(style, text)| Span::styled(text.clone(), theme.code.patch(*style))),
+            ),
+            None => spans.push(Span::styled(line.to_string(), theme.code)),
+        }
+        lines.push(Line::from(spans));
+    }
+    lines.push(Line::from(Span::styled(
+        format!("└{}", "─".repeat(24 + title.chars().count())),
+        theme.faint,
+    )));
+    lines
+}
+
+/// Draws a poll as a bar chart of the votes for each option, followed by how to vote.
+fn poll_lines(
+    id: u64,
+    question: &str,
+    options: &[String],
+    counts: &[u32],
+    theme: &Theme,
+) -> Vec<Line<'static>> {
+    const BAR_WIDTH: u32 = 20;
+    let most = counts.iter().copied().max().unwrap_or_default().max(1);
+    let option_width = options
+        .iter()
+        .map(|option| option.chars().count())
+        .max()
+        .unwrap_or_default();
+
+    let mut lines = vec![Line::from(Span::styled(
+        format!("Poll #{}: {}", id, question),
+        theme.notice,
+    ))];
+    for (index, option) in options.iter().enumerate() {
+        let count = counts.get(index).copied().unwrap_or_default();
+        let bar = (count * BAR_WIDTH).div_ceil(most) as usize;
+        lines.push(Line::from(vec![
+            Span::styled(
+                format!("  {}. {:<width$} ", index + 1, option, width = option_width),
+                theme.message,
+            ),
+            Span::styled("█".repeat(bar), theme.bar),
+            Span::styled(format!(" {}", count), theme.faint),
+        ]));
+    }
+    lines.push(Line::from(Span::styled(
+        format!("  /vote {} <option> to vote", id),
+        theme.faint,
+    )));
+    lines
+}
+
+/// Draws the commands matching the input at the bottom of the given area, right above the input.
+fn render_command_hints(frame: &mut Frame, input: &str, area: Rect, theme: &Theme) {
+    let hints = command_hints(input);
+    if hints.is_empty() {
+        return;
+    }
+
+    let height = (hints.len() as u16 + 2).min(area.height);
+    let popup_area = Rect::new(area.x, area.y + area.height - height, area.width, height);
+
+    let items: Vec<ListItem> = hints
+        .iter()
+        .map(|(name, args, description)| {
+            ListItem::new(Line::from(vec![
+                Span::styled(command_usage(name, args), theme.highlight),
+                Span::raw(format!(" - {}", description)),
+            ]))
+        })
+        .collect();
+
+    frame.render_widget(Clear, popup_area);
+    frame.render_widget(
+        List::new(items).block(Block::default().title("Commands").borders(Borders::ALL)),
+        popup_area,
+    );
+}
+
+/// Draws the emoji picker popup centered over the given area.
+fn render_emoji_picker(frame: &mut Frame, emoji_picker: &mut EmojiPicker, area: Rect) {
+    let width = area.width.min(40);
+    let height = area.height.min(15);
+    let popup_area = Rect::new(
+        area.x + (area.width - width) / 2,
+        area.y + (area.height - height) / 2,
+        width,
+        height,
+    );
+
+    let items: Vec<ListItem> = emoji_picker
+        .matches()
+        .iter()
+        .map(|(shortcode, emoji)| ListItem::new(format!("{} :{}:", emoji.as_str(), shortcode)))
+        .collect();
+
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .title(format!("Emoji: {}", emoji_picker.filter))
+                .borders(Borders::ALL),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+    frame.render_widget(Clear, popup_area);
+    frame.render_stateful_widget(list, popup_area, &mut emoji_picker.list_state);
+}
+
+/// Replaces `:shortcode:` keywords with their emoji.
+///
+/// Shortcodes are matched ignoring case and whether words are separated by `_`, `-` or nothing, so
+/// `:Thumbs-Up:` finds 👍. Also returns the keywords that matched no emoji, along with the closest
+/// shortcode if one is near enough to be a typo.
+fn replace_keywords_with_emojis(text: &str) -> (String, Vec<(String, Option<&'static str>)>) {
     let mut output = String::new();
+    let mut unknown = vec![];
     let mut current_word = String::new();
     let mut inside_keyword = false;
 
@@ -309,12 +1684,16 @@ fn replace_keywords_with_emojis(text: &str) -> String {
         match ch {
             ':' => {
                 if inside_keyword {
-                    if let Some(emoji) = emojis::get_by_shortcode(&current_word) {
+                    if let Some(emoji) = find_emoji(&current_word) {
                         output.push_str(emoji.as_str());
                     } else {
                         output.push(':');
                         output.push_str(&current_word);
                         output.push(':');
+                        // Colons in times and the like aren't meant as emojis
+                        if !current_word.is_empty() && !current_word.contains(char::is_whitespace) {
+                            unknown.push((current_word.clone(), nearest_shortcode(&current_word)));
+                        }
                     }
                     current_word.clear();
                 }
@@ -330,5 +1709,399 @@ fn replace_keywords_with_emojis(text: &str) -> String {
         }
     }
 
-    output
+    // An unclosed keyword is just text
+    if inside_keyword {
+        output.push(':');
+        output.push_str(&current_word);
+    }
+
+    (output, unknown)
+}
+
+/// Looks up an emoji by shortcode, ignoring case and word separators.
+fn find_emoji(shortcode: &str) -> Option<&'static emojis::Emoji> {
+    let shortcode = shortcode.to_lowercase();
+    [
+        shortcode.clone(),
+        shortcode.replace('-', "_"),
+        shortcode.replace('_', "-"),
+    ]
+    .iter()
+    .find_map(|candidate| emojis::get_by_shortcode(candidate))
+    .or_else(|| {
+        // Shortcodes like thumbsup have no separator to begin with
+        let bare = shortcode.replace(['_', '-'], "");
+        emojis::iter()
+            .flat_map(|emoji| emoji.shortcodes().map(move |code| (code, emoji)))
+            .find(|(code, _)| code.replace(['_', '-'], "") == bare)
+            .map(|(_, emoji)| emoji)
+    })
+}
+
+/// Returns the shortcode closest to a misspelled one, if it is at most two edits away.
+fn nearest_shortcode(shortcode: &str) -> Option<&'static str> {
+    const MAX_DISTANCE: usize = 2;
+    let shortcode = shortcode.to_lowercase();
+    if shortcode.chars().count() <= MAX_DISTANCE {
+        return None;
+    }
+
+    emojis::iter()
+        .flat_map(|emoji| emoji.shortcodes())
+        .map(|code| (edit_distance(&shortcode, code), code))
+        .filter(|(distance, _)| *distance <= MAX_DISTANCE)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, code)| code)
+}
+
+/// Number of single character insertions, deletions, substitutions or swaps of neighbours turning one
+/// string into the other.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    distances[0] = (0..=b.len()).collect();
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = distances[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = substitution
+                .min(distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Shutdown, TcpListener, TcpStream};
+
+    use super::*;
+    use crate::networking::connection::Endpoint;
+
+    #[test]
+    fn test_input_is_kept_only_when_it_cannot_run() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let client_state = ClientState::new(stream.try_clone().unwrap());
+        let shared_message_vector = Arc::default();
+        let mut messages = vec![];
+        let mut ignored = HashSet::new();
+        let mut submit = |input: &str, stream: &mut Connection| {
+            submit_input(
+                input,
+                &shared_message_vector,
+                &mut messages,
+                stream,
+                &mut ignored,
+                &client_state,
+                "alice",
+            )
+        };
+
+        assert_eq!(submit("/join", &mut stream).unwrap(), Submitted::Kept);
+        assert_eq!(submit("/msg bob hi", &mut stream).unwrap(), Submitted::Kept);
+        assert_eq!(
+            submit("/vote 1 first", &mut stream).unwrap(),
+            Submitted::Kept
+        );
+        assert_eq!(submit("/ignore bob", &mut stream).unwrap(), Submitted::Done);
+        assert_eq!(submit("/join games", &mut stream).unwrap(), Submitted::Done);
+        client_state.registered.store(true, Ordering::SeqCst);
+        assert_eq!(submit("hello", &mut stream).unwrap(), Submitted::Done);
+        for blank in ["\t", "\u{200B}", "   "] {
+            assert_eq!(submit(blank, &mut stream).unwrap(), Submitted::Done);
+        }
+        assert_eq!(client_state.deliveries.lock().unwrap().len(), 1);
+        assert_eq!(submit("/quit", &mut stream).unwrap(), Submitted::Quit);
+
+        let broken = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        broken.shutdown(Shutdown::Write).unwrap();
+        assert!(submit("hello again", &mut Connection::Tcp(broken)).is_err());
+    }
+
+    #[test]
+    fn test_paste_sends_text_files_only() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let client_state = ClientState::new(stream.try_clone().unwrap());
+        client_state.registered.store(true, Ordering::SeqCst);
+        let mut messages = vec![];
+        let mut paste = |path: &std::path::Path| {
+            submit_input(
+                &format!("/paste {}", path.display()),
+                &Arc::default(),
+                &mut messages,
+                &mut stream,
+                &mut HashSet::new(),
+                &client_state,
+                "alice",
+            )
+            .unwrap()
+        };
+
+        let dir = std::env::temp_dir();
+        let text_path = dir.join(format!("lan-chat-paste-{}.txt", std::process::id()));
+        let binary_path = dir.join(format!("lan-chat-paste-{}.bin", std::process::id()));
+        std::fs::write(&text_path, "line one\nline two\n").unwrap();
+        std::fs::write(&binary_path, [0xff, 0xfe, 0x00]).unwrap();
+
+        assert_eq!(paste(&text_path), Submitted::Done);
+        assert_eq!(paste(&binary_path), Submitted::Kept);
+        std::fs::remove_file(&text_path).unwrap();
+        std::fs::remove_file(&binary_path).unwrap();
+
+        assert!(matches!(
+            &messages[0],
+            MessageType::Message(sender, text, ..) if sender == "alice" && text == "line one\nline two\n"
+        ));
+        assert!(matches!(&messages[2], MessageType::Error(error) if error.contains("not UTF-8")));
+    }
+
+    #[test]
+    fn test_binary_looking_text_takes_a_second_enter() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let client_state = ClientState::new(stream.try_clone().unwrap());
+        client_state.registered.store(true, Ordering::SeqCst);
+        let mut messages = vec![];
+        let mut submit = |input: &str| {
+            submit_input(
+                input,
+                &Arc::default(),
+                &mut messages,
+                &mut stream,
+                &mut HashSet::new(),
+                &client_state,
+                "alice",
+            )
+            .unwrap()
+        };
+
+        assert_eq!(submit("\u{7f}ELF\u{2}\u{1}\u{1}"), Submitted::Kept);
+        assert_eq!(submit("hello"), Submitted::Done);
+        assert_eq!(submit("\u{7f}ELF\u{2}\u{1}\u{1}"), Submitted::Kept);
+        assert_eq!(submit("\u{7f}ELF\u{2}\u{1}\u{1}"), Submitted::Done);
+
+        assert!(matches!(&messages[0], MessageType::Error(error) if error.contains("binary data")));
+        assert!(
+            matches!(&messages[3], MessageType::Message(_, text, ..) if text.starts_with('\u{7f}'))
+        );
+    }
+
+    #[test]
+    fn test_quit_goes_ahead_without_a_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let client_state = ClientState::new(stream.try_clone().unwrap());
+        stream.shutdown().unwrap();
+
+        let submitted = submit_input(
+            "/quit",
+            &Arc::default(),
+            &mut vec![],
+            &mut stream,
+            &mut HashSet::new(),
+            &client_state,
+            "alice",
+        );
+        assert_eq!(submitted.unwrap(), Submitted::Quit);
+    }
+
+    #[test]
+    fn test_code_blocks_are_queued_like_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let client_state = ClientState::new(stream.try_clone().unwrap());
+        let mut messages = vec![];
+        let mut submit = |input: &str| {
+            submit_input(
+                input,
+                &Arc::default(),
+                &mut messages,
+                &mut stream,
+                &mut HashSet::new(),
+                &client_state,
+                "alice",
+            )
+            .unwrap()
+        };
+
+        // Not registered yet, so it waits in the outbox and is shown as queued
+        assert_eq!(submit("```rust\nfn main() {}\n```"), Submitted::Done);
+        let too_long = format!("```\n{}\n```", "x".repeat(crypto::max_text_length() + 1));
+        assert_eq!(submit(&too_long), Submitted::Kept);
+
+        let MessageType::Code { nonce, .. } = &messages[0] else {
+            panic!("expected the code block, got {:?}", messages[0]);
+        };
+        assert_eq!(
+            client_state.deliveries.lock().unwrap().get(nonce),
+            Some(&Delivery::Queued)
+        );
+        assert!(matches!(
+            client_state.outbox.lock().unwrap().front(),
+            Some(MessageType::Code { body, .. }) if body == "fn main() {}"
+        ));
+        assert!(matches!(&messages[1], MessageType::Error(error) if error.contains("up to")));
+        assert_eq!(messages.len(), 2);
+    }
+
+    #[test]
+    fn test_tiny_terminal_gets_a_note_instead_of_the_chat() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Endpoint::Tcp(listener.local_addr().unwrap())
+            .connect()
+            .unwrap();
+        let client_state = ClientState::new(stream);
+        let message_vector = Arc::new(Mutex::new(vec![MessageType::Info("hello".to_string())]));
+        let mut ui_state = UiState::default();
+        let mut text_area = TextArea::default();
+
+        let mut draw = |width, height| {
+            let mut terminal = Terminal::new(backend::TestBackend::new(width, height)).unwrap();
+            terminal
+                .draw(|frame| {
+                    ui(
+                        frame,
+                        Arc::clone(&message_vector),
+                        &mut text_area,
+                        &mut ui_state,
+                        &client_state,
+                    )
+                })
+                .unwrap();
+            let buffer = terminal.backend().buffer().clone();
+            buffer
+                .content
+                .iter()
+                .map(|cell| cell.symbol())
+                .collect::<String>()
+        };
+
+        for (width, height) in [(19, 10), (80, 4), (1, 1), (0, 0)] {
+            let screen = draw(width, height);
+            assert!(!screen.contains("hello"));
+        }
+        assert!(draw(19, 10).contains("Terminal"));
+        assert!(draw(MIN_WIDTH, MIN_HEIGHT).contains("hello"));
+    }
+
+    #[test]
+    fn test_command_hints_filter_by_prefix() {
+        let names = |input| {
+            command_hints(input)
+                .iter()
+                .map(|(name, _, _)| *name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names("/f"), vec!["file"]);
+        assert_eq!(names("/").len(), COMMANDS.len());
+        assert!(names("/file ").is_empty());
+        assert!(names("hello").is_empty());
+    }
+
+    #[test]
+    fn test_emoji_shortcodes_ignore_case_and_separators() {
+        let replace = |text| replace_keywords_with_emojis(text).0;
+
+        assert_eq!(replace("hi :Smile:"), "hi 😄");
+        assert_eq!(replace(":THUMBSUP:"), "👍");
+        assert_eq!(replace(":thumbs_up:"), "👍");
+        assert_eq!(replace(":thumbs-up:"), "👍");
+        assert_eq!(replace(":heart-eyes:"), "😍");
+        assert_eq!(replace("see you at 10:30"), "see you at 10:30");
+    }
+
+    #[test]
+    fn test_copyable_text() {
+        assert_eq!(
+            copyable_text(&MessageType::Message(
+                "bob".to_string(),
+                "hi".to_string(),
+                1,
+                vec![]
+            )),
+            Some("hi")
+        );
+        let code = MessageType::Code {
+            sender: "bob".to_string(),
+            lang: "rust".to_string(),
+            body: "fn main() {}".to_string(),
+            nonce: 1,
+            signature: vec![],
+        };
+        assert_eq!(copyable_text(&code), Some("fn main() {}"));
+        assert_eq!(copyable_text(&MessageType::Info("hi".to_string())), None);
+    }
+
+    #[test]
+    fn test_move_selection_skips_what_cant_be_selected() {
+        let messages = vec![
+            MessageType::Message("bob".to_string(), "first".to_string(), 1, vec![]),
+            MessageType::Info("alice joined".to_string()),
+            MessageType::Message("eve".to_string(), "ignored".to_string(), 2, vec![]),
+            MessageType::Code {
+                sender: "bob".to_string(),
+                lang: String::new(),
+                body: "ls".to_string(),
+                nonce: 3,
+                signature: vec![],
+            },
+        ];
+        let ignored = HashSet::from(["eve".to_string()]);
+        assert!(!is_selectable(&messages[1], &ignored));
+        assert!(!is_selectable(&messages[2], &ignored));
+        assert_eq!(move_selection(&messages, &ignored, 3, true), Some(0));
+        assert_eq!(move_selection(&messages, &ignored, 0, false), Some(3));
+        // Nothing further, so the selection stays
+        assert_eq!(move_selection(&messages, &ignored, 0, true), Some(0));
+        assert_eq!(move_selection(&messages, &ignored, 3, false), Some(3));
+    }
+
+    #[test]
+    fn test_reveal_scrolls_just_enough() {
+        let mut view = MessageView {
+            scroll: 10,
+            max_scroll: 40,
+            ..Default::default()
+        };
+        view.reveal(12..14, 10);
+        assert_eq!(view.scroll, 10);
+        view.reveal(4..6, 10);
+        assert_eq!(view.scroll, 4);
+        view.reveal(20..22, 10);
+        assert_eq!(view.scroll, 12);
+        // Taller than the pane, so its first line is shown
+        view.reveal(30..50, 10);
+        assert_eq!(view.scroll, 30);
+    }
+
+    #[test]
+    fn test_unknown_emoji_suggests_nearest() {
+        let (text, unknown) = replace_keywords_with_emojis("nice :smiel: and 9:30 or 10:45");
+        assert_eq!(text, "nice :smiel: and 9:30 or 10:45");
+        assert_eq!(unknown, vec![("smiel".to_string(), Some("smile"))]);
+
+        assert_eq!(edit_distance("smiel", "smile"), 1);
+        assert_eq!(nearest_shortcode("zzzzzzzz"), None);
+    }
 }